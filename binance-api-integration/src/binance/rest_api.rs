@@ -25,7 +25,7 @@ impl ExchangeInfo {
         // }
         ExchangeInfo {
             weight: 20,
-            limit: limit,
+            limit,
             // symbols_name: symbols_names,
         }
     }
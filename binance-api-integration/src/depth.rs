@@ -18,7 +18,7 @@ impl From<&str> for Depth {
 }
 
 impl Depth {
-    pub fn iter(&self) -> DepthIterator {
+    pub fn iter(&self) -> DepthIterator<'_> {
         DepthIterator {
             E: self.E,
             s: &self.s,
@@ -3,6 +3,7 @@ use crate::depth::DepthItem;
 use crate::snapshot::SnapshotItem;
 use serde::{Deserialize, Serialize};
 
+#[allow(clippy::enum_variant_names)]
 pub enum RawEvent {
     RawTrade(String),
     RawDepth(String),
@@ -86,6 +87,8 @@ impl Event {
         Ok(bincode::serialize(self)?)
     }
 
+    /// Reads back an event written by [`Event::as_bytes`].
+    #[allow(dead_code)]
     pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
         Ok(bincode::deserialize(bytes)?)
     }
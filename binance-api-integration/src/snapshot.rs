@@ -16,7 +16,7 @@ impl From<&str> for Snapshot {
 }
 
 impl Snapshot {
-    pub fn iter(&self) -> SnapshotIter {
+    pub fn iter(&self) -> SnapshotIter<'_> {
         SnapshotIter {
             last_update_id: self.last_update_id,
             bids: self.bids.iter(),
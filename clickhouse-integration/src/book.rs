@@ -0,0 +1,58 @@
+use fpdec::Decimal;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::event::Event;
+
+/// Price levels of a single product rebuilt from snapshot and depth events.
+#[derive(Default)]
+pub struct Book {
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl Book {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    /// Sets the level of a snapshot or depth event, a zero quantity removes it.
+    pub fn apply(&mut self, event: &Event) {
+        let (Ok(price), Ok(quantity)) = (
+            Decimal::from_str(&event.price),
+            Decimal::from_str(&event.quantity),
+        ) else {
+            return;
+        };
+        let side = if event.ask_not_bid == Some(true) {
+            &mut self.asks
+        } else {
+            &mut self.bids
+        };
+        if quantity.eq_zero() {
+            side.remove(&price);
+        } else {
+            side.insert(price, quantity);
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids
+            .last_key_value()
+            .map(|(&price, &quantity)| (price, quantity))
+    }
+
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks
+            .first_key_value()
+            .map(|(&price, &quantity)| (price, quantity))
+    }
+
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((best_bid, _)), Some((best_ask, _))) => best_bid >= best_ask,
+            _ => false,
+        }
+    }
+}
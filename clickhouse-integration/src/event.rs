@@ -0,0 +1,214 @@
+use bincode::{BincodeRead, Options};
+use clickhouse::Row;
+use flate2::read::GzDecoder;
+use serde::{de::Visitor, Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Row)]
+pub struct Event {
+    pub local_unique_id: i64,
+    pub venue_timestamp: i64,
+    pub gate_timestamp: i64,
+    pub event_type: String,
+    pub product: String,
+    pub id1: Option<u64>,
+    pub id2: Option<u64>,
+    pub ask_not_bid: Option<bool>,
+    pub buy_not_sell: Option<bool>,
+    pub price: String,
+    pub quantity: String,
+}
+
+/// Borrowed [`Event`], decoded in place from a chunk of a capture file and
+/// written to ClickHouse without allocating its strings.
+#[derive(Debug, Serialize, Deserialize, Row)]
+pub struct EventRef<'a> {
+    pub local_unique_id: i64,
+    pub venue_timestamp: i64,
+    pub gate_timestamp: i64,
+    pub event_type: &'a str,
+    pub product: &'a str,
+    pub id1: Option<u64>,
+    pub id2: Option<u64>,
+    pub ask_not_bid: Option<bool>,
+    pub buy_not_sell: Option<bool>,
+    pub price: &'a str,
+    pub quantity: &'a str,
+}
+
+impl<'a> EventRef<'a> {
+    /// Decodes the event at the start of `bytes`, returning it together with
+    /// its encoded length. `Ok(None)` means `bytes` ends in the middle of it.
+    pub fn decode(bytes: &'a [u8]) -> bincode::Result<Option<(Self, usize)>> {
+        let mut rest = bytes;
+        // The options of `bincode::serialize`, which the collector writes with.
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut deserializer =
+            bincode::Deserializer::with_bincode_read(SliceCursor(&mut rest), options);
+        match EventRef::deserialize(&mut deserializer) {
+            Ok(event) => Ok(Some((event, bytes.len() - rest.len()))),
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(ref io_err)
+                    if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    Ok(None)
+                }
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+/// Reader of a slice that advances the slice itself, so that after decoding
+/// what is left of it tells how many bytes the event took.
+struct SliceCursor<'s, 'a>(&'s mut &'a [u8]);
+
+impl<'a> SliceCursor<'_, 'a> {
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let slice: &'a [u8] = self.0;
+        if length > slice.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (taken, rest) = slice.split_at(length);
+        *self.0 = rest;
+        Ok(taken)
+    }
+}
+
+impl Read for SliceCursor<'_, '_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(out.len())
+    }
+}
+
+impl<'a> BincodeRead<'a> for SliceCursor<'_, 'a> {
+    fn forward_read_str<V: Visitor<'a>>(
+        &mut self,
+        length: usize,
+        visitor: V,
+    ) -> bincode::Result<V::Value> {
+        match std::str::from_utf8(self.take(length)?) {
+            Ok(string) => visitor.visit_borrowed_str(string),
+            Err(err) => Err(bincode::ErrorKind::InvalidUtf8Encoding(err).into()),
+        }
+    }
+    fn get_byte_buffer(&mut self, length: usize) -> bincode::Result<Vec<u8>> {
+        Ok(self.take(length)?.to_vec())
+    }
+    fn forward_read_bytes<V: Visitor<'a>>(
+        &mut self,
+        length: usize,
+        visitor: V,
+    ) -> bincode::Result<V::Value> {
+        visitor.visit_borrowed_bytes(self.take(length)?)
+    }
+}
+
+/// Sequential reader over a capture file written by `binance-api-integration`.
+pub struct EventReader<R: Read> {
+    reader: R,
+}
+
+impl EventReader<BufReader<Box<dyn Read + Send>>> {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(BufReader::new(open_capture(
+            path,
+            File::open(path)?,
+        ))))
+    }
+}
+
+impl<R: Read> EventReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+}
+
+impl<R: Read> Iterator for EventReader<R> {
+    type Item = bincode::Result<Event>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match bincode::deserialize_from::<_, Event>(&mut self.reader) {
+            Ok(event) => Some(Ok(event)),
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(ref io_err)
+                    if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    None
+                }
+                _ => Some(Err(err)),
+            },
+        }
+    }
+}
+
+/// Whether `path` is a capture file, either as written by the collector or
+/// gzip-compressed by the retention janitor.
+pub fn is_capture_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    path.is_file() && (name.ends_with(".bin") || name.ends_with(".bin.gz"))
+}
+
+/// Wraps the contents of a capture file, decompressing archived ones.
+pub fn open_capture<R: Read + Send + 'static>(path: &Path, file: R) -> Box<dyn Read + Send> {
+    if path.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, id1: u64, price: &str) -> Event {
+        Event {
+            local_unique_id: id1 as i64,
+            venue_timestamp: 1_732_600_000_000,
+            gate_timestamp: 1_732_600_000_005,
+            event_type: event_type.to_string(),
+            product: String::from("BTCUSDT"),
+            id1: Some(id1),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: String::from("0.00120000"),
+        }
+    }
+
+    #[test]
+    fn decodes_events_in_place_with_their_length() {
+        let events = [
+            event("depth", 1, "95000.01000000"),
+            event("trade", 22, "95000.1"),
+        ];
+        let mut bytes = Vec::new();
+        for event in &events {
+            bytes.extend(bincode::serialize(event).unwrap());
+        }
+        let first_len = bincode::serialized_size(&events[0]).unwrap() as usize;
+
+        let (first, len) = EventRef::decode(&bytes).unwrap().unwrap();
+        assert_eq!(len, first_len);
+        assert_eq!((first.event_type, first.price), ("depth", "95000.01000000"));
+        let (second, len) = EventRef::decode(&bytes[first_len..]).unwrap().unwrap();
+        assert_eq!(first_len + len, bytes.len());
+        assert_eq!((second.id1, second.price), (Some(22), "95000.1"));
+
+        assert!(EventRef::decode(&bytes[..first_len - 1]).unwrap().is_none());
+        assert!(EventRef::decode(&[]).unwrap().is_none());
+        let read: Vec<Event> = EventReader::new(bytes.as_slice())
+            .collect::<bincode::Result<_>>()
+            .unwrap();
+        assert_eq!(read.len(), 2);
+    }
+}
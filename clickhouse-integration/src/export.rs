@@ -0,0 +1,410 @@
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use arrow_array::{
+    builder::{
+        BooleanBuilder, Decimal128Builder, Int64Builder, StringBuilder,
+        TimestampMillisecondBuilder, UInt64Builder,
+    },
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::DateTime;
+use clickhouse::Client;
+use fpdec::{Decimal, Round};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::{
+    event::Event,
+    source::{EventFilter, EventSource},
+};
+
+/// Rows buffered per partition before they are handed to the Parquet writer.
+const BATCH_ROWS: usize = 65_536;
+/// Decimal digits of the prices and quantities, those of Binance, and
+/// the precision of their Parquet columns.
+const SCALE: u8 = 8;
+const PRECISION: u8 = 38;
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Parquet,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parquet" => Ok(Format::Parquet),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {:?}, expected parquet or csv", s)),
+        }
+    }
+}
+
+#[derive(FromArgs)]
+/// Export captured market data to Parquet or CSV
+#[argh(subcommand, name = "export")]
+pub struct ExportOptions {
+    /// capture file or directory of them to export instead of the table
+    #[argh(option)]
+    path: Option<String>,
+    /// market data table to export
+    #[argh(option, default = "String::from(\"marketDataSorted\")")]
+    table: String,
+    /// start of the exported range (UTC), e.g. "2024-11-26 00:00:00"
+    #[argh(option)]
+    from: Option<String>,
+    /// end of the exported range (UTC), exclusive
+    #[argh(option)]
+    to: Option<String>,
+    /// product to export (can be repeated), all by default
+    #[argh(option)]
+    product: Vec<String>,
+    /// event type to export: snapshot, depth or trade (can be repeated)
+    #[argh(option)]
+    event_type: Vec<String>,
+    /// output format: parquet or csv
+    #[argh(option, default = "Format::Parquet")]
+    format: Format,
+    /// output directory, partitioned as `product=.../date=.../events.*`
+    #[argh(option)]
+    output: String,
+}
+
+pub async fn run(client: &Client, options: ExportOptions) -> Result<()> {
+    let mut filter = EventFilter::new(options.from.as_deref(), options.to.as_deref())?;
+    filter.products = options.product;
+    filter.event_types = options.event_type;
+    let mut source = EventSource::open(client, options.path.as_deref(), &options.table, filter)?;
+
+    let mut exporter = Exporter::new(Path::new(&options.output), options.format);
+    while let Some(event) = source.next().await? {
+        exporter.write(&event)?;
+    }
+    let (rows, files) = exporter.finish()?;
+    println!(
+        "Exported {} rows into {} files under {}",
+        rows, files, options.output
+    );
+    Ok(())
+}
+
+/// Writes events into `product=.../date=...` partitions. Events come
+/// ordered by time, so a partition is closed as soon as an event of a
+/// later date arrives. An event of a closed partition, e.g. one received
+/// late, goes to another file of it, `events.1.parquet` and so on.
+struct Exporter {
+    output: PathBuf,
+    format: Format,
+    schema: SchemaRef,
+    partitions: HashMap<(String, String), Partition>,
+    /// Number of files of each partition created so far.
+    files: HashMap<(String, String), usize>,
+    last_date: String,
+    rows: u64,
+}
+
+impl Exporter {
+    fn new(output: &Path, format: Format) -> Self {
+        Self {
+            output: output.to_path_buf(),
+            format,
+            schema: parquet_schema(),
+            partitions: HashMap::new(),
+            files: HashMap::new(),
+            last_date: String::new(),
+            rows: 0,
+        }
+    }
+
+    fn write(&mut self, event: &Event) -> Result<()> {
+        let Some(date) = DateTime::from_timestamp_millis(event.gate_timestamp) else {
+            bail!("invalid gate_timestamp {}", event.gate_timestamp);
+        };
+        let date = date.format("%Y-%m-%d").to_string();
+        if date > self.last_date {
+            let closed: Vec<_> = self
+                .partitions
+                .keys()
+                .filter(|(_, partition_date)| *partition_date < date)
+                .cloned()
+                .collect();
+            for key in closed {
+                if let Some(partition) = self.partitions.remove(&key) {
+                    partition.close(&self.schema)?;
+                }
+            }
+            self.last_date.clone_from(&date);
+        }
+        let key = (event.product.clone(), date);
+        let partition = match self.partitions.get_mut(&key) {
+            Some(partition) => partition,
+            None => {
+                let dir = self
+                    .output
+                    .join(format!("product={}", key.0))
+                    .join(format!("date={}", key.1));
+                create_dir_all(&dir)?;
+                let file = self.files.entry(key.clone()).or_default();
+                let partition = Partition::create(&dir, *file, self.format, &self.schema)?;
+                *file += 1;
+                self.partitions.entry(key).or_insert(partition)
+            }
+        };
+        partition.write(event, &self.schema)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Closes the open partitions, returning the number of rows and files
+    /// written.
+    fn finish(self) -> Result<(u64, usize)> {
+        for partition in self.partitions.into_values() {
+            partition.close(&self.schema)?;
+        }
+        Ok((self.rows, self.files.values().sum()))
+    }
+}
+
+/// Columns of the Parquet files. `product` and the date are encoded in the
+/// partition path instead.
+fn parquet_schema() -> SchemaRef {
+    let timestamp = DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into()));
+    Arc::new(Schema::new(vec![
+        Field::new("local_unique_id", DataType::Int64, false),
+        Field::new("venue_timestamp", timestamp.clone(), false),
+        Field::new("gate_timestamp", timestamp, false),
+        Field::new("event_type", DataType::Utf8, false),
+        Field::new("id1", DataType::UInt64, true),
+        Field::new("id2", DataType::UInt64, true),
+        Field::new("ask_not_bid", DataType::Boolean, true),
+        Field::new("buy_not_sell", DataType::Boolean, true),
+        Field::new("price", DataType::Decimal128(PRECISION, SCALE as i8), true),
+        Field::new(
+            "quantity",
+            DataType::Decimal128(PRECISION, SCALE as i8),
+            true,
+        ),
+    ]))
+}
+
+enum Partition {
+    Parquet {
+        writer: ArrowWriter<File>,
+        columns: Box<Columns>,
+    },
+    Csv(csv::Writer<File>),
+}
+
+impl Partition {
+    /// Creates file number `file` of the partition in `dir`.
+    fn create(dir: &Path, file: usize, format: Format, schema: &SchemaRef) -> Result<Self> {
+        Ok(match format {
+            Format::Parquet => {
+                let file = File::create(file_path(dir, file, "parquet"))?;
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Partition::Parquet {
+                    writer: ArrowWriter::try_new(file, schema.clone(), Some(properties))?,
+                    columns: Box::default(),
+                }
+            }
+            Format::Csv => Partition::Csv(csv::Writer::from_path(file_path(dir, file, "csv"))?),
+        })
+    }
+
+    fn write(&mut self, event: &Event, schema: &SchemaRef) -> Result<()> {
+        match self {
+            Partition::Parquet { writer, columns } => {
+                columns.push(event);
+                if columns.rows >= BATCH_ROWS {
+                    writer.write(&columns.finish(schema)?)?;
+                }
+            }
+            Partition::Csv(writer) => writer.serialize(event)?,
+        }
+        Ok(())
+    }
+
+    fn close(self, schema: &SchemaRef) -> Result<()> {
+        match self {
+            Partition::Parquet {
+                mut writer,
+                mut columns,
+            } => {
+                if columns.rows > 0 {
+                    writer.write(&columns.finish(schema)?)?;
+                }
+                writer.close()?;
+            }
+            Partition::Csv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn file_path(dir: &Path, file: usize, extension: &str) -> PathBuf {
+    match file {
+        0 => dir.join(format!("events.{}", extension)),
+        file => dir.join(format!("events.{}.{}", file, extension)),
+    }
+}
+
+/// `value` in units of the last of [`SCALE`] decimal digits.
+fn scaled(value: &str) -> Option<i128> {
+    let value = Decimal::from_str(value).ok()?.round(SCALE as i8);
+    value
+        .coefficient()
+        .checked_mul(10i128.pow((SCALE - value.n_frac_digits()) as u32))
+}
+
+#[derive(Default)]
+struct Columns {
+    rows: usize,
+    local_unique_id: Int64Builder,
+    venue_timestamp: TimestampMillisecondBuilder,
+    gate_timestamp: TimestampMillisecondBuilder,
+    event_type: StringBuilder,
+    id1: UInt64Builder,
+    id2: UInt64Builder,
+    ask_not_bid: BooleanBuilder,
+    buy_not_sell: BooleanBuilder,
+    price: Decimal128Builder,
+    quantity: Decimal128Builder,
+}
+
+impl Columns {
+    fn push(&mut self, event: &Event) {
+        self.rows += 1;
+        self.local_unique_id.append_value(event.local_unique_id);
+        self.venue_timestamp.append_value(event.venue_timestamp);
+        self.gate_timestamp.append_value(event.gate_timestamp);
+        self.event_type.append_value(&event.event_type);
+        self.id1.append_option(event.id1);
+        self.id2.append_option(event.id2);
+        self.ask_not_bid.append_option(event.ask_not_bid);
+        self.buy_not_sell.append_option(event.buy_not_sell);
+        self.price.append_option(scaled(&event.price));
+        self.quantity.append_option(scaled(&event.quantity));
+    }
+
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch> {
+        self.rows = 0;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.local_unique_id.finish()),
+            Arc::new(self.venue_timestamp.finish().with_timezone("UTC")),
+            Arc::new(self.gate_timestamp.finish().with_timezone("UTC")),
+            Arc::new(self.event_type.finish()),
+            Arc::new(self.id1.finish()),
+            Arc::new(self.id2.finish()),
+            Arc::new(self.ask_not_bid.finish()),
+            Arc::new(self.buy_not_sell.finish()),
+            Arc::new(
+                self.price
+                    .finish()
+                    .with_precision_and_scale(PRECISION, SCALE as i8)?,
+            ),
+            Arc::new(
+                self.quantity
+                    .finish()
+                    .with_precision_and_scale(PRECISION, SCALE as i8)?,
+            ),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{cast::AsArray, types::Decimal128Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    /// 2024-11-26 00:00:00 UTC.
+    const DAY: i64 = 1_732_579_200_000;
+
+    fn trade(product: &str, gate_timestamp: i64, price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp - 3,
+            gate_timestamp,
+            event_type: String::from("trade"),
+            product: product.to_string(),
+            id1: Some(gate_timestamp as u64),
+            id2: None,
+            ask_not_bid: None,
+            buy_not_sell: Some(true),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    /// Prices and quantities of a Parquet file.
+    fn read(path: &Path) -> Vec<(i128, i128)> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut rows = Vec::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            let price = batch
+                .column_by_name("price")
+                .unwrap()
+                .as_primitive::<Decimal128Type>();
+            let quantity = batch
+                .column_by_name("quantity")
+                .unwrap()
+                .as_primitive::<Decimal128Type>();
+            assert_eq!(price.scale(), SCALE as i8);
+            rows.extend(
+                price
+                    .values()
+                    .iter()
+                    .copied()
+                    .zip(quantity.values().iter().copied()),
+            );
+        }
+        rows
+    }
+
+    #[test]
+    fn partitions_round_trip_with_exact_decimals() {
+        let output = std::env::temp_dir().join(format!("export-{}", std::process::id()));
+        let mut exporter = Exporter::new(&output, Format::Parquet);
+        for event in [
+            trade("BTCUSDT", DAY + 1, "95123.45678901", "0.00012345"),
+            trade("ETHUSDT", DAY + 2, "3312.1", "1.5"),
+            trade("BTCUSDT", DAY + 86_400_000, "95200", "0.1"),
+            trade("BTCUSDT", DAY + 3, "95123.5", "2"),
+        ] {
+            exporter.write(&event).unwrap();
+        }
+        assert_eq!(exporter.partitions.len(), 2);
+        assert_eq!(exporter.finish().unwrap(), (4, 4));
+
+        let day = output.join("product=BTCUSDT").join("date=2024-11-26");
+        assert_eq!(
+            read(&day.join("events.parquet")),
+            [(9_512_345_678_901, 12_345)]
+        );
+        assert_eq!(
+            read(&day.join("events.1.parquet")),
+            [(9_512_350_000_000, 200_000_000)]
+        );
+        let eth = output.join("product=ETHUSDT").join("date=2024-11-26");
+        assert_eq!(
+            read(&eth.join("events.parquet")),
+            [(331_210_000_000, 150_000_000)]
+        );
+        std::fs::remove_dir_all(&output).unwrap();
+        assert_eq!(scaled("bad"), None);
+    }
+}
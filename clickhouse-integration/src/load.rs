@@ -0,0 +1,253 @@
+use anyhow::Result;
+use argh::FromArgs;
+use clickhouse::{sql::Identifier, Client};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    event::{open_capture, EventRef},
+    source::capture_files,
+};
+
+#[derive(FromArgs)]
+/// Load capture files into the raw market data table
+#[argh(subcommand, name = "load")]
+pub struct LoadOptions {
+    /// directory with the `.bin` (or archived `.bin.gz`) capture files
+    #[argh(option, default = "String::from(\"/srv/storage/marketdata/\")")]
+    path: String,
+    /// raw market data table
+    #[argh(option, default = "String::from(\"marketDataUnprocessed\")")]
+    table: String,
+    /// number of files loaded concurrently
+    #[argh(option, default = "4")]
+    workers: usize,
+    /// size of a single insert in MiB of capture data
+    #[argh(option, default = "64")]
+    batch_size: usize,
+}
+
+struct FileSummary {
+    path: PathBuf,
+    rows: u64,
+    bytes: u64,
+    elapsed: Duration,
+    error: Option<String>,
+}
+
+pub async fn run(client: &Client, options: LoadOptions) -> Result<()> {
+    let table_name = options.table.as_str();
+    client
+        .query(
+            r#"
+            CREATE TABLE IF NOT EXISTS ? (
+                local_unique_id Int64,
+                venue_timestamp DateTime64(3, 'UTC'),
+                gate_timestamp  DateTime64(3, 'UTC'),
+                event_type      String,
+                product         String,
+                id1             Nullable(UInt64),
+                id2             Nullable(UInt64),
+                ask_not_bid     Nullable(Bool),
+                buy_not_sell    Nullable(Bool),
+                price           String,
+                quantity        String
+            )
+            ENGINE = MergeTree
+            ORDER BY (local_unique_id, venue_timestamp)
+            PARTITION BY toYYYYMMDD(gate_timestamp)
+            "#,
+        )
+        .bind(Identifier(table_name))
+        .execute()
+        .await?;
+
+    let files = capture_files(Path::new(&options.path))?;
+
+    let start_instant = Instant::now();
+    let progress = MultiProgress::new();
+    let style = ProgressStyle::with_template(
+        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta}) {msg}",
+    )
+    .unwrap()
+    .progress_chars("##-");
+    let workers = Arc::new(Semaphore::new(options.workers.max(1)));
+    let batch_size = options.batch_size.max(1) << 20;
+    let mut tasks = JoinSet::new();
+    for (index, file_path) in files.into_iter().enumerate() {
+        let total_size = file_path
+            .metadata()
+            .expect("Unable to get file metadata")
+            .len();
+        let pb = progress.add(ProgressBar::new(total_size).with_style(style.clone()));
+        pb.set_message(file_name(&file_path));
+        let client = client.clone();
+        let table_name = options.table.clone();
+        let workers = workers.clone();
+        tasks.spawn(async move {
+            let _permit = workers.acquire_owned().await.expect("semaphore closed");
+            let summary = load_file(client, table_name, file_path, pb, batch_size).await;
+            summary.map(|summary| (index, summary))
+        });
+    }
+
+    let mut summaries = Vec::new();
+    while let Some(summary) = tasks.join_next().await {
+        summaries.push(summary.expect("load task panicked")?);
+    }
+    summaries.sort_by_key(|(index, _)| *index);
+    let summaries: Vec<FileSummary> = summaries.into_iter().map(|(_, summary)| summary).collect();
+    print_summary(&summaries, start_instant.elapsed());
+    println!("All files have been processed");
+    Ok(())
+}
+
+/// Streams a capture file into `table_name`, one insert per `batch_size`
+/// bytes. Reading happens on a blocking thread so the next chunk is read
+/// while the previous one is being inserted.
+async fn load_file(
+    client: Client,
+    table_name: String,
+    file_path: PathBuf,
+    pb: ProgressBar,
+    batch_size: usize,
+) -> Result<FileSummary> {
+    let start_instant = Instant::now();
+    let (chunks_tx, mut chunks_rx) = mpsc::channel::<std::io::Result<Vec<u8>>>(2);
+    let reader_path = file_path.clone();
+    let reader_pb = pb.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut reader = match File::open(&reader_path) {
+            Ok(file) => open_capture(&reader_path, reader_pb.wrap_read(file)),
+            Err(err) => {
+                let _ = chunks_tx.blocking_send(Err(err));
+                return;
+            }
+        };
+        loop {
+            let mut chunk = Vec::with_capacity(batch_size);
+            match (&mut reader)
+                .take(batch_size as u64)
+                .read_to_end(&mut chunk)
+            {
+                Ok(0) => break,
+                Ok(_) => {
+                    if chunks_tx.blocking_send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = chunks_tx.blocking_send(Err(err));
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut summary = FileSummary {
+        path: file_path,
+        rows: 0,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+        error: None,
+    };
+    let mut pending: Vec<u8> = Vec::new();
+    while let Some(chunk) = chunks_rx.recv().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                summary.error = Some(err.to_string());
+                break;
+            }
+        };
+        summary.bytes += chunk.len() as u64;
+        pending.extend_from_slice(&chunk);
+
+        let mut offset = 0;
+        let mut insert = client.insert(&table_name)?;
+        loop {
+            match EventRef::decode(&pending[offset..]) {
+                Ok(Some((event, len))) => {
+                    insert.write(&event).await?;
+                    offset += len;
+                    summary.rows += 1;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    summary.error = Some(err.to_string());
+                    break;
+                }
+            }
+        }
+        insert.end().await?;
+        pending.drain(..offset);
+        if summary.error.is_some() {
+            break;
+        }
+    }
+    if summary.error.is_none() && !pending.is_empty() {
+        summary.error = Some(format!(
+            "truncated event of {} bytes at the end",
+            pending.len()
+        ));
+    }
+    summary.elapsed = start_instant.elapsed();
+    match &summary.error {
+        Some(err) => {
+            pb.abandon_with_message(format!("{}: {}", file_name(&summary.path), err));
+        }
+        None => pb.finish(),
+    }
+    Ok(summary)
+}
+
+fn print_summary(summaries: &[FileSummary], elapsed: Duration) {
+    println!(
+        "{:<32} {:>12} {:>10} {:>10} {:>12}",
+        "file", "rows", "MiB", "seconds", "rows/s"
+    );
+    let mut total_rows = 0;
+    let mut total_bytes = 0;
+    for summary in summaries {
+        let seconds = summary.elapsed.as_secs_f64();
+        println!(
+            "{:<32} {:>12} {:>10.1} {:>10.1} {:>12.0}{}",
+            file_name(&summary.path),
+            summary.rows,
+            summary.bytes as f64 / (1 << 20) as f64,
+            seconds,
+            summary.rows as f64 / seconds,
+            summary
+                .error
+                .as_ref()
+                .map(|err| format!("  error: {}", err))
+                .unwrap_or_default(),
+        );
+        total_rows += summary.rows;
+        total_bytes += summary.bytes;
+    }
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "Loaded {} rows ({:.1} MiB) from {} files in {:.1}s: {:.0} rows/s, {:.1} MiB/s",
+        total_rows,
+        total_bytes as f64 / (1 << 20) as f64,
+        summaries.len(),
+        seconds,
+        total_rows as f64 / seconds,
+        total_bytes as f64 / (1 << 20) as f64 / seconds,
+    );
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
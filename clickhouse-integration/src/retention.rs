@@ -0,0 +1,505 @@
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use chrono::Utc;
+use clickhouse::{sql::Identifier, Client, Row};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+use std::io::{copy, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    book::Book,
+    event::{is_capture_file, Event},
+    source::capture_start,
+};
+
+const SECOND: i64 = 1_000;
+const DAY: Duration = Duration::from_secs(86_400);
+
+#[derive(FromArgs)]
+/// Apply retention policies to the market data tables and capture files
+#[argh(subcommand, name = "retention")]
+pub struct RetentionOptions {
+    /// table replayed to build the 1s top-of-book aggregates
+    #[argh(option, default = "String::from(\"marketDataSorted\")")]
+    source: String,
+    /// table with the 1s top-of-book aggregates, kept forever
+    #[argh(option, default = "String::from(\"marketDataTopOfBook1s\")")]
+    top_of_book: String,
+    /// table to apply the TTL rules to (can be repeated), both market data
+    /// tables by default
+    #[argh(option)]
+    table: Vec<String>,
+    /// days to keep depth and snapshot events for, trades are kept forever
+    #[argh(option)]
+    keep_depth_days: Option<u32>,
+    /// storage volume to move partitions to after `--cold-after-days`
+    #[argh(option)]
+    cold_volume: Option<String>,
+    /// days after which partitions are moved to `--cold-volume`
+    #[argh(option, default = "30")]
+    cold_after_days: u32,
+    /// directory with the capture files of the collector
+    #[argh(option)]
+    path: Option<String>,
+    /// directory for compressed capture files, `<path>/archive` by default
+    #[argh(option)]
+    archive: Option<String>,
+    /// days after which capture files are compressed into the archive
+    #[argh(option, default = "7")]
+    archive_after_days: u64,
+    /// days after which archived capture files are deleted, never by default
+    #[argh(option)]
+    delete_after_days: Option<u64>,
+}
+
+/// Top of the book at the end of a second in which the book changed.
+#[derive(Debug, Serialize, Row)]
+struct TopOfBook {
+    product: String,
+    timestamp: i64,
+    bid_price: Option<String>,
+    bid_quantity: Option<String>,
+    ask_price: Option<String>,
+    ask_quantity: Option<String>,
+}
+
+pub async fn run(client: &Client, options: RetentionOptions) -> Result<()> {
+    // Without a TTL the tables keep their depth, so there is nothing to
+    // downsample before it is gone.
+    if ttl(&options).is_some() {
+        downsample(client, &options.source, &options.top_of_book).await?;
+    }
+
+    let tables = if options.table.is_empty() {
+        vec![
+            "marketDataUnprocessed".to_string(),
+            "marketDataSorted".to_string(),
+        ]
+    } else {
+        options.table.clone()
+    };
+    for table in &tables {
+        apply_ttl(client, table, &options).await?;
+    }
+
+    if let Some(path) = &options.path {
+        let archive = options
+            .archive
+            .clone()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(path).join("archive"));
+        archive_files(
+            Path::new(path),
+            &archive,
+            days(options.archive_after_days)?,
+            options.delete_after_days.map(days).transpose()?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Builds the top-of-book aggregates of every finished day that does not
+/// have them yet. This has to run before the depth TTL removes the day.
+async fn downsample(client: &Client, source: &str, target: &str) -> Result<()> {
+    client
+        .query(
+            r#"
+            CREATE TABLE IF NOT EXISTS ? (
+                product      String,
+                timestamp    DateTime64(3, 'UTC'),
+                bid_price    Nullable(String),
+                bid_quantity Nullable(String),
+                ask_price    Nullable(String),
+                ask_quantity Nullable(String)
+            )
+            ENGINE = MergeTree
+            ORDER BY (product, timestamp)
+            PARTITION BY toYYYYMM(timestamp)
+            "#,
+        )
+        .bind(Identifier(target))
+        .execute()
+        .await?;
+
+    let today = Utc::now().format("%Y-%m-%d").to_string();
+    let built_days = table_days(client, "timestamp", target).await?;
+    let days: Vec<String> = table_days(client, "gate_timestamp", source)
+        .await?
+        .into_iter()
+        .filter(|day| *day < today && !built_days.contains(day))
+        .collect();
+    for day in days {
+        println!("Building {} for {}", target, day);
+        // Snapshots are taken hourly, replaying from two hours before the day
+        // starts gives every product a book by midnight.
+        let mut cursor = client
+            .query(
+                "SELECT ?fields FROM ? \
+                 WHERE gate_timestamp >= toDateTime64(?, 3, 'UTC') - INTERVAL 2 HOUR \
+                 AND toDate(gate_timestamp) <= toDate(?) \
+                 ORDER BY product, gate_timestamp, venue_timestamp, event_type, \
+                 ifNull(if(event_type = 'depth', id2, id1), 0), local_unique_id",
+            )
+            .bind(Identifier(source))
+            .bind(&day)
+            .bind(&day)
+            .fetch::<Event>()?;
+        let day_start = chrono::NaiveDate::parse_from_str(&day, "%Y-%m-%d")?
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight is a valid time")
+            .and_utc()
+            .timestamp_millis();
+
+        let mut insert = client.insert::<TopOfBook>(target)?;
+        let mut downsampler = Downsampler::new(day_start);
+        while let Some(event) = cursor.next().await? {
+            if let Some(top) = downsampler.on_event(&event) {
+                insert.write(&top).await?;
+            }
+        }
+        if let Some(top) = downsampler.finish() {
+            insert.write(&top).await?;
+        }
+        insert.end().await?;
+    }
+    Ok(())
+}
+
+/// Replays the events of a day ordered by product and time into the top of
+/// the book at the end of each second in which it changed.
+struct Downsampler {
+    /// Seconds before it only build the books.
+    day_start: i64,
+    book: Book,
+    product: String,
+    snapshot_id: Option<u64>,
+    /// Second of the last change of the book not reported yet.
+    second: Option<i64>,
+}
+
+impl Downsampler {
+    fn new(day_start: i64) -> Self {
+        Self {
+            day_start,
+            book: Book::default(),
+            product: String::new(),
+            snapshot_id: None,
+            second: None,
+        }
+    }
+
+    /// Top of the book of the last second that changed it, once `event`
+    /// closes that second.
+    fn on_event(&mut self, event: &Event) -> Option<TopOfBook> {
+        let event_second = event.gate_timestamp.div_euclid(SECOND) * SECOND;
+        let mut top = None;
+        if event.product != self.product || Some(event_second) != self.second {
+            top = self.finish();
+        }
+        if event.product != self.product {
+            self.product.clone_from(&event.product);
+            self.book.clear();
+            self.snapshot_id = None;
+        }
+        match event.event_type.as_str() {
+            "snapshot" => {
+                if self.snapshot_id != event.id1 {
+                    self.snapshot_id = event.id1;
+                    self.book.clear();
+                }
+                self.book.apply(event);
+            }
+            "depth" if self.snapshot_id.is_some() => self.book.apply(event),
+            _ => return top,
+        }
+        self.second = Some(event_second);
+        top
+    }
+
+    /// Top of the book of the last second that changed it, if that second
+    /// is in the day.
+    fn finish(&mut self) -> Option<TopOfBook> {
+        let second = self.second.take()?;
+        (second >= self.day_start).then(|| top_of_book(&self.product, second, &self.book))
+    }
+}
+
+fn top_of_book(product: &str, timestamp: i64, book: &Book) -> TopOfBook {
+    let best_bid = book.best_bid();
+    let best_ask = book.best_ask();
+    TopOfBook {
+        product: product.to_string(),
+        timestamp,
+        bid_price: best_bid.map(|(price, _)| price.to_string()),
+        bid_quantity: best_bid.map(|(_, quantity)| quantity.to_string()),
+        ask_price: best_ask.map(|(price, _)| price.to_string()),
+        ask_quantity: best_ask.map(|(_, quantity)| quantity.to_string()),
+    }
+}
+
+/// Duration of `days` days.
+fn days(days: u64) -> Result<Duration> {
+    let days = u32::try_from(days).with_context(|| format!("{} days is too long", days))?;
+    Ok(DAY * days)
+}
+
+async fn table_days(client: &Client, column: &str, table: &str) -> Result<BTreeSet<String>> {
+    let days = client
+        .query("SELECT DISTINCT toString(toDate(?)) FROM ?")
+        .bind(Identifier(column))
+        .bind(Identifier(table))
+        .fetch_all::<String>()
+        .await?;
+    Ok(days.into_iter().collect())
+}
+
+/// Sets the TTL of `table` unless it already has it: modifying the TTL
+/// makes ClickHouse materialize it over the whole table again.
+async fn apply_ttl(client: &Client, table: &str, options: &RetentionOptions) -> Result<()> {
+    let Some(ttl) = ttl(options) else {
+        return Ok(());
+    };
+    let create_table_query = client
+        .query(
+            "SELECT create_table_query FROM system.tables \
+             WHERE database = currentDatabase() AND name = ?",
+        )
+        .bind(table)
+        .fetch_optional::<String>()
+        .await?
+        .with_context(|| format!("table {} does not exist", table))?;
+    if current_ttl(&create_table_query) == Some(ttl.as_str()) {
+        return Ok(());
+    }
+    println!("Setting TTL of {}: {}", table, ttl);
+    client
+        .query(&format!("ALTER TABLE ? MODIFY TTL {}", ttl))
+        .bind(Identifier(table))
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// TTL clause of the options, written the way ClickHouse shows it in
+/// `create_table_query` so that it can be compared with the current one.
+fn ttl(options: &RetentionOptions) -> Option<String> {
+    let mut rules = Vec::new();
+    if let Some(days) = options.keep_depth_days {
+        rules.push(format!(
+            "toDateTime(gate_timestamp) + toIntervalDay({}) WHERE event_type != 'trade'",
+            days
+        ));
+    }
+    if let Some(volume) = &options.cold_volume {
+        rules.push(format!(
+            "toDateTime(gate_timestamp) + toIntervalDay({}) TO VOLUME '{}'",
+            options.cold_after_days,
+            volume.replace('\\', "\\\\").replace('\'', "\\'")
+        ));
+    }
+    (!rules.is_empty()).then(|| rules.join(", "))
+}
+
+/// TTL clause of a `CREATE TABLE` query, which ClickHouse puts between the
+/// sorting key and the settings.
+fn current_ttl(create_table_query: &str) -> Option<&str> {
+    let (_, ttl) = create_table_query.split_once(" TTL ")?;
+    Some(ttl.split(" SETTINGS ").next().unwrap_or(ttl).trim())
+}
+
+/// Compresses capture files older than `archive_after` into `archive` and
+/// deletes archived files captured more than `delete_after` ago. The age of
+/// a capture file is taken from its modification time, so the file the
+/// collector is writing is never touched, the age of an archive from the
+/// hour in its name, as archiving rewrites the file.
+fn archive_files(
+    path: &Path,
+    archive: &Path,
+    archive_after: Duration,
+    delete_after: Option<Duration>,
+) -> Result<()> {
+    create_dir_all(archive)?;
+    let now = SystemTime::now();
+    let age = |file: &Path| -> Result<Duration> {
+        let modified = file.metadata()?.modified()?;
+        Ok(now.duration_since(modified).unwrap_or_default())
+    };
+
+    for entry in read_dir(path).with_context(|| format!("failed to read {:?}", path))? {
+        let file = entry?.path();
+        if !is_capture_file(&file) || file.extension().and_then(|ext| ext.to_str()) != Some("bin") {
+            continue;
+        }
+        if age(&file)? < archive_after {
+            continue;
+        }
+        let name = file.file_name().expect("capture files have names");
+        let target = archive.join(format!("{}.gz", name.to_string_lossy()));
+        let partial = archive.join(format!("{}.gz.partial", name.to_string_lossy()));
+        println!("Archiving {:?} to {:?}", file, target);
+        let mut encoder =
+            GzEncoder::new(BufWriter::new(File::create(&partial)?), Compression::best());
+        copy(&mut BufReader::new(File::open(&file)?), &mut encoder)?;
+        encoder.finish()?.into_inner()?.sync_all()?;
+        rename(&partial, &target)?;
+        remove_file(&file)?;
+    }
+
+    if let Some(delete_after) = delete_after {
+        for entry in read_dir(archive)? {
+            let file = entry?.path();
+            let name = file.to_string_lossy();
+            if !file.is_file() || !name.ends_with(".bin.gz") {
+                continue;
+            }
+            let captured = match capture_start(&file) {
+                Some(start) => now
+                    .duration_since(SystemTime::from(start.and_utc()))
+                    .unwrap_or_default(),
+                None => age(&file)?,
+            };
+            if captured >= delete_after {
+                println!("Deleting {:?}", file);
+                remove_file(&file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    const DAY_START: i64 = 1_732_579_200_000;
+
+    fn event(
+        event_type: &str,
+        second: i64,
+        id: u64,
+        ask: bool,
+        price: &str,
+        quantity: &str,
+    ) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: DAY_START + second * SECOND,
+            gate_timestamp: DAY_START + second * SECOND,
+            event_type: event_type.to_string(),
+            product: String::from("BTCUSDT"),
+            id1: Some(id),
+            id2: Some(id),
+            ask_not_bid: Some(ask),
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    #[test]
+    fn downsamples_the_seconds_the_book_changed_in() {
+        let mut downsampler = Downsampler::new(DAY_START);
+        let events = [
+            event("depth", -10, 1, false, "90", "1"),
+            event("snapshot", -5, 7, false, "99", "1"),
+            event("snapshot", -5, 7, true, "101", "2"),
+            event("depth", 0, 8, false, "100", "3"),
+            event("depth", 0, 9, true, "101", "0"),
+            event("trade", 1, 5, false, "100", "1"),
+            event("depth", 3, 10, true, "102", "4"),
+        ];
+        let mut tops: Vec<TopOfBook> = events
+            .iter()
+            .filter_map(|event| downsampler.on_event(event))
+            .collect();
+        tops.extend(downsampler.finish());
+        let tops: Vec<(i64, Option<&str>, Option<&str>)> = tops
+            .iter()
+            .map(|top| {
+                (
+                    (top.timestamp - DAY_START) / SECOND,
+                    top.bid_price.as_deref(),
+                    top.ask_price.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            tops,
+            [(0, Some("100"), None), (3, Some("100"), Some("102"))]
+        );
+    }
+
+    #[test]
+    fn ttl_is_compared_with_the_current_one() {
+        let options = RetentionOptions::from_args(
+            &["retention"],
+            &["--keep-depth-days", "14", "--cold-volume", "cold"],
+        )
+        .unwrap();
+        let ttl = ttl(&options).unwrap();
+        let create_table_query = format!(
+            "CREATE TABLE default.marketDataSorted (`gate_timestamp` DateTime64(3, 'UTC')) \
+             ENGINE = MergeTree PARTITION BY toYYYYMMDD(gate_timestamp) ORDER BY gate_timestamp \
+             TTL {} SETTINGS index_granularity = 8192",
+            ttl
+        );
+        assert_eq!(current_ttl(&create_table_query), Some(ttl.as_str()));
+        assert!(ttl.ends_with("toIntervalDay(30) TO VOLUME 'cold'"));
+        assert_eq!(current_ttl("CREATE TABLE t (x UInt8) ENGINE = Log"), None);
+        let options = RetentionOptions::from_args(&["retention"], &[]).unwrap();
+        assert_eq!(super::ttl(&options), None);
+        assert!(days(u64::from(u32::MAX) + 1).is_err());
+        assert_eq!(days(2).unwrap(), DAY * 2);
+    }
+
+    #[test]
+    fn archives_old_capture_files_and_deletes_old_archives() {
+        let path = std::env::temp_dir().join(format!("retention-{}", std::process::id()));
+        let archive = path.join("archive");
+        create_dir_all(&archive).unwrap();
+        let old = SystemTime::now() - DAY * 10;
+        let captured = |days: i64| {
+            let start = Utc::now() - chrono::Duration::days(days);
+            start.format("%d-%m-%Y %H-00-00.bin").to_string()
+        };
+        // Archived now, captured three days ago.
+        let archived = captured(3);
+        for name in [archived.as_str(), "notes.txt"] {
+            File::create(path.join(name))
+                .unwrap()
+                .set_modified(old)
+                .unwrap();
+        }
+        std::fs::write(path.join("26-11-2024 05-00-00.bin"), b"events").unwrap();
+        // Captured long ago, archived recently.
+        std::fs::write(archive.join("01-11-2024 05-00-00.bin.gz"), b"archived").unwrap();
+        // Captured recently, with an old modification time.
+        let recent = format!("{}.gz", captured(2));
+        File::create(archive.join(&recent))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        archive_files(&path, &archive, DAY * 7, Some(DAY * 5)).unwrap();
+        let mut names: Vec<String> = read_dir(&archive)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let mut expected = vec![format!("{}.gz", archived), recent];
+        expected.sort();
+        assert_eq!(names, expected);
+        let mut names: Vec<String> = read_dir(&path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(names, ["26-11-2024 05-00-00.bin", "archive", "notes.txt"]);
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::Result;
+use argh::FromArgs;
+use chrono::{Datelike, NaiveDate};
+use clickhouse::{sql::Identifier, Client};
+use std::collections::BTreeSet;
+
+#[derive(FromArgs)]
+/// Build the deduplicated, ordered table replayed by `marketdata-player`
+#[argh(subcommand, name = "build-sorted")]
+pub struct BuildSortedOptions {
+    /// raw market data table
+    #[argh(option, default = "String::from(\"marketDataUnprocessed\")")]
+    source: String,
+    /// sorted market data table
+    #[argh(option, default = "String::from(\"marketDataSorted\")")]
+    target: String,
+    /// rebuild every day, not only the new ones
+    #[argh(switch)]
+    force: bool,
+    /// rebuild only the given day, e.g. "2024-11-26" (can be repeated)
+    #[argh(option, from_str_fn(parse_day))]
+    day: Vec<NaiveDate>,
+}
+
+fn parse_day(day: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|_| format!("invalid day {:?}, expected YYYY-MM-DD", day))
+}
+
+pub async fn run(client: &Client, options: BuildSortedOptions) -> Result<()> {
+    let source = options.source.as_str();
+    let target = options.target.as_str();
+    // Days are built here and swapped into the target, so it never misses
+    // a day while it is rebuilt.
+    let staging = format!("{}_staging", target);
+    client
+        .query(
+            r#"
+            CREATE TABLE IF NOT EXISTS ? (
+                local_unique_id Int64,
+                venue_timestamp DateTime64(3, 'UTC'),
+                gate_timestamp  DateTime64(3, 'UTC'),
+                event_type      String,
+                product         String,
+                id1             Nullable(UInt64),
+                id2             Nullable(UInt64),
+                ask_not_bid     Nullable(Bool),
+                buy_not_sell    Nullable(Bool),
+                price           String,
+                quantity        String
+            )
+            ENGINE = MergeTree
+            ORDER BY (product, gate_timestamp, venue_timestamp, event_type, ifNull(if(event_type = 'depth', id2, id1), 0), local_unique_id)
+            PARTITION BY toYYYYMMDD(gate_timestamp)
+            "#,
+        )
+        .bind(Identifier(target))
+        .execute()
+        .await?;
+    client
+        .query("CREATE TABLE IF NOT EXISTS ? AS ?")
+        .bind(Identifier(&staging))
+        .bind(Identifier(target))
+        .execute()
+        .await?;
+
+    let source_days = days(client, source).await?;
+    let built_days = days(client, target).await?;
+    let rebuild = days_to_rebuild(source_days, &built_days, options.force, options.day);
+    if rebuild.is_empty() {
+        println!("{} is up to date", target);
+        return Ok(());
+    }
+
+    for day in rebuild {
+        println!("Building {} for {}", target, day);
+        // Left over by an interrupted build.
+        client
+            .query("ALTER TABLE ? DROP PARTITION ?")
+            .bind(Identifier(&staging))
+            .bind(partition(day))
+            .execute()
+            .await?;
+        // Events are deduplicated on their venue identity (the collector keeps
+        // two websocket connections open while reconnecting), depth diffs are
+        // kept only after a snapshot they can be applied to, and the day is
+        // written in replay order. Update ids are only compared within an
+        // event type: depth diffs are ordered by their last update id, trades
+        // by their trade id and snapshots by their last update id.
+        client
+            .query(
+                r#"
+                INSERT INTO ?
+                SELECT
+                    events.local_unique_id,
+                    events.venue_timestamp,
+                    events.gate_timestamp,
+                    events.event_type,
+                    events.product,
+                    events.id1,
+                    events.id2,
+                    events.ask_not_bid,
+                    events.buy_not_sell,
+                    events.price,
+                    events.quantity
+                FROM (
+                    SELECT *
+                    FROM ?
+                    WHERE toDate(gate_timestamp) = toDate(?)
+                    ORDER BY local_unique_id
+                    LIMIT 1 BY product, event_type, id1, id2, ask_not_bid, price
+                ) AS events
+                ASOF LEFT JOIN (
+                    SELECT product, gate_timestamp AS snapshot_timestamp, max(id1) AS snapshot_id
+                    FROM ?
+                    WHERE event_type = 'snapshot'
+                        AND toDate(gate_timestamp) BETWEEN toDate(?) - 1 AND toDate(?)
+                    GROUP BY product, gate_timestamp
+                ) AS snapshots
+                ON events.product = snapshots.product
+                    AND events.gate_timestamp >= snapshots.snapshot_timestamp
+                WHERE events.event_type != 'depth'
+                    OR (snapshots.snapshot_id > 0 AND events.id2 > snapshots.snapshot_id)
+                ORDER BY
+                    events.product,
+                    events.gate_timestamp,
+                    events.venue_timestamp,
+                    events.event_type,
+                    ifNull(if(events.event_type = 'depth', events.id2, events.id1), 0),
+                    events.local_unique_id
+                "#,
+            )
+            .bind(Identifier(&staging))
+            .bind(Identifier(source))
+            .bind(day.to_string())
+            .bind(Identifier(source))
+            .bind(day.to_string())
+            .bind(day.to_string())
+            .execute()
+            .await?;
+        client
+            .query("ALTER TABLE ? REPLACE PARTITION ? FROM ?")
+            .bind(Identifier(target))
+            .bind(partition(day))
+            .bind(Identifier(&staging))
+            .execute()
+            .await?;
+        client
+            .query("ALTER TABLE ? DROP PARTITION ?")
+            .bind(Identifier(&staging))
+            .bind(partition(day))
+            .execute()
+            .await?;
+    }
+    println!("{} has been built", target);
+    Ok(())
+}
+
+async fn days(client: &Client, table: &str) -> Result<BTreeSet<NaiveDate>> {
+    let days = client
+        .query("SELECT DISTINCT toString(toDate(gate_timestamp)) FROM ?")
+        .bind(Identifier(table))
+        .fetch_all::<String>()
+        .await?;
+    days.iter()
+        .map(|day| parse_day(day).map_err(anyhow::Error::msg))
+        .collect()
+}
+
+/// Days `days` if any are given, every day with `force`, otherwise the days
+/// missing from the target and the newest built one, which may still have
+/// been collecting when it was built.
+fn days_to_rebuild(
+    source_days: BTreeSet<NaiveDate>,
+    built_days: &BTreeSet<NaiveDate>,
+    force: bool,
+    days: Vec<NaiveDate>,
+) -> Vec<NaiveDate> {
+    if !days.is_empty() {
+        return days;
+    }
+    if force {
+        return source_days.into_iter().collect();
+    }
+    let last_built = built_days.last();
+    source_days
+        .into_iter()
+        .filter(|day| !built_days.contains(day) || Some(day) == last_built)
+        .collect()
+}
+
+/// `toYYYYMMDD` partition of `day`.
+fn partition(day: NaiveDate) -> u32 {
+    day.year() as u32 * 10_000 + day.month() * 100 + day.day()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(day: &str) -> NaiveDate {
+        parse_day(day).unwrap()
+    }
+
+    #[test]
+    fn parses_days_into_partitions() {
+        assert_eq!(partition(day("2024-11-26")), 20241126);
+        assert_eq!(partition(day("2025-01-05")), 20250105);
+        assert!(parse_day("26-11-2024").is_err());
+        assert!(parse_day("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn rebuilds_missing_days_and_the_last_built_one() {
+        let source: BTreeSet<NaiveDate> = ["2024-11-24", "2024-11-25", "2024-11-26", "2024-11-27"]
+            .map(day)
+            .into();
+        let built: BTreeSet<NaiveDate> = ["2024-11-24", "2024-11-25"].map(day).into();
+        assert_eq!(
+            days_to_rebuild(source.clone(), &built, false, Vec::new()),
+            ["2024-11-25", "2024-11-26", "2024-11-27"].map(day)
+        );
+        assert_eq!(
+            days_to_rebuild(source.clone(), &built, true, Vec::new()).len(),
+            4
+        );
+        assert_eq!(
+            days_to_rebuild(source.clone(), &built, true, vec![day("2024-11-24")]),
+            [day("2024-11-24")]
+        );
+        assert_eq!(
+            days_to_rebuild(source.clone(), &source, false, Vec::new()),
+            [day("2024-11-27")]
+        );
+    }
+}
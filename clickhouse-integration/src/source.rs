@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use clickhouse::{query::RowCursor, sql::Identifier, Client};
+use std::collections::VecDeque;
+use std::fs::read_dir;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::event::{is_capture_file, Event, EventReader};
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+/// 2100-01-01 00:00:00 UTC, the end of an unbounded range.
+const UNBOUNDED_TO: i64 = 4_102_444_800_000;
+
+/// Selection of the events to read, empty lists select everything.
+pub struct EventFilter {
+    pub from: i64,
+    pub to: i64,
+    pub products: Vec<String>,
+    pub event_types: Vec<String>,
+}
+
+impl EventFilter {
+    /// `from` and `to` bound `gate_timestamp`, e.g. "2024-11-26 05:50:00".
+    pub fn new(from: Option<&str>, to: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            from: from.map(parse_timestamp).transpose()?.unwrap_or(0),
+            to: to.map(parse_timestamp).transpose()?.unwrap_or(UNBOUNDED_TO),
+            products: Vec::new(),
+            event_types: Vec::new(),
+        })
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        event.gate_timestamp >= self.from
+            && event.gate_timestamp < self.to
+            && (self.products.is_empty() || self.products.contains(&event.product))
+            && (self.event_types.is_empty() || self.event_types.contains(&event.event_type))
+    }
+}
+
+/// Events selected by an [`EventFilter`], read either from capture files or
+/// from a table.
+pub enum EventSource {
+    Files {
+        files: VecDeque<PathBuf>,
+        reader: Option<EventReader<BufReader<Box<dyn Read + Send>>>>,
+        filter: EventFilter,
+    },
+    ClickHouse(RowCursor<Event>),
+}
+
+impl EventSource {
+    /// Reads `path`, a capture file or a directory of them, when it is given
+    /// and `table` otherwise.
+    pub fn open(
+        client: &Client,
+        path: Option<&str>,
+        table: &str,
+        filter: EventFilter,
+    ) -> Result<Self> {
+        if let Some(path) = path {
+            return Ok(EventSource::Files {
+                files: capture_files(Path::new(path))?.into(),
+                reader: None,
+                filter,
+            });
+        }
+        let mut query = String::from(
+            "SELECT ?fields FROM ? \
+             WHERE gate_timestamp >= fromUnixTimestamp64Milli(toInt64(?), 'UTC') \
+             AND gate_timestamp < fromUnixTimestamp64Milli(toInt64(?), 'UTC')",
+        );
+        if !filter.products.is_empty() {
+            query.push_str(" AND has(?, product)");
+        }
+        if !filter.event_types.is_empty() {
+            query.push_str(" AND has(?, event_type)");
+        }
+        query.push_str(" ORDER BY gate_timestamp, local_unique_id");
+        let mut query = client
+            .query(&query)
+            .bind(Identifier(table))
+            .bind(filter.from)
+            .bind(filter.to);
+        if !filter.products.is_empty() {
+            query = query.bind(&filter.products);
+        }
+        if !filter.event_types.is_empty() {
+            query = query.bind(&filter.event_types);
+        }
+        Ok(EventSource::ClickHouse(query.fetch::<Event>()?))
+    }
+
+    /// Whether the events come in the order the collector received them:
+    /// capture files are, the table is read in gate timestamp order.
+    pub fn arrival_order(&self) -> bool {
+        matches!(self, EventSource::Files { .. })
+    }
+
+    pub async fn next(&mut self) -> Result<Option<Event>> {
+        match self {
+            EventSource::Files {
+                files,
+                reader,
+                filter,
+            } => loop {
+                if let Some(events) = reader {
+                    match events.next() {
+                        Some(event) => {
+                            let event = event?;
+                            if filter.matches(&event) {
+                                return Ok(Some(event));
+                            }
+                            continue;
+                        }
+                        None => *reader = None,
+                    }
+                }
+                match files.pop_front() {
+                    Some(file) => {
+                        *reader = Some(
+                            EventReader::open(&file)
+                                .with_context(|| format!("failed to open {:?}", file))?,
+                        );
+                    }
+                    None => return Ok(None),
+                }
+            },
+            EventSource::ClickHouse(cursor) => Ok(cursor.next().await?),
+        }
+    }
+}
+
+/// Capture files under `path` in the order they were written. The collector
+/// names them after the hour they start at, e.g. "26-11-2024 05-00-00.bin",
+/// archived ones get an additional ".gz".
+pub fn capture_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in read_dir(path).with_context(|| format!("failed to read {:?}", path))? {
+        let file_path = entry?.path();
+        if is_capture_file(&file_path) {
+            files.push(file_path);
+        }
+    }
+    files.sort_by_key(|file| (capture_start(file), file.clone()));
+    Ok(files)
+}
+
+/// Hour the capture file `file` starts at, taken from its name.
+pub fn capture_start(file: &Path) -> Option<NaiveDateTime> {
+    file.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split(".bin").next())
+        .and_then(|stem| NaiveDateTime::parse_from_str(stem, "%d-%m-%Y %H-%M-%S").ok())
+}
+
+/// Parses a UTC timestamp such as "2024-11-26 05:50:00" into milliseconds.
+pub fn parse_timestamp(timestamp: &str) -> Result<i64> {
+    let timestamp = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .with_context(|| format!("failed to parse timestamp {:?}", timestamp))?;
+    Ok(timestamp.and_utc().timestamp_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_files_are_ordered_by_their_start() {
+        let dir = std::env::temp_dir().join(format!("capture-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let names = [
+            "01-12-2024 00-00-00.bin",
+            "30-11-2024 23-00-00.bin.gz",
+            "26-11-2024 05-00-00.bin",
+            "notes.txt",
+        ];
+        for name in names {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let files: Vec<String> = capture_files(&dir)
+            .unwrap()
+            .iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(files, [names[2], names[1], names[0]]);
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{bail, Result};
+use argh::FromArgs;
+use chrono::DateTime;
+use clickhouse::Client;
+use fpdec::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::{
+    book::Book,
+    event::Event,
+    source::{EventFilter, EventSource},
+};
+
+const HOUR: i64 = 3_600_000;
+
+#[derive(FromArgs)]
+/// Check captured market data for gaps and inconsistencies
+#[argh(subcommand, name = "verify")]
+pub struct VerifyOptions {
+    /// capture file or directory of them to check instead of the table
+    #[argh(option)]
+    path: Option<String>,
+    /// market data table to check
+    #[argh(option, default = "String::from(\"marketDataUnprocessed\")")]
+    table: String,
+    /// start of the checked range (UTC), e.g. "2024-11-26 00:00:00"
+    #[argh(option)]
+    from: Option<String>,
+    /// end of the checked range (UTC), exclusive
+    #[argh(option)]
+    to: Option<String>,
+    /// fail when an hour of a product has more depth update id gaps
+    #[argh(option)]
+    max_depth_gaps: Option<u64>,
+    /// fail when an hour of a product has more trade id gaps
+    #[argh(option)]
+    max_trade_gaps: Option<u64>,
+    /// fail when an hour of a product has more timestamps going backwards
+    #[argh(option)]
+    max_non_monotonic: Option<u64>,
+    /// fail when an hour of a product has more crossed books after replay
+    #[argh(option)]
+    max_crossed: Option<u64>,
+    /// fail when an hour of a product has more zero or negative quantities
+    #[argh(option)]
+    max_bad_quantities: Option<u64>,
+    /// fail when an hour of a product has more depth events without a snapshot
+    #[argh(option)]
+    max_uncovered: Option<u64>,
+    /// fail when an hour of a product has no snapshot
+    #[argh(switch)]
+    require_snapshots: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct HourStats {
+    snapshot: u64,
+    depth: u64,
+    trade: u64,
+    other: u64,
+    snapshots: u64,
+    /// Depth messages and trades received again, e.g. from both websocket
+    /// connections the collector keeps open while reconnecting.
+    duplicates: u64,
+    depth_gaps: u64,
+    trade_gaps: u64,
+    non_monotonic: u64,
+    crossed: u64,
+    bad_quantities: u64,
+    uncovered: u64,
+}
+
+/// Replay state of a single product.
+#[derive(Default)]
+struct ProductState {
+    book: Book,
+    snapshot_id: Option<u64>,
+    last_update_id: Option<u64>,
+    last_trade_id: Option<u64>,
+    last_gate_timestamp: Option<i64>,
+    last_depth_timestamp: Option<i64>,
+    last_trade_timestamp: Option<i64>,
+    /// `(event_type, id1, id2)` of the snapshot or depth message being read.
+    message: Option<(String, Option<u64>, Option<u64>)>,
+    message_hour: i64,
+    apply_message: bool,
+}
+
+impl ProductState {
+    /// Closes the current message, reporting a crossed book if it left one.
+    fn end_message(&mut self, stats: &mut BTreeMap<(String, i64), HourStats>, product: &str) {
+        if self.message.take().is_none() || !self.apply_message {
+            return;
+        }
+        if self.book.is_crossed() {
+            stats
+                .entry((product.to_string(), self.message_hour))
+                .or_default()
+                .crossed += 1;
+        }
+    }
+}
+
+/// Checks of the events of every product, hour by hour.
+struct Verifier {
+    /// Whether the events come in the order they arrived, which the gate
+    /// timestamps should follow. The table is read in gate timestamp
+    /// order, so they cannot go backwards there.
+    arrival_order: bool,
+    stats: BTreeMap<(String, i64), HourStats>,
+    products: HashMap<String, ProductState>,
+}
+
+impl Verifier {
+    fn new(arrival_order: bool) -> Self {
+        Self {
+            arrival_order,
+            stats: BTreeMap::new(),
+            products: HashMap::new(),
+        }
+    }
+
+    fn on_event(&mut self, event: &Event) {
+        let stats = &mut self.stats;
+        let hour = event.gate_timestamp.div_euclid(HOUR) * HOUR;
+        let state = self.products.entry(event.product.clone()).or_default();
+        let hour_stats = stats.entry((event.product.clone(), hour)).or_default();
+
+        if self.arrival_order
+            && state
+                .last_gate_timestamp
+                .is_some_and(|last| event.gate_timestamp < last)
+        {
+            hour_stats.non_monotonic += 1;
+        }
+        state.last_gate_timestamp = Some(event.gate_timestamp);
+
+        let quantity = Decimal::from_str(&event.quantity).ok();
+        let bad_quantity = match (event.event_type.as_str(), quantity) {
+            (_, None) => true,
+            ("depth", Some(quantity)) => quantity < Decimal::ZERO,
+            (_, Some(quantity)) => quantity <= Decimal::ZERO,
+        };
+        if bad_quantity {
+            hour_stats.bad_quantities += 1;
+        }
+
+        let message = (event.event_type.clone(), event.id1, event.id2);
+        let new_message = state.message.as_ref() != Some(&message);
+        if new_message {
+            state.end_message(stats, &event.product);
+        }
+        let hour_stats = stats.entry((event.product.clone(), hour)).or_default();
+        match event.event_type.as_str() {
+            "snapshot" => {
+                hour_stats.snapshot += 1;
+                if new_message {
+                    hour_stats.snapshots += 1;
+                    let snapshot_id = event.id1.unwrap_or_default();
+                    // A snapshot older than the diffs already applied would
+                    // roll the book back, so it only replaces a stale book.
+                    state.apply_message = state
+                        .last_update_id
+                        .is_none_or(|last_update_id| snapshot_id >= last_update_id);
+                    if state.apply_message {
+                        state.book.clear();
+                        state.snapshot_id = Some(snapshot_id);
+                        state.last_update_id = None;
+                    }
+                }
+            }
+            "depth" => {
+                hour_stats.depth += 1;
+                if state
+                    .last_depth_timestamp
+                    .is_some_and(|last| event.venue_timestamp < last)
+                {
+                    hour_stats.non_monotonic += 1;
+                }
+                state.last_depth_timestamp = Some(event.venue_timestamp);
+                let (first_update_id, last_update_id) =
+                    (event.id1.unwrap_or_default(), event.id2.unwrap_or_default());
+                match state.snapshot_id {
+                    None => {
+                        hour_stats.uncovered += 1;
+                        state.apply_message = false;
+                    }
+                    Some(snapshot_id) if new_message => {
+                        // Update ids only grow, so a message that does not
+                        // end after the last applied one was applied already.
+                        let duplicate = state
+                            .last_update_id
+                            .is_some_and(|applied| last_update_id <= applied);
+                        state.apply_message = !duplicate && last_update_id > snapshot_id;
+                        if duplicate {
+                            hour_stats.duplicates += 1;
+                        } else if state.apply_message {
+                            let expected = state.last_update_id.unwrap_or(snapshot_id) + 1;
+                            let gap = match state.last_update_id {
+                                Some(_) => first_update_id != expected,
+                                None => first_update_id > expected,
+                            };
+                            if gap {
+                                hour_stats.depth_gaps += 1;
+                            }
+                            state.last_update_id = Some(last_update_id);
+                        }
+                    }
+                    Some(_) => {}
+                }
+            }
+            "trade" => {
+                hour_stats.trade += 1;
+                if state
+                    .last_trade_timestamp
+                    .is_some_and(|last| event.venue_timestamp < last)
+                {
+                    hour_stats.non_monotonic += 1;
+                }
+                state.last_trade_timestamp = Some(event.venue_timestamp);
+                if let Some(trade_id) = event.id1 {
+                    match state.last_trade_id {
+                        Some(last_trade_id) if trade_id <= last_trade_id => {
+                            hour_stats.duplicates += 1;
+                        }
+                        last_trade_id => {
+                            if last_trade_id.is_some_and(|last| trade_id != last + 1) {
+                                hour_stats.trade_gaps += 1;
+                            }
+                            state.last_trade_id = Some(trade_id);
+                        }
+                    }
+                }
+            }
+            _ => hour_stats.other += 1,
+        }
+
+        if event.event_type == "trade" {
+            return;
+        }
+        if new_message {
+            state.message = Some(message);
+            state.message_hour = hour;
+        }
+        if state.apply_message && !bad_quantity {
+            state.book.apply(event);
+        }
+    }
+
+    /// Stats of every product and hour, after the last message of each
+    /// product has been checked.
+    fn finish(mut self) -> BTreeMap<(String, i64), HourStats> {
+        for (product, state) in self.products.iter_mut() {
+            state.end_message(&mut self.stats, product);
+        }
+        self.stats
+    }
+}
+
+/// Fails when a check exceeds its threshold.
+pub async fn run(client: &Client, options: VerifyOptions) -> Result<()> {
+    let mut source = EventSource::open(
+        client,
+        options.path.as_deref(),
+        &options.table,
+        EventFilter::new(options.from.as_deref(), options.to.as_deref())?,
+    )?;
+    let mut verifier = Verifier::new(source.arrival_order());
+    while let Some(event) = source.next().await? {
+        verifier.on_event(&event);
+    }
+    let stats = verifier.finish();
+
+    println!(
+        "{:<12} {:<16} {:>9} {:>9} {:>9} {:>5} {:>6} {:>10} {:>10} {:>8} {:>8} {:>8} {:>9}",
+        "product",
+        "hour",
+        "snapshot",
+        "depth",
+        "trade",
+        "snaps",
+        "dups",
+        "depth_gaps",
+        "trade_gaps",
+        "non_mono",
+        "crossed",
+        "bad_qty",
+        "uncovered",
+    );
+    for ((product, hour), hour_stats) in &stats {
+        println!(
+            "{:<12} {:<16} {:>9} {:>9} {:>9} {:>5} {:>6} {:>10} {:>10} {:>8} {:>8} {:>8} {:>9}",
+            product,
+            format_hour(*hour),
+            hour_stats.snapshot,
+            hour_stats.depth,
+            hour_stats.trade,
+            hour_stats.snapshots,
+            hour_stats.duplicates,
+            hour_stats.depth_gaps,
+            hour_stats.trade_gaps,
+            hour_stats.non_monotonic,
+            hour_stats.crossed,
+            hour_stats.bad_quantities,
+            hour_stats.uncovered,
+        );
+    }
+    let failures = failures(&stats, &options);
+    for failure in &failures {
+        eprintln!("FAILED {}", failure);
+    }
+    if !failures.is_empty() {
+        bail!("{} checks failed", failures.len());
+    }
+    Ok(())
+}
+
+fn format_hour(hour: i64) -> String {
+    DateTime::from_timestamp_millis(hour)
+        .map(|hour| hour.format("%Y-%m-%d %H:00").to_string())
+        .unwrap_or_default()
+}
+
+/// Checks of `stats` over the thresholds of `options`.
+fn failures(stats: &BTreeMap<(String, i64), HourStats>, options: &VerifyOptions) -> Vec<String> {
+    let mut failures = Vec::new();
+    for ((product, hour), hour_stats) in stats {
+        let hour = format_hour(*hour);
+        let checks = [
+            ("depth gaps", hour_stats.depth_gaps, options.max_depth_gaps),
+            ("trade gaps", hour_stats.trade_gaps, options.max_trade_gaps),
+            (
+                "non-monotonic timestamps",
+                hour_stats.non_monotonic,
+                options.max_non_monotonic,
+            ),
+            ("crossed books", hour_stats.crossed, options.max_crossed),
+            (
+                "bad quantities",
+                hour_stats.bad_quantities,
+                options.max_bad_quantities,
+            ),
+            (
+                "uncovered depth events",
+                hour_stats.uncovered,
+                options.max_uncovered,
+            ),
+        ];
+        for (name, value, threshold) in checks {
+            if let Some(threshold) = threshold.filter(|&threshold| value > threshold) {
+                failures.push(format!(
+                    "{} {}: {} {} > {}",
+                    product, hour, name, value, threshold
+                ));
+            }
+        }
+        if options.require_snapshots && hour_stats.snapshots == 0 {
+            failures.push(format!("{} {}: no snapshot", product, hour));
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argh::FromArgs;
+
+    const START: i64 = 1_732_600_800_000;
+
+    fn event(
+        event_type: &str,
+        gate_timestamp: i64,
+        ids: (Option<u64>, Option<u64>),
+        ask_not_bid: Option<bool>,
+        price: &str,
+        quantity: &str,
+    ) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: String::from("BTCUSDT"),
+            id1: ids.0,
+            id2: ids.1,
+            ask_not_bid,
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    fn snapshot(at: i64, id: u64, bid: &str, ask: &str) -> [Event; 2] {
+        [
+            event("snapshot", at, (Some(id), None), Some(false), bid, "1"),
+            event("snapshot", at, (Some(id), None), Some(true), ask, "1"),
+        ]
+    }
+
+    fn depth(at: i64, first: u64, last: u64, ask: bool, price: &str, quantity: &str) -> Event {
+        event(
+            "depth",
+            at,
+            (Some(first), Some(last)),
+            Some(ask),
+            price,
+            quantity,
+        )
+    }
+
+    fn trade(at: i64, id: u64) -> Event {
+        event("trade", at, (Some(id), None), None, "100", "0.1")
+    }
+
+    fn verify(arrival_order: bool, events: &[Event]) -> HourStats {
+        let mut verifier = Verifier::new(arrival_order);
+        for event in events {
+            verifier.on_event(event);
+        }
+        let mut stats = verifier.finish();
+        assert_eq!(stats.len(), 1);
+        stats.pop_first().unwrap().1
+    }
+
+    #[test]
+    fn counts_gaps_but_not_duplicates() {
+        let mut events = snapshot(START, 100, "99", "101").to_vec();
+        events.extend([
+            depth(START + 1, 95, 100, false, "98", "1"),
+            depth(START + 2, 101, 105, false, "99", "2"),
+            depth(START + 3, 106, 110, false, "99", "3"),
+            depth(START + 4, 101, 105, false, "99", "2"),
+            depth(START + 5, 106, 110, false, "99", "3"),
+            depth(START + 6, 112, 115, false, "99", "4"),
+            trade(START + 7, 7),
+            trade(START + 8, 8),
+            trade(START + 8, 8),
+            trade(START + 9, 10),
+        ]);
+        let stats = verify(true, &events);
+        assert_eq!(stats.snapshots, 1);
+        assert_eq!(stats.depth, 6);
+        assert_eq!(stats.duplicates, 3);
+        assert_eq!(stats.depth_gaps, 1);
+        assert_eq!(stats.trade_gaps, 1);
+        assert_eq!(stats.crossed, 0);
+    }
+
+    #[test]
+    fn checks_crossed_books_and_coverage() {
+        let mut events = vec![depth(START, 90, 95, true, "101", "1")];
+        events.extend(snapshot(START + 1, 100, "99", "101"));
+        events.extend([
+            depth(START + 2, 101, 101, false, "102", "1"),
+            depth(START + 3, 102, 102, false, "102", "0"),
+            depth(START + 4, 103, 103, true, "101", "-1"),
+        ]);
+        let stats = verify(true, &events);
+        assert_eq!(stats.uncovered, 1);
+        assert_eq!(stats.crossed, 1);
+        assert_eq!(stats.bad_quantities, 1);
+        assert_eq!(stats.depth_gaps, 0);
+    }
+
+    #[test]
+    fn gate_timestamps_are_only_checked_in_arrival_order() {
+        let events = [trade(START + 5, 1), trade(START, 2)];
+        assert_eq!(verify(true, &events).non_monotonic, 2);
+        assert_eq!(verify(false, &events).non_monotonic, 1);
+
+        let stats = verify(true, &events);
+        let options = VerifyOptions::from_args(
+            &["verify"],
+            &["--max-non-monotonic", "1", "--require-snapshots"],
+        )
+        .unwrap();
+        let failures = failures(
+            &[((String::from("BTCUSDT"), START), stats)].into(),
+            &options,
+        );
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].contains("non-monotonic timestamps 2 > 1"));
+        assert!(failures[1].ends_with("no snapshot"));
+    }
+}
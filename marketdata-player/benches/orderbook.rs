@@ -0,0 +1,78 @@
+//! Replays the book events of one product of a recorded capture through each
+//! book implementation.
+//!
+//! MARKETDATA_BENCH_FILE=captures/26-11-2024\ 05-00-00.bin \
+//! MARKETDATA_BENCH_PRODUCT=BTCUSDT MARKETDATA_BENCH_TICK=0.01 \
+//! cargo bench -p marketdata-player
+
+use std::{env, fs::File, io::BufReader, str::FromStr};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use fpdec::Decimal;
+use marketdata_player::{
+    orderbook::{Book, Orderbook, Side},
+    tickbook::{TickBook, DEFAULT_TICK_SIZE},
+    Event, EventKind,
+};
+
+fn load(path: &str, product: Option<&str>) -> Vec<Event> {
+    let mut reader = BufReader::new(File::open(path).expect("failed to open the capture"));
+    let mut events = Vec::new();
+    while let Ok(event) = bincode::deserialize_from::<_, Event>(&mut reader) {
+        events.push(event);
+    }
+    let product = product
+        .map(str::to_string)
+        .or_else(|| events.first().map(|event| event.product().to_string()))
+        .expect("the capture is empty");
+    events.retain(|event| event.product() == product);
+    events
+}
+
+fn replay(mut book: impl Book, events: Vec<Event>) -> Option<Decimal> {
+    for event in events {
+        match event.kind() {
+            Some(EventKind::Snapshot | EventKind::Depth) => {
+                book.update(event).unwrap();
+            }
+            Some(EventKind::Trade) => book.handle_trade(event).unwrap(),
+            None => {}
+        }
+        // The player reads the touch after every event.
+        std::hint::black_box(book.spread());
+    }
+    book.cost(Side::Buy, Decimal::ONE)
+        .map(|execution| execution.notional)
+}
+
+fn orderbook(c: &mut Criterion) {
+    let Ok(path) = env::var("MARKETDATA_BENCH_FILE") else {
+        eprintln!("MARKETDATA_BENCH_FILE is not set, skipping the order book benchmarks");
+        return;
+    };
+    let product = env::var("MARKETDATA_BENCH_PRODUCT").ok();
+    let tick_size = env::var("MARKETDATA_BENCH_TICK")
+        .map(|tick| Decimal::from_str(&tick).expect("invalid tick size"))
+        .unwrap_or(DEFAULT_TICK_SIZE);
+    let events = load(&path, product.as_deref());
+    let mut group = c.benchmark_group("replay");
+    group.throughput(criterion::Throughput::Elements(events.len() as u64));
+    group.bench_function("map", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| replay(Orderbook::default(), events),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("tick", |b| {
+        b.iter_batched(
+            || events.clone(),
+            |events| replay(TickBook::new(tick_size), events),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, orderbook);
+criterion_main!(benches);
@@ -0,0 +1,620 @@
+pub mod profile;
+pub mod shortfall;
+
+use anyhow::{Ok, Result};
+use async_trait::async_trait;
+use fpdec::{Decimal, Round};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str::FromStr,
+};
+
+use self::{
+    profile::VolumeProfile,
+    shortfall::{kappa, temporary_impact, traded, MidVariance},
+};
+use crate::{
+    clock::TimerId,
+    orderbook::{Book, Price, Quantity, Side},
+    simulator::{
+        limit::{OrderEvent, OrderUpdate, Rejection},
+        OrderId,
+    },
+    strategy::{Context, Strategy},
+    tickbook::QUANTITY_DIGITS,
+    Event,
+};
+
+/// How a parent order is spread over its horizon.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Algo {
+    /// Even slices.
+    Twap,
+    /// Slices following the volume the product trades by time of day, see
+    /// [`ExecutionAlgo::with_volume_profile`]. Even slices where the profile
+    /// has no volume.
+    Vwap,
+    /// `rate` of the volume the product has traded since the parent order
+    /// arrived.
+    Pov { rate: f64 },
+    /// The Almgren–Chriss trajectory of [`shortfall::traded`] for a trader
+    /// with `risk_aversion`. The price variance is measured over the replay
+    /// before the arrival of the parent order and the temporary impact in
+    /// the book at its arrival.
+    Shortfall { risk_aversion: f64 },
+}
+
+impl FromStr for Algo {
+    type Err = String;
+    /// Parses "twap", "vwap", "pov:RATE" and "is:RISK_AVERSION".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["twap"] => Result::Ok(Algo::Twap),
+            ["vwap"] => Result::Ok(Algo::Vwap),
+            ["pov", rate] => match rate.parse::<f64>() {
+                Result::Ok(rate) if rate > 0.0 && rate <= 1.0 => Result::Ok(Algo::Pov { rate }),
+                _ => Err(format!("invalid participation rate {:?}", rate)),
+            },
+            ["is", risk_aversion] => match risk_aversion.parse::<f64>() {
+                Result::Ok(risk_aversion) if risk_aversion >= 0.0 => {
+                    Result::Ok(Algo::Shortfall { risk_aversion })
+                }
+                _ => Err(format!("invalid risk aversion {:?}", risk_aversion)),
+            },
+            _ => Err(format!(
+                "unknown algo {:?}, expected twap, vwap, pov:RATE or is:RISK_AVERSION",
+                s
+            )),
+        }
+    }
+}
+
+/// Parent orders of an [`ExecutionAlgo`] and how they are worked.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AlgoParams {
+    pub side: Side,
+    pub quantity: Quantity,
+    /// Milliseconds from the arrival of a parent order by which the
+    /// schedule completes. Whatever is left then is sent across the spread
+    /// every slice.
+    pub horizon: i64,
+    /// Milliseconds between child orders.
+    pub slice: i64,
+    /// Worst price of the child orders.
+    pub limit_price: Option<Price>,
+    /// Share of each child order sent across the spread, between 0 and 1.
+    /// The rest joins the best price of its side until the next slice.
+    pub urgency: f64,
+    /// Lot step child quantities are rounded down to.
+    pub lot_step: Option<Quantity>,
+}
+
+/// Parent order worked by an [`ExecutionAlgo`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParentOrder {
+    /// Identifier of the order for the transaction cost analysis, see
+    /// [`Context::parent_order`].
+    pub id: OrderId,
+    pub product: String,
+    pub side: Side,
+    pub quantity: Quantity,
+    /// Simulation time the order arrived at.
+    pub arrival: i64,
+    /// Mid of the book when the order arrived.
+    pub arrival_mid: Option<Price>,
+    pub filled: Quantity,
+    /// Amount paid for a buy or received for a sell.
+    pub notional: Decimal,
+    /// Fees paid in the quote asset, negative for rebates.
+    pub fees: Decimal,
+    /// Simulation time of the fill that completed the order.
+    pub completed: Option<i64>,
+    /// Open quantities of the child orders.
+    children: BTreeMap<OrderId, Quantity>,
+    /// Child orders a cancel has been sent for.
+    cancelling: BTreeSet<OrderId>,
+    /// Volume of the product traded before the arrival.
+    volume_at_arrival: Quantity,
+    kappa: f64,
+    timer: Option<TimerId>,
+}
+
+impl ParentOrder {
+    pub fn remaining(&self) -> Quantity {
+        self.quantity - self.filled
+    }
+    pub fn average_price(&self) -> Option<Price> {
+        (!self.filled.eq_zero()).then(|| self.notional / self.filled)
+    }
+    /// How much worse than the arrival mid the average price is, in basis
+    /// points of the mid.
+    pub fn shortfall_bps(&self) -> Option<f64> {
+        let (price, mid) = self.average_price().zip(self.arrival_mid)?;
+        let shortfall = match self.side {
+            Side::Buy => price - mid,
+            Side::Sell => mid - price,
+        };
+        Some(f64::from(shortfall) / f64::from(mid) * 10_000.0)
+    }
+    /// Fees in basis points of the notional.
+    pub fn fees_bps(&self) -> Option<f64> {
+        let notional = f64::from(self.notional);
+        (notional > 0.0).then(|| f64::from(self.fees) / notional * 10_000.0)
+    }
+}
+
+/// Works parent orders of [`AlgoParams`] for every product by an [`Algo`].
+/// A parent order arrives at each order time of the player and is sliced
+/// into limit orders every `slice` milliseconds: the part of a child order
+/// given by the urgency crosses the spread, the rest is posted at the best
+/// price of its side. Every slice cancels the children of the previous one
+/// and their remainder goes to the next.
+pub struct ExecutionAlgo {
+    algo: Algo,
+    params: AlgoParams,
+    profile: VolumeProfile,
+    state: AlgoState,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct AlgoState {
+    parents: Vec<ParentOrder>,
+    /// Parent of each child order.
+    children: HashMap<OrderId, usize>,
+    /// Parent of each slice timer.
+    timers: HashMap<TimerId, usize>,
+    /// Volume traded by product.
+    volumes: HashMap<String, Quantity>,
+    variances: HashMap<String, MidVariance>,
+}
+
+impl ExecutionAlgo {
+    pub fn new(algo: Algo, params: AlgoParams) -> Self {
+        assert!(params.slice > 0, "slice must be positive");
+        Self {
+            algo,
+            params,
+            profile: VolumeProfile::default(),
+            state: AlgoState::default(),
+        }
+    }
+    /// Volume profile [`Algo::Vwap`] follows.
+    pub fn with_volume_profile(mut self, profile: VolumeProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+    pub fn parents(&self) -> &[ParentOrder] {
+        &self.state.parents
+    }
+    fn observe(&mut self, ctx: &Context<'_>, product: &str) {
+        if let Some(mid) = ctx.book(product).and_then(|book| book.mid()) {
+            self.state
+                .variances
+                .entry(product.to_string())
+                .or_default()
+                .observe(ctx.timestamp(), f64::from(mid));
+        }
+    }
+    fn start(&mut self, ctx: &mut Context<'_>, product: &str) {
+        let AlgoParams {
+            side,
+            quantity,
+            slice,
+            ..
+        } = self.params;
+        let kappa = match self.algo {
+            Algo::Shortfall { risk_aversion } => {
+                let eta = ctx
+                    .impacted_book(product)
+                    .and_then(|book| temporary_impact(&book, side, quantity, slice));
+                let variance = self
+                    .state
+                    .variances
+                    .get(product)
+                    .and_then(MidVariance::variance);
+                kappa(risk_aversion, variance, eta)
+            }
+            _ => 0.0,
+        };
+        let index = self.state.parents.len();
+        let id = ctx.parent_order(product, side, quantity);
+        self.state.parents.push(ParentOrder {
+            id,
+            product: product.to_string(),
+            side,
+            quantity,
+            arrival: ctx.timestamp(),
+            arrival_mid: ctx.book(product).and_then(|book| book.mid()),
+            filled: Decimal::ZERO,
+            notional: Decimal::ZERO,
+            fees: Decimal::ZERO,
+            completed: None,
+            children: BTreeMap::new(),
+            cancelling: BTreeSet::new(),
+            volume_at_arrival: self.volume(product),
+            kappa,
+            timer: None,
+        });
+        let timer = ctx.clock().every(slice);
+        self.state.parents[index].timer = Some(timer);
+        self.state.timers.insert(timer, index);
+        self.slice(ctx, index);
+    }
+    /// Cancels the children of parent `index` and sends the next ones.
+    fn slice(&mut self, ctx: &mut Context<'_>, index: usize) {
+        let AlgoParams {
+            horizon,
+            slice,
+            limit_price,
+            urgency,
+            lot_step,
+            ..
+        } = self.params;
+        let parent = &self.state.parents[index];
+        if parent.completed.is_some() {
+            return;
+        }
+        let elapsed = ctx.timestamp() - parent.arrival;
+        let late = elapsed >= horizon;
+        let target = match late {
+            true => parent.quantity,
+            false => self.target(parent, elapsed + slice),
+        };
+        let open = parent
+            .children
+            .values()
+            .fold(Decimal::ZERO, |open, quantity| open + *quantity);
+        // Children being cancelled may still fill, so they keep counting
+        // against the parent quantity.
+        let child = round_down(
+            (target - parent.filled).min(parent.remaining() - open),
+            lot_step,
+        );
+        let parent = &mut self.state.parents[index];
+        for &order in parent.children.keys() {
+            if parent.cancelling.insert(order) {
+                ctx.cancel(order);
+            }
+        }
+        if child <= Decimal::ZERO {
+            return;
+        }
+        let aggressive = match late {
+            true => child,
+            false => round_down(fraction(child, urgency), lot_step),
+        };
+        let (id, product, side) = (parent.id, parent.product.clone(), parent.side);
+        let Some(book) = ctx.impacted_book(&product) else {
+            return;
+        };
+        let mut children = Vec::new();
+        if aggressive > Decimal::ZERO {
+            if let Some(price) = worst_price(&book, side, aggressive) {
+                children.push((cap(side, price, limit_price), aggressive));
+            }
+        }
+        let passive = child - aggressive;
+        if passive > Decimal::ZERO {
+            let best = book.levels(side.opposite()).next().map(|level| level.price);
+            if let Some(price) = best
+                .map(|best| cap(side, best, limit_price))
+                .or(limit_price)
+            {
+                children.push((price, passive));
+            }
+        }
+        for (price, quantity) in children {
+            let Some(order) = ctx.child_order(id, price, quantity) else {
+                continue;
+            };
+            self.state.parents[index].children.insert(order, quantity);
+            self.state.children.insert(order, index);
+        }
+    }
+    /// Quantity of `parent` its schedule has traded `elapsed` milliseconds
+    /// after its arrival.
+    fn target(&self, parent: &ParentOrder, elapsed: i64) -> Quantity {
+        let horizon = self.params.horizon;
+        let even = (elapsed as f64 / horizon as f64).min(1.0);
+        let share = match self.algo {
+            Algo::Twap => even,
+            Algo::Vwap => self
+                .profile
+                .share(
+                    &parent.product,
+                    parent.arrival,
+                    parent.arrival + elapsed,
+                    parent.arrival + horizon,
+                )
+                .unwrap_or(even),
+            Algo::Pov { rate } => {
+                let traded = self.volume(&parent.product) - parent.volume_at_arrival;
+                return fraction(traded, rate).min(parent.quantity);
+            }
+            Algo::Shortfall { .. } => traded(parent.kappa, horizon, elapsed),
+        };
+        fraction(parent.quantity, share)
+    }
+    fn volume(&self, product: &str) -> Quantity {
+        self.state
+            .volumes
+            .get(product)
+            .copied()
+            .unwrap_or(Decimal::ZERO)
+    }
+    fn finish(&mut self, ctx: &mut Context<'_>, index: usize) {
+        let parent = &mut self.state.parents[index];
+        parent.completed = Some(ctx.timestamp());
+        if let Some(timer) = parent.timer.take() {
+            ctx.clock().cancel(timer);
+            self.state.timers.remove(&timer);
+        }
+        for &order in parent.children.keys() {
+            if parent.cancelling.insert(order) {
+                ctx.cancel(order);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Strategy for ExecutionAlgo {
+    async fn on_depth(&mut self, ctx: &mut Context<'_>, diff: &Event) -> Result<()> {
+        self.observe(ctx, &diff.product);
+        Ok(())
+    }
+    async fn on_trade(&mut self, ctx: &mut Context<'_>, trade: &Event) -> Result<()> {
+        self.observe(ctx, &trade.product);
+        let quantity = Decimal::from_str(&trade.quantity)?;
+        *self
+            .state
+            .volumes
+            .entry(trade.product.clone())
+            .or_insert(Decimal::ZERO) += quantity;
+        Ok(())
+    }
+    async fn on_order(&mut self, ctx: &mut Context<'_>, _order: usize) -> Result<()> {
+        let products: Vec<String> = ctx.products().map(String::from).collect();
+        for product in products {
+            self.start(ctx, &product);
+        }
+        Ok(())
+    }
+    async fn on_timer(&mut self, ctx: &mut Context<'_>, timer: TimerId) -> Result<()> {
+        if let Some(&index) = self.state.timers.get(&timer) {
+            self.slice(ctx, index);
+        }
+        Ok(())
+    }
+    async fn on_order_update(&mut self, ctx: &mut Context<'_>, update: &OrderUpdate) -> Result<()> {
+        let Some(&index) = self.state.children.get(&update.order) else {
+            return Ok(());
+        };
+        let parent = &mut self.state.parents[index];
+        match update.event {
+            OrderEvent::Filled {
+                price,
+                quantity,
+                remaining,
+                fee,
+                ..
+            } => {
+                parent.filled += quantity;
+                parent.notional += price * quantity;
+                parent.fees += fee;
+                if remaining.eq_zero() {
+                    parent.children.remove(&update.order);
+                    parent.cancelling.remove(&update.order);
+                    self.state.children.remove(&update.order);
+                } else {
+                    parent.children.insert(update.order, remaining);
+                }
+                if parent.remaining() <= Decimal::ZERO && parent.completed.is_none() {
+                    self.finish(ctx, index);
+                }
+            }
+            // A cancel that found the order gone changes nothing, its fill
+            // or cancellation is reported on its own.
+            OrderEvent::Rejected(Rejection::NotOpen) => {
+                parent.cancelling.remove(&update.order);
+            }
+            OrderEvent::Cancelled { .. } | OrderEvent::Rejected(_) => {
+                parent.children.remove(&update.order);
+                parent.cancelling.remove(&update.order);
+                self.state.children.remove(&update.order);
+            }
+            OrderEvent::Accepted { .. } | OrderEvent::Amended { .. } => {}
+        }
+        Ok(())
+    }
+    async fn on_end(&mut self, _ctx: &mut Context<'_>) -> Result<()> {
+        for parent in &self.state.parents {
+            let average = parent
+                .average_price()
+                .map_or(String::from("-"), |price| price.to_string());
+            let shortfall = parent
+                .shortfall_bps()
+                .map_or(String::from("-"), |bps| format!("{:.2}", bps));
+            println!(
+                "{} {:?} {} of {} from {}: average price {}, shortfall {} bps, fees {}",
+                parent.product,
+                parent.side,
+                parent.filled,
+                parent.quantity,
+                parent.arrival,
+                average,
+                shortfall,
+                parent.fees,
+            );
+        }
+        Ok(())
+    }
+    fn checkpoint(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&self.state)?)
+    }
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        self.state = bincode::deserialize(state)?;
+        Ok(())
+    }
+}
+
+/// Price of the last level of `book` an order of `side` for `quantity`
+/// reaches, the last level of the book if it is not deep enough.
+fn worst_price(book: &impl Book, side: Side, quantity: Quantity) -> Option<Price> {
+    let mut left = quantity;
+    let mut price = None;
+    for level in book.levels(side) {
+        price = Some(level.price);
+        left -= level.quantity;
+        if left <= Decimal::ZERO {
+            break;
+        }
+    }
+    price
+}
+
+/// `price` no worse than `limit` for an order of `side`.
+fn cap(side: Side, price: Price, limit: Option<Price>) -> Price {
+    match (side, limit) {
+        (Side::Buy, Some(limit)) => price.min(limit),
+        (Side::Sell, Some(limit)) => price.max(limit),
+        (_, None) => price,
+    }
+}
+
+/// `share` of `quantity`, with the share clamped to between 0 and 1.
+fn fraction(quantity: Quantity, share: f64) -> Quantity {
+    let share = Decimal::new_raw((share.clamp(0.0, 1.0) * 1e9).round() as i128, 9);
+    quantity * share
+}
+
+/// `quantity` rounded down to a multiple of `lot_step`, or of the smallest
+/// quantity of the venue without one.
+fn round_down(quantity: Quantity, lot_step: Option<Quantity>) -> Quantity {
+    let step = lot_step
+        .filter(|step| *step > Decimal::ZERO)
+        .unwrap_or(Decimal::new_raw(1, QUANTITY_DIGITS));
+    (quantity - quantity % step)
+        .round(step.n_frac_digits() as i8)
+        .max(Decimal::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::Orderbook;
+
+    fn decimal(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn params() -> AlgoParams {
+        AlgoParams {
+            side: Side::Buy,
+            quantity: decimal("10"),
+            horizon: 1000,
+            slice: 100,
+            limit_price: None,
+            urgency: 0.5,
+            lot_step: None,
+        }
+    }
+
+    fn parent(algo: &ExecutionAlgo, kappa: f64) -> ParentOrder {
+        ParentOrder {
+            id: 0,
+            product: "TEST".to_string(),
+            side: Side::Buy,
+            quantity: algo.params.quantity,
+            arrival: 0,
+            arrival_mid: Some(decimal("100")),
+            filled: Decimal::ZERO,
+            notional: Decimal::ZERO,
+            fees: Decimal::ZERO,
+            completed: None,
+            children: BTreeMap::new(),
+            cancelling: BTreeSet::new(),
+            volume_at_arrival: decimal("5"),
+            kappa,
+            timer: None,
+        }
+    }
+
+    #[test]
+    fn parses_algos() {
+        assert_eq!("twap".parse(), Result::Ok(Algo::Twap));
+        assert_eq!("pov:0.1".parse(), Result::Ok(Algo::Pov { rate: 0.1 }));
+        assert_eq!(
+            "is:2".parse(),
+            Result::Ok(Algo::Shortfall { risk_aversion: 2.0 })
+        );
+        assert!("pov:2".parse::<Algo>().is_err());
+        assert!("is".parse::<Algo>().is_err());
+    }
+
+    #[test]
+    fn schedules_follow_the_algo() {
+        let twap = ExecutionAlgo::new(Algo::Twap, params());
+        assert_eq!(twap.target(&parent(&twap, 0.0), 250), decimal("2.5"));
+        assert_eq!(twap.target(&parent(&twap, 0.0), 2000), decimal("10"));
+
+        let mut profile = VolumeProfile::new(500);
+        profile.add("TEST", 0, 3.0);
+        profile.add("TEST", 500, 1.0);
+        let vwap = ExecutionAlgo::new(Algo::Vwap, params()).with_volume_profile(profile);
+        assert_eq!(vwap.target(&parent(&vwap, 0.0), 500), decimal("7.5"));
+
+        let mut pov = ExecutionAlgo::new(Algo::Pov { rate: 0.1 }, params());
+        pov.state.volumes.insert("TEST".to_string(), decimal("25"));
+        assert_eq!(pov.target(&parent(&pov, 0.0), 100), decimal("2"));
+
+        let shortfall = ExecutionAlgo::new(Algo::Shortfall { risk_aversion: 1.0 }, params());
+        assert!(shortfall.target(&parent(&shortfall, 0.01), 250) > decimal("2.5"));
+    }
+
+    fn level(ask_not_bid: bool, price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: "snapshot".to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid: Some(ask_not_bid),
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    #[test]
+    fn children_are_priced_within_the_limit() {
+        let mut book = Orderbook::default();
+        for (ask_not_bid, price, quantity) in [
+            (true, "101", "1"),
+            (true, "102", "2"),
+            (false, "99", "1"),
+            (false, "98", "2"),
+        ] {
+            book.update(level(ask_not_bid, price, quantity)).unwrap();
+        }
+        assert_eq!(
+            worst_price(&book, Side::Buy, decimal("1.5")),
+            Some(decimal("102"))
+        );
+        assert_eq!(
+            worst_price(&book, Side::Sell, decimal("5")),
+            Some(decimal("98"))
+        );
+        let limit = Some(decimal("101.5"));
+        assert_eq!(cap(Side::Buy, decimal("102"), limit), decimal("101.5"));
+        assert_eq!(cap(Side::Sell, decimal("99"), limit), decimal("101.5"));
+        assert_eq!(
+            round_down(decimal("1.27"), Some(decimal("0.1"))),
+            decimal("1.2")
+        );
+        assert_eq!(round_down(decimal("-1"), None), Decimal::ZERO);
+    }
+}
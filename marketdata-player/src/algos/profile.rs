@@ -0,0 +1,131 @@
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{datasource::MarketDataSource, EventKind};
+
+/// Milliseconds in a day.
+pub const DAY: i64 = 86_400_000;
+/// Length of the time of day buckets by default, five minutes.
+pub const DEFAULT_BUCKET: i64 = 5 * 60 * 1000;
+
+/// Traded volume of each product by time of day, in buckets of equal
+/// length. Within a bucket the volume is taken to trade evenly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeProfile {
+    bucket: i64,
+    volumes: HashMap<String, Vec<f64>>,
+}
+
+impl Default for VolumeProfile {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUCKET)
+    }
+}
+
+impl VolumeProfile {
+    pub fn new(bucket: i64) -> Self {
+        assert!(
+            bucket > 0 && DAY % bucket == 0,
+            "profile bucket must divide a day"
+        );
+        Self {
+            bucket,
+            volumes: HashMap::new(),
+        }
+    }
+    /// Profile of the trades of `source` between `from` and `to`, typically
+    /// the days before a replay.
+    pub async fn from_source(
+        source: &mut dyn MarketDataSource,
+        from: i64,
+        to: i64,
+        bucket: i64,
+    ) -> Result<Self> {
+        ensure!(from < to, "the profile period is empty");
+        let mut profile = Self::new(bucket);
+        source.seek(from).await?;
+        while let Some(event) = source.next().await? {
+            if event.gate_timestamp >= to {
+                break;
+            }
+            if event.kind() == Some(EventKind::Trade) {
+                profile.add(
+                    &event.product,
+                    event.venue_timestamp,
+                    event.quantity.parse()?,
+                );
+            }
+        }
+        Ok(profile)
+    }
+    pub fn add(&mut self, product: &str, timestamp: i64, quantity: f64) {
+        let buckets = (DAY / self.bucket) as usize;
+        let volumes = self
+            .volumes
+            .entry(product.to_string())
+            .or_insert_with(|| vec![0.0; buckets]);
+        volumes[(timestamp.rem_euclid(DAY) / self.bucket) as usize] += quantity;
+    }
+    /// Share of the volume of `product` expected between `from` and `to`
+    /// that trades before `until`. `None` if the profile has no volume for
+    /// the period.
+    pub fn share(&self, product: &str, from: i64, until: i64, to: i64) -> Option<f64> {
+        let volumes = self.volumes.get(product)?;
+        let total = self.volume(volumes, from, to);
+        (total > 0.0).then(|| self.volume(volumes, from, until.clamp(from, to)) / total)
+    }
+    fn volume(&self, volumes: &[f64], from: i64, to: i64) -> f64 {
+        let mut volume = 0.0;
+        let mut time = from;
+        while time < to {
+            let end = (time - time.rem_euclid(self.bucket) + self.bucket).min(to);
+            let bucket = (time.rem_euclid(DAY) / self.bucket) as usize;
+            volume += volumes[bucket] * (end - time) as f64 / self.bucket as f64;
+            time = end;
+        }
+        volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_follow_the_volume_by_time_of_day() {
+        let mut profile = VolumeProfile::new(1000);
+        // Three days of a bucket trading three times the volume of the one
+        // after it.
+        for day in 0..3 {
+            profile.add("TEST", day * DAY + 500, 3.0);
+            profile.add("TEST", day * DAY + 1500, 1.0);
+        }
+        let start = 5 * DAY;
+        assert_eq!(
+            profile.share("TEST", start, start + 1000, start + 2000),
+            Some(0.75)
+        );
+        assert_eq!(
+            profile.share("TEST", start, start + 500, start + 2000),
+            Some(0.375)
+        );
+        assert_eq!(
+            profile.share("TEST", start + 1000, start + 1500, start + 2000),
+            Some(0.5)
+        );
+        // The profile wraps around midnight.
+        assert_eq!(
+            profile.share("TEST", start - 1000, start, start + 1000),
+            Some(0.0)
+        );
+        assert_eq!(
+            profile.share("TEST", start + 2000, start + 2500, start + 3000),
+            None
+        );
+        assert_eq!(
+            profile.share("OTHER", start, start + 500, start + 1000),
+            None
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::orderbook::{Book, Quantity, Side};
+
+/// Share of the parent order the Almgren–Chriss trajectory has traded
+/// `elapsed` milliseconds into a `horizon`. The holdings minimizing
+/// `E[cost] + lambda * Var[cost]` decay as `sinh(kappa * (T - t)) /
+/// sinh(kappa * T)`, which is written here without overflowing for a large
+/// `kappa * T`. A `kappa` of zero is the straight line of TWAP, larger ones
+/// trade earlier to cut the exposure to the price.
+pub fn traded(kappa: f64, horizon: i64, elapsed: i64) -> f64 {
+    if horizon <= 0 {
+        return 1.0;
+    }
+    let (t, horizon) = (elapsed.clamp(0, horizon) as f64, horizon as f64);
+    if kappa * horizon < 1e-9 {
+        return t / horizon;
+    }
+    let held = (-kappa * t).exp() * (1.0 - (-2.0 * kappa * (horizon - t)).exp())
+        / (1.0 - (-2.0 * kappa * horizon).exp());
+    1.0 - held
+}
+
+/// `kappa = sqrt(lambda * sigma^2 / eta)` of a trader with `risk_aversion`
+/// facing a price `variance` per millisecond and a temporary impact `eta`
+/// per unit of trading rate, zero if either is unknown.
+pub fn kappa(risk_aversion: f64, variance: Option<f64>, eta: Option<f64>) -> f64 {
+    match (variance, eta) {
+        (Some(variance), Some(eta)) if eta > 0.0 => {
+            (risk_aversion * variance / eta).max(0.0).sqrt()
+        }
+        _ => 0.0,
+    }
+}
+
+/// Temporary impact of `book`: the cost per unit, beyond the best price, of
+/// trading `quantity` on `side` within one `slice` of milliseconds,
+/// divided by the rate of trading it.
+pub fn temporary_impact(
+    book: &impl Book,
+    side: Side,
+    quantity: Quantity,
+    slice: i64,
+) -> Option<f64> {
+    let touch = f64::from(book.levels(side).next()?.price);
+    let average = f64::from(book.cost(side, quantity)?.average_price()?);
+    let cost = match side {
+        Side::Buy => average - touch,
+        Side::Sell => touch - average,
+    };
+    Some(cost * slice as f64 / f64::from(quantity))
+}
+
+/// Variance of the mid price per millisecond, from its changes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MidVariance {
+    last: Option<(i64, f64)>,
+    squares: f64,
+    time: i64,
+}
+
+impl MidVariance {
+    pub fn observe(&mut self, timestamp: i64, mid: f64) {
+        if let Some((last_timestamp, last_mid)) = self.last {
+            self.squares += (mid - last_mid).powi(2);
+            self.time += timestamp - last_timestamp;
+        }
+        self.last = Some((timestamp, mid));
+    }
+    pub fn variance(&self) -> Option<f64> {
+        (self.time > 0).then(|| self.squares / self.time as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trajectories_front_load_with_kappa() {
+        assert_eq!(traded(0.0, 100, 25), 0.25);
+        let urgent = traded(0.05, 100, 25);
+        assert!(urgent > 0.25 && urgent < 1.0);
+        assert!((traded(0.05, 100, 100) - 1.0).abs() < 1e-12);
+        // Far past the point where sinh overflows.
+        let immediate = traded(100.0, 100, 1);
+        assert!(immediate.is_finite() && immediate > 0.99);
+    }
+
+    #[test]
+    fn variance_is_per_millisecond() {
+        let mut variance = MidVariance::default();
+        assert_eq!(variance.variance(), None);
+        variance.observe(0, 100.0);
+        variance.observe(10, 102.0);
+        variance.observe(20, 100.0);
+        assert_eq!(variance.variance(), Some(0.4));
+        assert_eq!(kappa(1.0, variance.variance(), Some(0.1)), 2.0);
+        assert_eq!(kappa(1.0, variance.variance(), None), 0.0);
+    }
+}
@@ -0,0 +1,451 @@
+use fpdec::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::orderbook::{Book, Level, OrderSize, Price, Quantity, Side};
+
+/// Number of levels of each side imbalance and weighted mid are taken over
+/// by default.
+pub const DEFAULT_LEVELS: usize = 5;
+/// Distances from the mid, in basis points, of the default depth curve.
+pub const DEFAULT_DEPTH_BPS: [f64; 5] = [5.0, 10.0, 25.0, 50.0, 100.0];
+
+/// Features of a book at one moment, `None` where a side is missing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BookFeatures {
+    /// `(bid - ask) / (bid + ask)` quantity of the top levels, in `[-1, 1]`.
+    pub imbalance: Option<f64>,
+    /// Best prices weighted by the quantity on the opposite side.
+    pub microprice: Option<f64>,
+    /// Average price of the top levels of both sides weighted by quantity.
+    pub weighted_mid: Option<f64>,
+    /// Cumulative bid and ask quantity within each distance of the depth
+    /// curve from the mid.
+    pub depth_curve: Vec<(f64, f64)>,
+    /// Average prices of buying and selling the order size.
+    pub vwap_buy: Option<f64>,
+    pub vwap_sell: Option<f64>,
+    /// Milliseconds the book took to get back its spread and top depth
+    /// after the last trade that was followed by a recovery.
+    pub resilience: Option<f64>,
+}
+
+impl BookFeatures {
+    /// Column names of [`BookFeatures::row`] for a depth curve at `depth_bps`.
+    pub fn header(depth_bps: &[f64]) -> String {
+        let mut header = String::from(
+            "Imbalance | Microprice | Weighted mid | VWAP buy | VWAP sell | Resilience",
+        );
+        for bps in depth_bps {
+            header.push_str(&format!(" | Bid depth {bps}bps | Ask depth {bps}bps"));
+        }
+        header
+    }
+    /// Space separated values of the columns of [`BookFeatures::header`],
+    /// NaN for missing ones.
+    pub fn row(&self, depth_bps: &[f64]) -> String {
+        let value = |value: Option<f64>| value.unwrap_or(f64::NAN).to_string();
+        let mut row = [
+            self.imbalance,
+            self.microprice,
+            self.weighted_mid,
+            self.vwap_buy,
+            self.vwap_sell,
+            self.resilience,
+        ]
+        .map(value)
+        .join(" ");
+        for index in 0..depth_bps.len() {
+            let (bid, ask) = self
+                .depth_curve
+                .get(index)
+                .copied()
+                .unwrap_or((f64::NAN, f64::NAN));
+            row.push_str(&format!(" {} {}", bid, ask));
+        }
+        row
+    }
+}
+
+/// Spread and top depth before a trade, kept until the book is back to them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Recovery {
+    timestamp: i64,
+    spread: Decimal,
+    depth: Decimal,
+}
+
+/// Keeps the [`BookFeatures`] of a book up to date as updates and trades are
+/// applied to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analytics {
+    levels: usize,
+    depth_bps: Vec<f64>,
+    order: Option<OrderSize>,
+    features: BookFeatures,
+    recovery: Option<Recovery>,
+    /// Quantity of the top `levels` levels of both sides.
+    total: Decimal,
+    /// Price of the last of the top `levels` levels of bids and asks, `None`
+    /// while a side has fewer, so that changes past them are skipped.
+    edges: [Option<Price>; 2],
+    /// Worst price the VWAP of each side reached, `None` if the order took
+    /// the whole side.
+    reach: [Option<Price>; 2],
+    /// Mid and exact cumulative quantities of the depth curve.
+    mid: Option<Price>,
+    depth_curve: Vec<(Quantity, Quantity)>,
+    /// Timestamp of the updates deferred by [`Analytics::defer`].
+    deferred: Option<i64>,
+}
+
+impl Default for Analytics {
+    fn default() -> Self {
+        Self::new(DEFAULT_LEVELS, DEFAULT_DEPTH_BPS.to_vec(), None)
+    }
+}
+
+impl Analytics {
+    /// Takes imbalance and weighted mid over `levels` levels, the depth curve
+    /// at `depth_bps` from the mid and VWAPs for an order of `order` size.
+    pub fn new(levels: usize, depth_bps: Vec<f64>, order: Option<OrderSize>) -> Self {
+        Self {
+            levels: levels.max(1),
+            depth_bps,
+            order,
+            features: BookFeatures::default(),
+            recovery: None,
+            total: Decimal::ZERO,
+            edges: [None; 2],
+            reach: [None; 2],
+            mid: None,
+            depth_curve: Vec::new(),
+            deferred: None,
+        }
+    }
+    pub fn depth_bps(&self) -> &[f64] {
+        &self.depth_bps
+    }
+    pub fn features(&self) -> &BookFeatures {
+        &self.features
+    }
+    /// Recomputes the features after `book` has been updated at `timestamp`.
+    pub fn on_update(&mut self, book: &impl Book, timestamp: i64) {
+        self.deferred = None;
+        self.update_top(book);
+        self.update_microprice(book);
+        self.update_depth_curve(book);
+        for side in [Side::Sell, Side::Buy] {
+            self.update_vwap(book, side);
+        }
+        self.update_resilience(book, timestamp);
+    }
+    /// Updates the features after the level at `price` of the side an
+    /// order of `side` takes changed from `before` to its quantity in
+    /// `book`. Only the features the level is part of are recomputed.
+    pub fn on_level(
+        &mut self,
+        book: &impl Book,
+        side: Side,
+        price: Price,
+        before: Quantity,
+        timestamp: i64,
+    ) {
+        if self.deferred.is_some() {
+            return self.on_update(book, timestamp);
+        }
+        if reaches(side, price, self.edges[index(side)]) {
+            self.update_top(book);
+        }
+        self.update_microprice(book);
+        match self.mid.filter(|&mid| book.mid() == Some(mid)) {
+            Some(mid) => {
+                let change = book.quantity(side, price) - before;
+                let bps = bps(price, mid);
+                for (quantities, &limit) in self.depth_curve.iter_mut().zip(&self.depth_bps) {
+                    if bps <= limit {
+                        match side {
+                            Side::Sell => quantities.0 += change,
+                            Side::Buy => quantities.1 += change,
+                        }
+                    }
+                }
+                self.publish_depth_curve();
+            }
+            None => self.update_depth_curve(book),
+        }
+        if reaches(side, price, self.reach[index(side)]) {
+            self.update_vwap(book, side);
+        }
+        self.update_resilience(book, timestamp);
+    }
+    /// Leaves the features as they are while a snapshot is applied level by
+    /// level, until [`Analytics::refresh`].
+    pub fn defer(&mut self, timestamp: i64) {
+        self.deferred = Some(timestamp);
+    }
+    /// Recomputes the features if updates have been deferred.
+    pub fn refresh(&mut self, book: &impl Book) {
+        if let Some(timestamp) = self.deferred {
+            self.on_update(book, timestamp);
+        }
+    }
+    /// Imbalance and weighted mid of the top levels.
+    fn update_top(&mut self, book: &impl Book) {
+        let depth = book.depth(self.levels);
+        let bid_quantity = sum(depth.bids.iter().map(|level| level.quantity));
+        let ask_quantity = sum(depth.asks.iter().map(|level| level.quantity));
+        let total = bid_quantity + ask_quantity;
+        self.features.imbalance =
+            (!total.eq_zero()).then(|| f64::from((bid_quantity - ask_quantity) / total));
+        self.features.weighted_mid = (!total.eq_zero()).then(|| {
+            let notional = sum(depth
+                .bids
+                .iter()
+                .chain(&depth.asks)
+                .map(|level| level.price * level.quantity));
+            f64::from(notional / total)
+        });
+        let edge = |levels: &[Level]| {
+            (levels.len() == self.levels)
+                .then(|| levels.last().map(|level| level.price))
+                .flatten()
+        };
+        self.edges = [edge(&depth.bids), edge(&depth.asks)];
+        self.total = total;
+    }
+    fn update_microprice(&mut self, book: &impl Book) {
+        self.features.microprice = book.best_bid().zip(book.best_ask()).map(|(bid, ask)| {
+            f64::from(
+                (bid.price * ask.quantity + ask.price * bid.quantity)
+                    / (bid.quantity + ask.quantity),
+            )
+        });
+    }
+    fn update_depth_curve(&mut self, book: &impl Book) {
+        self.mid = book.mid();
+        self.depth_curve.clear();
+        if let Some(mid) = self.mid {
+            let max_bps = self.depth_bps.iter().copied().fold(0.0, f64::max);
+            let cumulative = |side| {
+                let mut cumulative = vec![Decimal::ZERO; self.depth_bps.len()];
+                for level in book.levels(side) {
+                    let bps = bps(level.price, mid);
+                    if bps > max_bps {
+                        break;
+                    }
+                    for (quantity, &limit) in cumulative.iter_mut().zip(&self.depth_bps) {
+                        if bps <= limit {
+                            *quantity += level.quantity;
+                        }
+                    }
+                }
+                cumulative
+            };
+            self.depth_curve = cumulative(Side::Sell)
+                .into_iter()
+                .zip(cumulative(Side::Buy))
+                .collect();
+        }
+        self.publish_depth_curve();
+    }
+    fn publish_depth_curve(&mut self) {
+        self.features.depth_curve = self
+            .depth_curve
+            .iter()
+            .map(|&(bid, ask)| (f64::from(bid), f64::from(ask)))
+            .collect();
+    }
+    fn update_vwap(&mut self, book: &impl Book, side: Side) {
+        let execution = self.order.and_then(|order| book.execute(side, order));
+        // Levels past the last one the order took only matter if it took
+        // them all.
+        self.reach[index(side)] = execution.as_ref().and_then(|execution| {
+            let last = execution.fills.last()?;
+            book.levels(side)
+                .nth(execution.levels())
+                .map(|_| last.price)
+        });
+        let vwap = execution
+            .and_then(|execution| execution.average_price())
+            .map(f64::from);
+        match side {
+            Side::Buy => self.features.vwap_buy = vwap,
+            Side::Sell => self.features.vwap_sell = vwap,
+        }
+    }
+    fn update_resilience(&mut self, book: &impl Book, timestamp: i64) {
+        if let (Some(recovery), Some(spread)) = (&self.recovery, book.spread()) {
+            if spread <= recovery.spread && self.total >= recovery.depth {
+                self.features.resilience = Some((timestamp - recovery.timestamp) as f64);
+                self.recovery = None;
+            }
+        }
+    }
+    /// Remembers the state of `book` right before a trade at `timestamp`, to
+    /// time how long the book takes to recover from it. A burst of trades is
+    /// timed from its first one.
+    pub fn on_trade(&mut self, book: &impl Book, timestamp: i64) {
+        if self.recovery.is_some() {
+            return;
+        }
+        let Some(spread) = book.spread() else {
+            return;
+        };
+        let depth = book.depth(self.levels);
+        self.recovery = Some(Recovery {
+            timestamp,
+            spread,
+            depth: sum(depth
+                .bids
+                .iter()
+                .chain(&depth.asks)
+                .map(|level| level.quantity)),
+        });
+    }
+}
+
+/// Index of the side an order of `side` takes in the per side arrays,
+/// bids first.
+fn index(side: Side) -> usize {
+    match side {
+        Side::Sell => 0,
+        Side::Buy => 1,
+    }
+}
+
+/// Whether a level at `price` of the side an order of `side` takes is at or
+/// before `limit`, the worst level a feature looks at.
+fn reaches(side: Side, price: Price, limit: Option<Price>) -> bool {
+    match (side, limit) {
+        (_, None) => true,
+        (Side::Buy, Some(limit)) => price <= limit,
+        (Side::Sell, Some(limit)) => price >= limit,
+    }
+}
+
+/// Distance of `price` from `mid` in basis points.
+fn bps(price: Price, mid: Price) -> f64 {
+    let mid = f64::from(mid);
+    (f64::from(price) - mid).abs() / mid * 10_000.0
+}
+
+fn sum(values: impl Iterator<Item = Decimal>) -> Decimal {
+    values.fold(Decimal::ZERO, |sum, value| sum + value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{orderbook::Orderbook, Event};
+    use proptest::prelude::*;
+
+    fn event(event_type: &str, ask_not_bid: Option<bool>, price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: None,
+            id2: None,
+            ask_not_bid,
+            buy_not_sell: ask_not_bid.is_none().then_some(false),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    /// Bids 2@99 and 1@98, asks 1@101 and 3@102.
+    fn book() -> Orderbook {
+        let mut book = Orderbook::default();
+        for (ask_not_bid, price, quantity) in [
+            (false, "99", "2"),
+            (false, "98", "1"),
+            (true, "101", "1"),
+            (true, "102", "3"),
+        ] {
+            book.update(event("depth", Some(ask_not_bid), price, quantity))
+                .unwrap();
+        }
+        book
+    }
+
+    fn analytics() -> Analytics {
+        let order = OrderSize::Quantity(Decimal::from(2));
+        Analytics::new(2, vec![100.0, 250.0], Some(order))
+    }
+
+    #[test]
+    fn features_of_a_small_book() {
+        let mut analytics = analytics();
+        analytics.on_update(&book(), 0);
+        let features = analytics.features();
+        assert_eq!(features.imbalance, Some(-1.0 / 7.0));
+        assert_eq!(
+            features.weighted_mid,
+            Some(f64::from(Decimal::from(703) / 7))
+        );
+        assert_eq!(features.microprice, Some(f64::from(Decimal::from(301) / 3)));
+        assert_eq!(features.depth_curve, [(2.0, 1.0), (3.0, 4.0)]);
+        assert_eq!(features.vwap_buy, Some(101.5));
+        assert_eq!(features.vwap_sell, Some(99.0));
+        assert_eq!(features.resilience, None);
+    }
+
+    #[test]
+    fn times_the_recovery_from_a_trade() {
+        let mut book = book();
+        let mut analytics = analytics();
+        analytics.on_update(&book, 0);
+        analytics.on_trade(&book, 10);
+        book.handle_trade(event("trade", None, "101", "1")).unwrap();
+        analytics.on_update(&book, 10);
+        assert_eq!(analytics.features().resilience, None);
+
+        let before = book.quantity(Side::Buy, Decimal::from(101));
+        book.update(event("depth", Some(true), "101", "2")).unwrap();
+        analytics.on_level(&book, Side::Buy, Decimal::from(101), before, 35);
+        assert_eq!(analytics.features().resilience, Some(25.0));
+    }
+
+    #[test]
+    fn deferred_updates_wait_for_the_refresh() {
+        let book = book();
+        let mut analytics = analytics();
+        analytics.defer(0);
+        assert_eq!(analytics.features(), &BookFeatures::default());
+        analytics.refresh(&book);
+        let mut full = self::analytics();
+        full.on_update(&book, 0);
+        assert_eq!(analytics.features(), full.features());
+    }
+
+    proptest! {
+        #[test]
+        fn level_updates_match_recomputing(
+            updates in prop::collection::vec(
+                (any::<bool>(), 0i64..6, 0i64..4),
+                0..80,
+            ),
+        ) {
+            let mut book = book();
+            let mut analytics = analytics();
+            analytics.on_update(&book, 0);
+            for (ask_not_bid, price, quantity) in updates {
+                // Asks from 101 and bids from 99 away, the book never crosses.
+                let (side, price) = if ask_not_bid {
+                    (Side::Buy, Decimal::from(101 + price))
+                } else {
+                    (Side::Sell, Decimal::from(99 - price))
+                };
+                let before = book.quantity(side, price);
+                let diff = event("depth", Some(ask_not_bid), &price.to_string(), &quantity.to_string());
+                book.update(diff).unwrap();
+                analytics.on_level(&book, side, price, before, 0);
+                let mut full = self::analytics();
+                full.on_update(&book, 0);
+                prop_assert_eq!(analytics.features(), full.features());
+            }
+        }
+    }
+}
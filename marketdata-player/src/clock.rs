@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::simulator::OrderId;
+
+/// Identifier of a timer, unique within a replay.
+pub type TimerId = u64;
+
+/// What the clock wakes the player up for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Wakeup {
+    /// A timer scheduled by the strategy.
+    Timer(TimerId),
+    /// Arrival of the parent order at this index of the order times.
+    Order(usize),
+    /// A simulated order reaching the book.
+    Arrival(OrderId),
+}
+
+/// Current simulation time and the wakeups scheduled on it. A wakeup at `t`
+/// fires after every event at `t` and before the first event after it, so
+/// the strategy sees the market as of `t`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SimulationClock {
+    now: i64,
+    /// Wakeups by time and then by the order they were scheduled in.
+    queue: BTreeMap<(i64, u64), Wakeup>,
+    scheduled: u64,
+    next_timer: TimerId,
+    /// Queue keys of the pending timers.
+    timers: HashMap<TimerId, (i64, u64)>,
+    /// Intervals of the periodic timers.
+    intervals: HashMap<TimerId, i64>,
+}
+
+impl SimulationClock {
+    pub fn new(now: i64) -> Self {
+        Self {
+            now,
+            ..Default::default()
+        }
+    }
+    /// Timestamp of the last event or wakeup, never going back.
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+    /// Timer firing once at `timestamp`, or right away if it has passed.
+    pub fn at(&mut self, timestamp: i64) -> TimerId {
+        let timer = self.next_timer;
+        self.next_timer += 1;
+        self.schedule_timer(timer, timestamp);
+        timer
+    }
+    /// Timer firing once `delay` milliseconds from now, e.g. after the event
+    /// being handled.
+    pub fn after(&mut self, delay: i64) -> TimerId {
+        self.at(self.now.saturating_add(delay))
+    }
+    /// Timer firing every `interval` milliseconds from now until cancelled.
+    pub fn every(&mut self, interval: i64) -> TimerId {
+        assert!(interval > 0, "timer interval must be positive");
+        let timer = self.after(interval);
+        self.intervals.insert(timer, interval);
+        timer
+    }
+    /// Stops `timer`, nothing if it has already fired.
+    pub fn cancel(&mut self, timer: TimerId) {
+        if let Some(key) = self.timers.remove(&timer) {
+            self.queue.remove(&key);
+        }
+        self.intervals.remove(&timer);
+    }
+    pub(crate) fn order_at(&mut self, timestamp: i64, order: usize) {
+        self.push(timestamp, Wakeup::Order(order));
+    }
+    pub(crate) fn arrival_at(&mut self, timestamp: i64, order: OrderId) {
+        self.push(timestamp, Wakeup::Arrival(order));
+    }
+    /// Takes the next wakeup before `timestamp`, moving the clock to its
+    /// time. Periodic timers are scheduled again.
+    pub(crate) fn next_before(&mut self, timestamp: i64) -> Option<Wakeup> {
+        let entry = self.queue.first_entry()?;
+        let (at, _) = *entry.key();
+        if at >= timestamp {
+            return None;
+        }
+        let wakeup = entry.remove();
+        self.advance(at);
+        if let Wakeup::Timer(timer) = wakeup {
+            self.timers.remove(&timer);
+            if let Some(&interval) = self.intervals.get(&timer) {
+                self.schedule_timer(timer, at.saturating_add(interval));
+            }
+        }
+        Some(wakeup)
+    }
+    /// Moves the clock to `timestamp` unless it is already past it.
+    pub(crate) fn advance(&mut self, timestamp: i64) {
+        self.now = self.now.max(timestamp);
+    }
+    fn schedule_timer(&mut self, timer: TimerId, timestamp: i64) {
+        let key = self.push(timestamp, Wakeup::Timer(timer));
+        self.timers.insert(timer, key);
+    }
+    fn push(&mut self, timestamp: i64, wakeup: Wakeup) -> (i64, u64) {
+        let key = (timestamp.max(self.now), self.scheduled);
+        self.scheduled += 1;
+        self.queue.insert(key, wakeup);
+        key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(clock: &mut SimulationClock, timestamp: i64) -> Vec<(i64, Wakeup)> {
+        std::iter::from_fn(|| {
+            let wakeup = clock.next_before(timestamp)?;
+            Some((clock.now(), wakeup))
+        })
+        .collect()
+    }
+
+    #[test]
+    fn fires_in_time_order() {
+        let mut clock = SimulationClock::new(0);
+        let late = clock.at(30);
+        clock.order_at(10, 0);
+        let periodic = clock.every(10);
+        let early = clock.after(5);
+        assert_eq!(
+            drain(&mut clock, 30),
+            [
+                (5, Wakeup::Timer(early)),
+                (10, Wakeup::Order(0)),
+                (10, Wakeup::Timer(periodic)),
+                (20, Wakeup::Timer(periodic)),
+            ]
+        );
+        clock.cancel(periodic);
+        assert_eq!(drain(&mut clock, 100), [(30, Wakeup::Timer(late))]);
+        assert_eq!(clock.now(), 30);
+    }
+
+    #[test]
+    fn past_timers_fire_now() {
+        let mut clock = SimulationClock::new(0);
+        clock.advance(50);
+        let timer = clock.at(20);
+        assert_eq!(drain(&mut clock, 51), [(50, Wakeup::Timer(timer))]);
+    }
+
+    #[test]
+    fn timers_cancelled_while_firing_stop() {
+        let mut clock = SimulationClock::new(0);
+        let timer = clock.every(10);
+        assert_eq!(clock.next_before(15), Some(Wakeup::Timer(timer)));
+        clock.cancel(timer);
+        assert_eq!(clock.next_before(100), None);
+    }
+}
@@ -1,52 +1,469 @@
-use chrono::{Duration, NaiveDateTime};
-use clickhouse::{query, Client};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clickhouse::{query::RowCursor, sql::Identifier, Client};
 use std::vec::IntoIter;
+use tokio::{sync::mpsc, task::JoinHandle};
 
-use crate::Event;
+use crate::{
+    datasource::{Clock, MarketDataSource},
+    replaycache::ReplayCache,
+    Event,
+};
 
+/// Number of events fetched ahead of the player by default.
+const DEFAULT_PREFETCH: usize = 65_536;
+/// Number of events passed from the background task at once.
+const BATCH_SIZE: usize = 1024;
+
+/// Streams the events of a set of products from ClickHouse with a single
+/// query over `[start_timestamp, end_timestamp)`, ordered by a [`Clock`].
+///
+/// With a [`ReplayCache`] the range is fetched window by window instead,
+/// reusing windows stored by earlier replays and loading the next window
+/// while the player processes the current one.
 pub struct DataProvider {
     client: Client,
-    product: String,
+    products: Vec<String>,
+    event_types: Vec<String>,
+    clock: Clock,
     tablename: String,
-    current_timestamp: NaiveDateTime,
+    start_timestamp: i64,
+    end_timestamp: i64,
+    prefetch: usize,
+    cache: Option<ReplayCache>,
+    events: Option<mpsc::Receiver<Result<Vec<Event>>>>,
     buffer: IntoIter<Event>,
+    task: Option<JoinHandle<()>>,
 }
 
 impl DataProvider {
-    pub fn new(client: Client, product: String, tablename: String, start_timestamp: &str) -> Self {
-        let current_timestamp =
-            NaiveDateTime::parse_from_str(start_timestamp, "%Y-%m-%d %H:%M:%S%.f")
-                .expect("Failed to parse timestamp");
+    pub fn new(
+        client: Client,
+        products: Vec<String>,
+        tablename: String,
+        start_timestamp: i64,
+        end_timestamp: i64,
+    ) -> Self {
         Self {
             client,
-            product,
+            products,
+            event_types: Vec::new(),
+            clock: Clock::Gate,
             tablename,
-            current_timestamp,
+            start_timestamp,
+            end_timestamp,
+            prefetch: DEFAULT_PREFETCH,
+            cache: None,
+            events: None,
             buffer: Vec::new().into_iter(),
+            task: None,
+        }
+    }
+    /// Sets how many events are fetched ahead of the player.
+    pub fn with_prefetch(mut self, events: usize) -> Self {
+        self.prefetch = events.max(1);
+        self
+    }
+    /// Restricts the replay to the given event types, e.g. "trade".
+    pub fn with_event_types(mut self, event_types: Vec<String>) -> Self {
+        self.event_types = event_types;
+        self
+    }
+    /// Sets the timestamp the products are merged by.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+    /// Fetches the range through a local cache of `cache.window()` long
+    /// windows.
+    pub fn with_cache(mut self, cache: ReplayCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+    fn load_marketdata(&mut self) -> Result<()> {
+        match self.cache.clone() {
+            Some(cache) => self.load_windows(cache),
+            None => self.stream(),
+        }
+    }
+    /// Starts the query in a background task that keeps up to `prefetch`
+    /// events buffered.
+    fn stream(&mut self) -> Result<()> {
+        let mut query = self
+            .client
+            .query(&self.query())
+            .bind(Identifier(&self.tablename))
+            .bind(&self.products);
+        if !self.event_types.is_empty() {
+            query = query.bind(&self.event_types);
         }
+        let cursor = query
+            .bind(format_timestamp(self.start_timestamp))
+            .bind(format_timestamp(self.end_timestamp))
+            .fetch::<Event>()?;
+        let (events_tx, events_rx) = mpsc::channel(self.prefetch.div_ceil(BATCH_SIZE));
+        let task = tokio::spawn(forward(cursor, events_tx));
+        self.events = Some(events_rx);
+        self.task = Some(task);
+        Ok(())
+    }
+    /// Query of the whole range, filtering the event types only when the
+    /// replay is restricted to some.
+    fn query(&self) -> String {
+        let event_type_filter = if self.event_types.is_empty() {
+            ""
+        } else {
+            "AND has(?, event_type)"
+        };
+        format!(
+            "SELECT ?fields FROM ? \
+             WHERE has(?, product) {} \
+             AND gate_timestamp >= toDateTime64(?, 3, 'UTC') \
+             AND gate_timestamp < toDateTime64(?, 3, 'UTC') \
+             ORDER BY {}",
+            event_type_filter,
+            self.clock.order_by()
+        )
+    }
+    /// Loads the range window by window in a background task. The channel
+    /// holds a single window, so the next one is fetched while the player
+    /// works through the current one.
+    fn load_windows(&mut self, cache: ReplayCache) -> Result<()> {
+        let (events_tx, events_rx) = mpsc::channel(1);
+        let mut window = Window {
+            client: self.client.clone(),
+            tablename: self.tablename.clone(),
+            products: self.products.clone(),
+            event_types: self.event_types.clone(),
+            clock: self.clock,
+            start_timestamp: self.start_timestamp,
+            end_timestamp: self.end_timestamp,
+            loaded: None,
+        };
+        let task = tokio::spawn(async move {
+            for window_start in cache.windows(window.start_timestamp, window.end_timestamp) {
+                let events = window.load(&cache, window_start).await;
+                let failed = events.is_err();
+                if events_tx.send(events).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        self.events = Some(events_rx);
+        self.task = Some(task);
+        Ok(())
+    }
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.events = None;
+        self.buffer = Vec::new().into_iter();
+    }
+}
+
+/// Rows of a query, in the order the player replays them.
+#[async_trait]
+trait EventCursor: Send + 'static {
+    async fn next(&mut self) -> Result<Option<Event>>;
+}
+
+#[async_trait]
+impl EventCursor for RowCursor<Event> {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        Ok(RowCursor::next(self).await?)
     }
-    async fn load_marketdata(&mut self) -> Option<()> {
-        let next_timestamp = self.current_timestamp + Duration::minutes(5);
-        let query = format!("SELECT local_unique_id, venue_timestamp, gate_timestamp, event_type, product, id1, id2, ask_not_bid, buy_not_sell, price, quantity FROM {} WHERE product = '{}' AND gate_timestamp >= toDateTime64('{}', 3, 'UTC') AND gate_timestamp < toDateTime64('{}', 3, 'UTC')",
-        self.tablename, self.product, self.current_timestamp, next_timestamp);
-        self.current_timestamp = next_timestamp;
-        let events = self.client.query(&query).fetch_all::<Event>().await.ok();
-        if let Some(events) = events {
-            self.buffer = events.into_iter();
-            return Some(());
+}
+
+/// Passes the rows of `cursor` to the player in batches of [`BATCH_SIZE`].
+/// An error ends the stream after being passed on, and the task stops as
+/// soon as the player is gone.
+async fn forward(mut cursor: impl EventCursor, events_tx: mpsc::Sender<Result<Vec<Event>>>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    loop {
+        match cursor.next().await {
+            Ok(Some(event)) => {
+                batch.push(event);
+                if batch.len() < BATCH_SIZE {
+                    continue;
+                }
+            }
+            Ok(None) => {
+                if !batch.is_empty() {
+                    let _ = events_tx.send(Ok(batch)).await;
+                }
+                break;
+            }
+            Err(err) => {
+                let _ = events_tx.send(Err(err)).await;
+                break;
+            }
+        }
+        let batch = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+        if events_tx.send(Ok(batch)).await.is_err() {
+            break;
         }
-        None
     }
-    pub async fn next(&mut self) -> Option<Event> {
-        if let Some(event) = self.buffer.next() {
-            return Some(event);
+}
+
+/// What the background task of a cached replay needs to load a window.
+struct Window {
+    client: Client,
+    tablename: String,
+    products: Vec<String>,
+    event_types: Vec<String>,
+    clock: Clock,
+    start_timestamp: i64,
+    end_timestamp: i64,
+    /// Gate timestamp of the last event of the products in the table,
+    /// queried with the first window that is not cached.
+    loaded: Option<i64>,
+}
+
+impl Window {
+    /// Events of every product within the window starting at
+    /// `window_start`, clipped to the replay range and merged by the clock.
+    async fn load(&mut self, cache: &ReplayCache, window_start: i64) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for product in self.products.clone() {
+            let cached = cache.read(&self.tablename, &product, window_start).await?;
+            let product_events = match cached {
+                Some(product_events) => product_events,
+                None => {
+                    let product_events = self.fetch(&product, window_start, cache.window()).await?;
+                    // Windows that may still be loaded into are fetched
+                    // again next time. Settled empty ones are cached too, so
+                    // that gaps in the data are not queried on every replay.
+                    let loaded = self.loaded().await?;
+                    if cache.settled(window_start, loaded, Utc::now().timestamp_millis()) {
+                        cache
+                            .write(&self.tablename, &product, window_start, &product_events)
+                            .await?;
+                    }
+                    product_events
+                }
+            };
+            events.extend(product_events.into_iter().filter(|event| {
+                event.gate_timestamp >= self.start_timestamp
+                    && event.gate_timestamp < self.end_timestamp
+                    && (self.event_types.is_empty() || self.event_types.contains(&event.event_type))
+            }));
         }
-        if let Some(_) = self.load_marketdata().await {
-            return self.buffer.next();
+        events.sort_by(|a, b| self.clock.key(a).cmp(&self.clock.key(b)));
+        Ok(events)
+    }
+    async fn loaded(&mut self) -> Result<i64> {
+        if let Some(loaded) = self.loaded {
+            return Ok(loaded);
         }
-        None
+        let loaded = self
+            .client
+            .query(
+                "SELECT toUnixTimestamp64Milli(max(gate_timestamp)) FROM ? WHERE has(?, product)",
+            )
+            .bind(Identifier(&self.tablename))
+            .bind(&self.products)
+            .fetch_one::<i64>()
+            .await?;
+        Ok(*self.loaded.insert(loaded))
+    }
+    /// Every event of a product within a window, as stored in the cache.
+    async fn fetch(&self, product: &str, window_start: i64, window: i64) -> Result<Vec<Event>> {
+        Ok(self
+            .client
+            .query(&format!(
+                "SELECT ?fields FROM ? \
+                 WHERE product = ? \
+                 AND gate_timestamp >= toDateTime64(?, 3, 'UTC') \
+                 AND gate_timestamp < toDateTime64(?, 3, 'UTC') \
+                 ORDER BY {}",
+                Clock::Gate.order_by()
+            ))
+            .bind(Identifier(&self.tablename))
+            .bind(product)
+            .bind(format_timestamp(window_start))
+            .bind(format_timestamp(window_start + window))
+            .fetch_all::<Event>()
+            .await?)
+    }
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp_millis(timestamp)
+        .expect("timestamp out of range")
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string()
+}
+
+impl Drop for DataProvider {
+    fn drop(&mut self) {
+        self.stop();
     }
-    pub fn product(&self) -> String {
-        self.product.clone()
+}
+
+#[async_trait]
+impl MarketDataSource for DataProvider {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        if self.events.is_none() {
+            self.load_marketdata()?;
+        }
+        loop {
+            if let Some(event) = self.buffer.next() {
+                return Ok(Some(event));
+            }
+            let events = self.events.as_mut().expect("stream has been started");
+            match events.recv().await {
+                Some(batch) => self.buffer = batch?.into_iter(),
+                None => return Ok(None),
+            }
+        }
+    }
+    async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        self.stop();
+        self.start_timestamp = timestamp;
+        Ok(())
+    }
+    async fn time_range(&mut self) -> Result<Option<(i64, i64)>> {
+        let (first, last, count) = self
+            .client
+            .query(
+                "SELECT toUnixTimestamp64Milli(min(gate_timestamp)), \
+                 toUnixTimestamp64Milli(max(gate_timestamp)), count() \
+                 FROM ? WHERE has(?, product)",
+            )
+            .bind(Identifier(&self.tablename))
+            .bind(&self.products)
+            .fetch_one::<(i64, i64, u64)>()
+            .await?;
+        Ok((count > 0).then_some((first, last)))
+    }
+    async fn last_snapshot(&mut self, timestamp: i64) -> Result<Option<i64>> {
+        let snapshots = self
+            .client
+            .query(
+                "SELECT product, toUnixTimestamp64Milli(max(gate_timestamp)) \
+                 FROM ? WHERE has(?, product) AND event_type = 'snapshot' \
+                 AND gate_timestamp <= toDateTime64(?, 3, 'UTC') \
+                 GROUP BY product",
+            )
+            .bind(Identifier(&self.tablename))
+            .bind(&self.products)
+            .bind(format_timestamp(timestamp))
+            .fetch_all::<(String, i64)>()
+            .await?;
+        Ok(snapshots.into_iter().map(|(_, snapshot)| snapshot).min())
+    }
+    fn products(&self) -> Vec<String> {
+        self.products.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::memory::MemorySource;
+    use anyhow::anyhow;
+
+    /// Replays the events of a [`MemorySource`], then fails like a dropped
+    /// connection.
+    struct Failing(MemorySource);
+
+    #[async_trait]
+    impl EventCursor for Failing {
+        async fn next(&mut self) -> Result<Option<Event>> {
+            match MarketDataSource::next(&mut self.0).await? {
+                Some(event) => Ok(Some(event)),
+                None => Err(anyhow!("connection reset")),
+            }
+        }
+    }
+
+    fn event(gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: "trade".to_string(),
+            product: "TEST".to_string(),
+            id1: Some(gate_timestamp as u64),
+            id2: None,
+            ask_not_bid: None,
+            buy_not_sell: Some(false),
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    fn provider() -> DataProvider {
+        // Nothing listens on the port, so every query fails.
+        let client = Client::default().with_url("http://127.0.0.1:1");
+        DataProvider::new(client, vec!["TEST".to_string()], "md".to_string(), 0, 1000)
+    }
+
+    fn normalize(query: String) -> String {
+        query.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn filters_event_types_only_when_restricted() {
+        let query = normalize(provider().query());
+        assert!(!query.contains("has(?, event_type)"));
+        assert!(query.contains("WHERE has(?, product) AND gate_timestamp >="));
+        assert!(query.ends_with(&format!("ORDER BY {}", Clock::Gate.order_by())));
+
+        let query = normalize(
+            provider()
+                .with_event_types(vec!["trade".to_string()])
+                .with_clock(Clock::Venue)
+                .query(),
+        );
+        assert!(query.contains("WHERE has(?, product) AND has(?, event_type) AND"));
+        assert!(query.ends_with(&format!("ORDER BY {}", Clock::Venue.order_by())));
+    }
+
+    #[tokio::test]
+    async fn forwards_batches_then_the_error() {
+        let events = (0..BATCH_SIZE as i64 + 10).map(event).collect();
+        let (events_tx, mut events_rx) = mpsc::channel(1);
+        tokio::spawn(forward(Failing(MemorySource::new(events)), events_tx));
+        let batch = events_rx.recv().await.unwrap().unwrap();
+        assert_eq!(batch.len(), BATCH_SIZE);
+        let err = events_rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "connection reset");
+        assert!(events_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn passes_query_errors_to_the_player() {
+        let mut streamed = provider();
+        assert!(streamed.next().await.is_err());
+        assert!(streamed.next().await.unwrap().is_none());
+
+        let cache_dir = std::env::temp_dir().join(format!("provider-{}", std::process::id()));
+        let mut cached = provider().with_cache(ReplayCache::new(&cache_dir, 100));
+        assert!(cached.next().await.is_err());
+        assert!(cached.next().await.unwrap().is_none());
+        assert!(!cache_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn replays_cached_windows_without_the_table() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("provider-cached-{}", std::process::id()));
+        let cache = ReplayCache::new(&cache_dir, 500);
+        let events: Vec<Event> = [100, 600, 900, 1200].into_iter().map(event).collect();
+        cache.write("md", "TEST", 0, &events[..1]).await.unwrap();
+        cache.write("md", "TEST", 500, &events[1..3]).await.unwrap();
+        cache.write("md", "TEST", 1000, &events[3..]).await.unwrap();
+
+        let mut cached = provider().with_cache(cache);
+        cached.seek(600).await.unwrap();
+        let mut timestamps = Vec::new();
+        while let Some(event) = cached.next().await.unwrap() {
+            timestamps.push(event.gate_timestamp);
+        }
+        assert_eq!(timestamps, [600, 900]);
+        std::fs::remove_dir_all(cache_dir).unwrap();
     }
 }
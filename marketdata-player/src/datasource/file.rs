@@ -0,0 +1,336 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use flate2::read::GzDecoder;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::{datasource::MarketDataSource, Event};
+
+/// Replays the raw `.bin` capture files written by `binance-api-integration`
+/// and the `.bin.gz` archives `clickhouse-integration retention` compresses
+/// them into. Events are passed on as they were captured: a message the collector
+/// received twice is replayed twice, and within a file events are in the
+/// order they arrived in. The sorted table `clickhouse-integration` builds
+/// is the deduplicated replay.
+pub struct FileSource {
+    /// Capture files with the time they were opened at, in that order.
+    files: Vec<(Option<i64>, PathBuf)>,
+    products: HashSet<String>,
+    next_file: usize,
+    reader: Option<BufReader<Box<dyn Read + Send>>>,
+    start_timestamp: i64,
+    end_timestamp: i64,
+}
+
+impl FileSource {
+    /// Reads the capture files in `path`, keeping the events of `products`.
+    pub fn new(path: impl AsRef<Path>, products: Vec<String>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut files = Vec::new();
+        for entry in read_dir(path).with_context(|| format!("failed to read {:?}", path))? {
+            let file = entry?.path();
+            let name = file
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default();
+            if file.is_file() && (name.ends_with(".bin") || name.ends_with(".bin.gz")) {
+                // The collector names files after the moment it opened them,
+                // e.g. "26-11-2024 05-00-00.bin".
+                let opened = name
+                    .split(".bin")
+                    .next()
+                    .and_then(|stem| NaiveDateTime::parse_from_str(stem, "%d-%m-%Y %H-%M-%S").ok())
+                    .map(|opened| opened.and_utc().timestamp_millis());
+                files.push((opened, file));
+            }
+        }
+        files.sort();
+        Ok(Self {
+            files,
+            products: products.into_iter().collect(),
+            next_file: 0,
+            reader: None,
+            start_timestamp: i64::MIN,
+            end_timestamp: i64::MAX,
+        })
+    }
+
+    /// Stops the replay before `timestamp`.
+    pub fn with_end_timestamp(mut self, timestamp: i64) -> Self {
+        self.end_timestamp = timestamp;
+        self
+    }
+
+    /// Gate timestamps of the first and the last event of interest in `file`.
+    fn scan(&self, file: &Path) -> Result<Option<(i64, i64)>> {
+        let mut reader = open(file)?;
+        let mut range: Option<(i64, i64)> = None;
+        while let Some(event) = read_event(&mut reader)? {
+            if self.products.contains(&event.product) {
+                let (first, last) =
+                    range.get_or_insert((event.gate_timestamp, event.gate_timestamp));
+                *first = (*first).min(event.gate_timestamp);
+                *last = (*last).max(event.gate_timestamp);
+            }
+        }
+        Ok(range)
+    }
+}
+
+/// Reader of the events of capture file `file`, decompressing archives.
+fn open(file: &Path) -> Result<BufReader<Box<dyn Read + Send>>> {
+    let reader = File::open(file).with_context(|| format!("failed to open {:?}", file))?;
+    let reader: Box<dyn Read + Send> =
+        if file.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+            Box::new(GzDecoder::new(reader))
+        } else {
+            Box::new(reader)
+        };
+    Ok(BufReader::new(reader))
+}
+
+fn read_event(reader: &mut impl Read) -> Result<Option<Event>> {
+    match bincode::deserialize_from::<_, Event>(reader) {
+        Ok(event) => Ok(Some(event)),
+        Err(err) => match *err {
+            bincode::ErrorKind::Io(ref io_err)
+                if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                Ok(None)
+            }
+            _ => Err(err.into()),
+        },
+    }
+}
+
+#[async_trait]
+impl MarketDataSource for FileSource {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            let Some(reader) = self.reader.as_mut() else {
+                let Some((opened, file)) = self.files.get(self.next_file) else {
+                    return Ok(None);
+                };
+                if opened.is_some_and(|opened| opened >= self.end_timestamp) {
+                    return Ok(None);
+                }
+                self.reader = Some(open(file)?);
+                self.next_file += 1;
+                continue;
+            };
+            match read_event(reader)? {
+                Some(event) => {
+                    if event.gate_timestamp >= self.start_timestamp
+                        && event.gate_timestamp < self.end_timestamp
+                        && self.products.contains(&event.product)
+                    {
+                        return Ok(Some(event));
+                    }
+                }
+                None => self.reader = None,
+            }
+        }
+    }
+    async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        // Events of a file come after the moment it was opened, so reading
+        // starts from the last file opened before `timestamp`.
+        self.next_file = self
+            .files
+            .partition_point(|(opened, _)| opened.is_none_or(|opened| opened <= timestamp))
+            .saturating_sub(1);
+        self.reader = None;
+        self.start_timestamp = timestamp;
+        Ok(())
+    }
+    /// Reads whole files from either end until one has events of the
+    /// products, so the first and the last files are read in full.
+    async fn time_range(&mut self) -> Result<Option<(i64, i64)>> {
+        let mut first = None;
+        for (_, file) in &self.files {
+            if let Some((timestamp, _)) = self.scan(file)? {
+                first = Some(timestamp);
+                break;
+            }
+        }
+        let mut last = None;
+        for (_, file) in self.files.iter().rev() {
+            if let Some((_, timestamp)) = self.scan(file)? {
+                last = Some(timestamp);
+                break;
+            }
+        }
+        Ok(first.zip(last))
+    }
+    async fn last_snapshot(&mut self, timestamp: i64) -> Result<Option<i64>> {
+        let mut snapshots: HashMap<String, i64> = HashMap::new();
+        // Files are read backwards from the last one opened before
+        // `timestamp` until every product has been seen.
+        for (opened, file) in self.files.iter().rev() {
+            if opened.is_none_or(|opened| opened > timestamp) {
+                continue;
+            }
+            let mut file_snapshots = HashMap::new();
+            let mut reader = open(file)?;
+            while let Some(event) = read_event(&mut reader)? {
+                if event.gate_timestamp > timestamp {
+                    break;
+                }
+                if event.event_type == "snapshot" && self.products.contains(&event.product) {
+                    file_snapshots.insert(event.product, event.gate_timestamp);
+                }
+            }
+            for (product, snapshot) in file_snapshots {
+                snapshots.entry(product).or_insert(snapshot);
+            }
+            if snapshots.len() == self.products.len() {
+                break;
+            }
+        }
+        Ok(snapshots.into_values().min())
+    }
+    fn products(&self) -> Vec<String> {
+        self.products.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    fn event(product: &str, event_type: &str, gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: product.to_string(),
+            id1: Some(gate_timestamp as u64),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    fn opened(stem: &str) -> i64 {
+        NaiveDateTime::parse_from_str(stem, "%d-%m-%Y %H-%M-%S")
+            .unwrap()
+            .and_utc()
+            .timestamp_millis()
+    }
+
+    /// Two capture files an hour apart, each with a snapshot and a depth
+    /// event of `A` and a depth event of `B`, the first one archived.
+    fn capture(name: &str) -> (PathBuf, i64, i64) {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = opened("26-11-2024 05-00-00");
+        let second = opened("26-11-2024 06-00-00");
+        for opened in [first, second] {
+            let stem = chrono::DateTime::from_timestamp_millis(opened)
+                .unwrap()
+                .format("%d-%m-%Y %H-%M-%S");
+            let mut bytes = Vec::new();
+            for event in [
+                event("A", "snapshot", opened + 10),
+                event("B", "depth", opened + 20),
+                event("A", "depth", opened + 30),
+            ] {
+                bytes.extend(bincode::serialize(&event).unwrap());
+            }
+            if opened == first {
+                let file = File::create(dir.join(format!("{stem}.bin.gz"))).unwrap();
+                let mut encoder = GzEncoder::new(file, Compression::default());
+                encoder.write_all(&bytes).unwrap();
+                encoder.finish().unwrap();
+            } else {
+                std::fs::write(dir.join(format!("{stem}.bin")), bytes).unwrap();
+            }
+        }
+        File::create(dir.join("notes.txt")).unwrap();
+        (dir, first, second)
+    }
+
+    async fn drain(source: &mut FileSource) -> Vec<(String, i64)> {
+        let mut events = Vec::new();
+        while let Some(event) = source.next().await.unwrap() {
+            events.push((event.product, event.gate_timestamp));
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn replays_the_products_until_the_end_timestamp() {
+        let (dir, first, second) = capture("file-source-end");
+        let mut source = FileSource::new(&dir, vec!["A".to_string()])
+            .unwrap()
+            .with_end_timestamp(second + 30);
+        assert_eq!(
+            drain(&mut source).await,
+            [
+                ("A".to_string(), first + 10),
+                ("A".to_string(), first + 30),
+                ("A".to_string(), second + 10)
+            ]
+        );
+        assert_eq!(source.products(), ["A"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeks_into_the_file_opened_before_the_timestamp() {
+        let (dir, first, second) = capture("file-source-seek");
+        let mut source = FileSource::new(&dir, vec!["A".to_string(), "B".to_string()]).unwrap();
+        source.seek(first + 20).await.unwrap();
+        let events = drain(&mut source).await;
+        let timestamps: Vec<i64> = events.iter().map(|(_, timestamp)| *timestamp).collect();
+        assert_eq!(
+            timestamps,
+            [
+                first + 20,
+                first + 30,
+                second + 10,
+                second + 20,
+                second + 30
+            ]
+        );
+
+        source.seek(second + 25).await.unwrap();
+        assert_eq!(drain(&mut source).await, [("A".to_string(), second + 30)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ranges_and_snapshots_of_the_products() {
+        let (dir, first, second) = capture("file-source-range");
+        let mut source = FileSource::new(&dir, vec!["B".to_string()]).unwrap();
+        assert_eq!(
+            source.time_range().await.unwrap(),
+            Some((first + 20, second + 20))
+        );
+        assert_eq!(source.last_snapshot(second + 100).await.unwrap(), None);
+
+        let mut source = FileSource::new(&dir, vec!["A".to_string()]).unwrap();
+        assert_eq!(
+            source.time_range().await.unwrap(),
+            Some((first + 10, second + 30))
+        );
+        assert_eq!(
+            source.last_snapshot(second).await.unwrap(),
+            Some(first + 10)
+        );
+        assert_eq!(
+            source.last_snapshot(second + 100).await.unwrap(),
+            Some(second + 10)
+        );
+        assert_eq!(source.last_snapshot(first).await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
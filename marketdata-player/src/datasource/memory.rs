@@ -0,0 +1,105 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{datasource::MarketDataSource, Event};
+
+/// Replays events held in memory, ordered by gate timestamp.
+pub struct MemorySource {
+    events: Vec<Event>,
+    position: usize,
+}
+
+impl MemorySource {
+    pub fn new(events: Vec<Event>) -> Self {
+        Self {
+            events,
+            position: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl MarketDataSource for MemorySource {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        let event = self.events.get(self.position).cloned();
+        if event.is_some() {
+            self.position += 1;
+        }
+        Ok(event)
+    }
+    async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        self.position = self
+            .events
+            .partition_point(|event| event.gate_timestamp < timestamp);
+        Ok(())
+    }
+    async fn time_range(&mut self) -> Result<Option<(i64, i64)>> {
+        Ok(self
+            .events
+            .first()
+            .zip(self.events.last())
+            .map(|(first, last)| (first.gate_timestamp, last.gate_timestamp)))
+    }
+    async fn last_snapshot(&mut self, timestamp: i64) -> Result<Option<i64>> {
+        let mut snapshots: HashMap<&str, i64> = HashMap::new();
+        for event in &self.events {
+            if event.event_type == "snapshot" && event.gate_timestamp <= timestamp {
+                snapshots.insert(&event.product, event.gate_timestamp);
+            }
+        }
+        Ok(snapshots.into_values().min())
+    }
+    fn products(&self) -> Vec<String> {
+        let products: BTreeSet<&String> = self.events.iter().map(|event| &event.product).collect();
+        products.into_iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(product: &str, event_type: &str, gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: product.to_string(),
+            id1: Some(gate_timestamp as u64),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn seeks_and_finds_the_last_snapshots() {
+        let mut source = MemorySource::new(vec![
+            event("A", "snapshot", 10),
+            event("B", "snapshot", 20),
+            event("A", "depth", 30),
+            event("A", "snapshot", 40),
+            event("B", "depth", 40),
+        ]);
+        assert_eq!(source.products(), ["A", "B"]);
+        assert_eq!(source.time_range().await.unwrap(), Some((10, 40)));
+        assert_eq!(source.last_snapshot(15).await.unwrap(), Some(10));
+        assert_eq!(source.last_snapshot(40).await.unwrap(), Some(20));
+
+        source.seek(30).await.unwrap();
+        let mut timestamps = Vec::new();
+        while let Some(event) = source.next().await.unwrap() {
+            timestamps.push(event.gate_timestamp);
+        }
+        assert_eq!(timestamps, [30, 40, 40]);
+        assert!(MemorySource::new(Vec::new())
+            .time_range()
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
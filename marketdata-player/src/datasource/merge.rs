@@ -0,0 +1,186 @@
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    datasource::{Clock, MarketDataSource},
+    Event,
+};
+
+/// Merges several sources, each ordered by `clock`, into a single timeline.
+pub struct MergedSource {
+    sources: Vec<Box<dyn MarketDataSource>>,
+    clock: Clock,
+    /// Next event of every source, `None` until it is read or once the
+    /// source is exhausted.
+    heads: Vec<Option<Event>>,
+    primed: bool,
+}
+
+impl MergedSource {
+    pub fn new(sources: Vec<Box<dyn MarketDataSource>>, clock: Clock) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        Self {
+            sources,
+            clock,
+            heads,
+            primed: false,
+        }
+    }
+}
+
+#[async_trait]
+impl MarketDataSource for MergedSource {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        if !self.primed {
+            for (head, source) in self.heads.iter_mut().zip(self.sources.iter_mut()) {
+                *head = source.next().await?;
+            }
+            self.primed = true;
+        }
+        let clock = self.clock;
+        let Some(index) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(index, head)| head.as_ref().map(|event| (index, event)))
+            .min_by(|(_, a), (_, b)| clock.key(a).cmp(&clock.key(b)))
+            .map(|(index, _)| index)
+        else {
+            return Ok(None);
+        };
+        let next = self.sources[index].next().await?;
+        Ok(std::mem::replace(&mut self.heads[index], next))
+    }
+    async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        for source in self.sources.iter_mut() {
+            source.seek(timestamp).await?;
+        }
+        self.heads.iter_mut().for_each(|head| *head = None);
+        self.primed = false;
+        Ok(())
+    }
+    async fn time_range(&mut self) -> Result<Option<(i64, i64)>> {
+        let mut range: Option<(i64, i64)> = None;
+        for source in self.sources.iter_mut() {
+            if let Some((first, last)) = source.time_range().await? {
+                range = Some(match range {
+                    Some((min, max)) => (min.min(first), max.max(last)),
+                    None => (first, last),
+                });
+            }
+        }
+        Ok(range)
+    }
+    async fn last_snapshot(&mut self, timestamp: i64) -> Result<Option<i64>> {
+        let mut earliest: Option<i64> = None;
+        for source in self.sources.iter_mut() {
+            if let Some(snapshot) = source.last_snapshot(timestamp).await? {
+                earliest = Some(earliest.map_or(snapshot, |earliest| earliest.min(snapshot)));
+            }
+        }
+        Ok(earliest)
+    }
+    fn products(&self) -> Vec<String> {
+        let mut products: Vec<String> = self
+            .sources
+            .iter()
+            .flat_map(|source| source.products())
+            .collect();
+        products.sort();
+        products.dedup();
+        products
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::memory::MemorySource;
+
+    fn event(product: &str, venue_timestamp: i64, gate_timestamp: i64, update_id: u64) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp,
+            gate_timestamp,
+            event_type: "depth".to_string(),
+            product: product.to_string(),
+            id1: Some(update_id),
+            id2: Some(update_id),
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    async fn drain(source: &mut MergedSource) -> Vec<(String, i64, i64, u64)> {
+        let mut events = Vec::new();
+        while let Some(event) = source.next().await.unwrap() {
+            let update_id = event.id2.unwrap();
+            events.push((
+                event.product,
+                event.venue_timestamp,
+                event.gate_timestamp,
+                update_id,
+            ));
+        }
+        events
+    }
+
+    fn sources() -> Vec<Box<dyn MarketDataSource>> {
+        vec![
+            Box::new(MemorySource::new(vec![
+                event("B", 10, 20, 1),
+                event("B", 14, 28, 2),
+                event("B", 15, 30, 3),
+            ])),
+            Box::new(MemorySource::new(vec![
+                event("A", 12, 20, 7),
+                event("A", 15, 25, 8),
+                event("A", 15, 30, 9),
+            ])),
+        ]
+    }
+
+    #[tokio::test]
+    async fn merges_by_the_clock_and_breaks_ties_by_the_rest_of_the_key() {
+        let mut source = MergedSource::new(sources(), Clock::Venue);
+        assert_eq!(source.products(), ["A", "B"]);
+        let order: Vec<(String, u64)> = drain(&mut source)
+            .await
+            .into_iter()
+            .map(|(product, _, _, update_id)| (product, update_id))
+            .collect();
+        let expected = [("B", 1), ("A", 7), ("B", 2), ("A", 8), ("A", 9), ("B", 3)];
+        assert_eq!(
+            order,
+            expected.map(|(product, update_id)| (product.to_string(), update_id))
+        );
+
+        let mut source = MergedSource::new(sources(), Clock::Gate);
+        let order: Vec<(String, u64)> = drain(&mut source)
+            .await
+            .into_iter()
+            .map(|(product, _, _, update_id)| (product, update_id))
+            .collect();
+        let expected = [("B", 1), ("A", 7), ("A", 8), ("B", 2), ("A", 9), ("B", 3)];
+        assert_eq!(
+            order,
+            expected.map(|(product, update_id)| (product.to_string(), update_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn seeks_every_source() {
+        let mut source = MergedSource::new(sources(), Clock::Gate);
+        assert_eq!(source.time_range().await.unwrap(), Some((20, 30)));
+        source.next().await.unwrap();
+        source.seek(30).await.unwrap();
+        let gate: Vec<i64> = drain(&mut source)
+            .await
+            .into_iter()
+            .map(|(_, _, gate, _)| gate)
+            .collect();
+        assert_eq!(gate, [30, 30]);
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+use crate::{
+    datasource::{Clock, EventKey, MarketDataSource},
+    Event,
+};
+
+/// Puts the events of a source in arrival order, such as capture files,
+/// into `clock` order. Events are held back until the source has moved
+/// `window` milliseconds of gate time past them, so an event may be at most
+/// `window` milliseconds earlier on the clock than when it arrived.
+pub struct ReorderedSource {
+    source: Box<dyn MarketDataSource>,
+    clock: Clock,
+    window: i64,
+    /// Events read ahead, with the order they were read in to keep equal
+    /// keys apart.
+    pending: BTreeMap<(EventKey, u64), Event>,
+    read: u64,
+    /// Latest gate timestamp read from the source.
+    arrived: i64,
+    /// Key of the last event passed on.
+    released: Option<EventKey>,
+    exhausted: bool,
+}
+
+impl ReorderedSource {
+    pub fn new(source: Box<dyn MarketDataSource>, clock: Clock, window: i64) -> Self {
+        Self {
+            source,
+            clock,
+            window: window.max(0),
+            pending: BTreeMap::new(),
+            read: 0,
+            arrived: i64::MIN,
+            released: None,
+            exhausted: false,
+        }
+    }
+}
+
+#[async_trait]
+impl MarketDataSource for ReorderedSource {
+    async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            if let Some(entry) = self.pending.first_entry() {
+                let ((clock, ..), _) = entry.key();
+                if self.exhausted || *clock < self.arrived.saturating_sub(self.window) {
+                    let ((key, _), event) = entry.remove_entry();
+                    self.released = Some(key);
+                    return Ok(Some(event));
+                }
+            }
+            if self.exhausted {
+                return Ok(None);
+            }
+            let Some(event) = self.source.next().await? else {
+                self.exhausted = true;
+                continue;
+            };
+            let key = self.clock.owned_key(&event);
+            if self
+                .released
+                .as_ref()
+                .is_some_and(|released| key < *released)
+            {
+                bail!(
+                    "{} {} of {} arrived at {}, more than the reorder window of {} ms late",
+                    event.event_type,
+                    key.0,
+                    event.product,
+                    event.gate_timestamp,
+                    self.window
+                );
+            }
+            self.arrived = self.arrived.max(event.gate_timestamp);
+            self.pending.insert((key, self.read), event);
+            self.read += 1;
+        }
+    }
+    async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        self.source.seek(timestamp).await?;
+        self.pending.clear();
+        self.arrived = i64::MIN;
+        self.released = None;
+        self.exhausted = false;
+        Ok(())
+    }
+    async fn time_range(&mut self) -> Result<Option<(i64, i64)>> {
+        self.source.time_range().await
+    }
+    async fn last_snapshot(&mut self, timestamp: i64) -> Result<Option<i64>> {
+        self.source.last_snapshot(timestamp).await
+    }
+    fn products(&self) -> Vec<String> {
+        self.source.products()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::memory::MemorySource;
+
+    fn event(event_type: &str, venue_timestamp: i64, gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(venue_timestamp as u64),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    async fn drain(source: &mut ReorderedSource) -> Result<Vec<(i64, i64)>> {
+        let mut events = Vec::new();
+        while let Some(event) = source.next().await? {
+            events.push((event.venue_timestamp, event.gate_timestamp));
+        }
+        Ok(events)
+    }
+
+    #[tokio::test]
+    async fn sorts_arrivals_by_the_clock_within_the_window() {
+        // The snapshot is stamped with its arrival time on both clocks, so
+        // it arrives before depth that happened earlier at the exchange.
+        let events = vec![
+            event("depth", 100, 150),
+            event("snapshot", 160, 160),
+            event("depth", 120, 170),
+            event("trade", 155, 180),
+            event("depth", 200, 260),
+        ];
+        let mut source = ReorderedSource::new(
+            Box::new(MemorySource::new(events.clone())),
+            Clock::Venue,
+            100,
+        );
+        assert_eq!(
+            drain(&mut source).await.unwrap(),
+            [(100, 150), (120, 170), (155, 180), (160, 160), (200, 260)]
+        );
+
+        let mut source = ReorderedSource::new(Box::new(MemorySource::new(events)), Clock::Gate, 0);
+        let gate: Vec<i64> = drain(&mut source)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, gate)| gate)
+            .collect();
+        assert_eq!(gate, [150, 160, 170, 180, 260]);
+    }
+
+    #[tokio::test]
+    async fn fails_on_events_later_than_the_window() {
+        let events = vec![
+            event("depth", 100, 110),
+            event("depth", 300, 310),
+            event("depth", 90, 320),
+        ];
+        let mut source =
+            ReorderedSource::new(Box::new(MemorySource::new(events)), Clock::Venue, 100);
+        let err = drain(&mut source).await.unwrap_err();
+        assert!(err.to_string().contains("reorder window of 100 ms"));
+    }
+
+    #[tokio::test]
+    async fn breaks_ties_by_the_event_type_then_its_id() {
+        // Trade ids and depth update ids are unrelated counters.
+        let mut events = vec![
+            event("trade", 100, 100),
+            event("depth", 100, 100),
+            event("depth", 100, 100),
+        ];
+        events[0].id1 = Some(1);
+        for (event, (first, last)) in events[1..].iter_mut().zip([(190, 200), (101, 150)]) {
+            event.id1 = Some(first);
+            event.id2 = Some(last);
+        }
+        let mut source = ReorderedSource::new(Box::new(MemorySource::new(events)), Clock::Gate, 0);
+        let mut order = Vec::new();
+        while let Some(event) = source.next().await.unwrap() {
+            order.push((event.event_type, event.id1.unwrap()));
+        }
+        let expected = [("depth", 101), ("depth", 190), ("trade", 1)];
+        assert_eq!(
+            order,
+            expected.map(|(event_type, id)| (event_type.to_string(), id))
+        );
+    }
+}
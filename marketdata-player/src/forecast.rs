@@ -0,0 +1,131 @@
+pub mod evaluation;
+pub mod ewma;
+pub mod hotelling;
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use self::{ewma::EwmaModel, hotelling::HotellingModel};
+use crate::analytics::BookFeatures;
+
+/// What a [`PriceModel`] learns from.
+#[derive(Debug, Clone, Copy)]
+pub enum Observation<'a> {
+    /// A trade at `price`, `delta_t` milliseconds after the previous event
+    /// of its product.
+    Trade { delta_t: i64, price: f64 },
+    /// The book at the moment of a forecast.
+    Depth(&'a BookFeatures),
+}
+
+/// Interval the price is expected to be in with the confidence it was
+/// predicted for.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Forecast {
+    pub lower: f64,
+    pub upper: f64,
+    /// Number of trades the model learned from since it was reset.
+    pub observations: usize,
+}
+
+impl Forecast {
+    pub fn width(&self) -> f64 {
+        self.upper - self.lower
+    }
+    pub fn contains(&self, price: f64) -> bool {
+        (self.lower..=self.upper).contains(&price)
+    }
+}
+
+/// A forecast of the price of a product from its trades and book.
+pub trait PriceModel {
+    /// Starts a new run of observations from the reference `price`.
+    fn reset(&mut self, price: f64);
+    fn update(&mut self, observation: Observation<'_>);
+    /// Interval of the price `horizon` milliseconds ahead with
+    /// `confidence`, from 0 to 1.
+    fn predict(&self, horizon: i64, confidence: f64) -> Forecast;
+}
+
+/// Price model selected at runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ModelKind {
+    /// [`HotellingModel`].
+    #[default]
+    Hotelling,
+    /// [`EwmaModel`] with the decay `lambda`.
+    Ewma { lambda: f64 },
+}
+
+impl FromStr for ModelKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["hotelling"] => Ok(ModelKind::Hotelling),
+            ["ewma"] => Ok(ModelKind::Ewma {
+                lambda: ewma::DEFAULT_LAMBDA,
+            }),
+            ["ewma", lambda] => match lambda.parse() {
+                Ok(lambda) if (0.0..1.0).contains(&lambda) => Ok(ModelKind::Ewma { lambda }),
+                _ => Err(format!("invalid ewma decay {:?}, expected [0, 1)", lambda)),
+            },
+            _ => Err(format!(
+                "unknown model {:?}, expected hotelling or ewma[:LAMBDA]",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ModelKind {
+    /// The [`FromStr`] form of the model.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelKind::Hotelling => write!(f, "hotelling"),
+            ModelKind::Ewma { lambda } => write!(f, "ewma:{lambda}"),
+        }
+    }
+}
+
+/// Any of the price models.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyModel {
+    Hotelling(HotellingModel),
+    Ewma(EwmaModel),
+}
+
+impl AnyModel {
+    pub fn new(kind: ModelKind) -> Self {
+        match kind {
+            ModelKind::Hotelling => AnyModel::Hotelling(HotellingModel::default()),
+            ModelKind::Ewma { lambda } => AnyModel::Ewma(EwmaModel::new(lambda)),
+        }
+    }
+}
+
+impl Default for AnyModel {
+    fn default() -> Self {
+        AnyModel::Hotelling(HotellingModel::default())
+    }
+}
+
+impl PriceModel for AnyModel {
+    fn reset(&mut self, price: f64) {
+        match self {
+            AnyModel::Hotelling(model) => model.reset(price),
+            AnyModel::Ewma(model) => model.reset(price),
+        }
+    }
+    fn update(&mut self, observation: Observation<'_>) {
+        match self {
+            AnyModel::Hotelling(model) => model.update(observation),
+            AnyModel::Ewma(model) => model.update(observation),
+        }
+    }
+    fn predict(&self, horizon: i64, confidence: f64) -> Forecast {
+        match self {
+            AnyModel::Hotelling(model) => model.predict(horizon, confidence),
+            AnyModel::Ewma(model) => model.predict(horizon, confidence),
+        }
+    }
+}
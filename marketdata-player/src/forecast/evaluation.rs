@@ -0,0 +1,396 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use super::{Forecast, PriceModel};
+
+/// Confidence levels the forecasts are evaluated at by default.
+pub const DEFAULT_LEVELS: [f64; 11] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 0.95, 0.99];
+/// Upper ends of the buckets of the number of observations of the
+/// forecasts, the last bucket being open.
+const OBSERVATION_BUCKETS: [usize; 5] = [3, 5, 10, 20, 50];
+
+/// Intervals of a model at each confidence level of an [`Evaluation`],
+/// waiting for the value they forecast.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Prediction {
+    pub product: String,
+    pub observations: usize,
+    pub intervals: Vec<Forecast>,
+}
+
+impl Prediction {
+    pub fn new(product: &str, model: &impl PriceModel, horizon: i64, levels: &[f64]) -> Self {
+        let intervals: Vec<Forecast> = levels
+            .iter()
+            .map(|&level| model.predict(horizon, level))
+            .collect();
+        Self {
+            product: product.to_string(),
+            observations: intervals
+                .first()
+                .map_or(0, |interval| interval.observations),
+            intervals,
+        }
+    }
+    /// Prediction of `f` of the price, `f` being monotonic.
+    pub fn map(mut self, f: impl Fn(f64) -> f64) -> Self {
+        for interval in &mut self.intervals {
+            let (lower, upper) = (f(interval.lower), f(interval.upper));
+            interval.lower = lower.min(upper);
+            interval.upper = lower.max(upper);
+        }
+        self
+    }
+    fn is_finite(&self) -> bool {
+        self.intervals
+            .iter()
+            .all(|interval| interval.lower.is_finite() && interval.upper.is_finite())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Outcome {
+    prediction: Prediction,
+    realized: f64,
+}
+
+impl Outcome {
+    /// Bucket of the outcome by `dimension` and its index, which orders
+    /// the buckets of the number of observations.
+    fn bucket(&self, dimension: &str) -> (usize, String) {
+        match dimension {
+            "product" => (0, self.prediction.product.clone()),
+            "observations" => {
+                let observations = self.prediction.observations;
+                let mut lower = 0;
+                for (index, upper) in OBSERVATION_BUCKETS.into_iter().enumerate() {
+                    if observations < upper {
+                        return (index, format!("{lower}-{}", upper - 1));
+                    }
+                    lower = upper;
+                }
+                (OBSERVATION_BUCKETS.len(), format!("{lower}+"))
+            }
+            _ => (0, String::from("all")),
+        }
+    }
+}
+
+/// Interval forecasts of a model and the values they turned out to be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Evaluation {
+    levels: Vec<f64>,
+    outcomes: Vec<Outcome>,
+}
+
+impl Default for Evaluation {
+    fn default() -> Self {
+        Self::new(DEFAULT_LEVELS.to_vec())
+    }
+}
+
+impl Evaluation {
+    pub fn new(levels: Vec<f64>) -> Self {
+        Self {
+            levels,
+            outcomes: Vec::new(),
+        }
+    }
+    pub fn levels(&self) -> &[f64] {
+        &self.levels
+    }
+    /// Prediction of `model` for `product` at the levels of the evaluation.
+    pub fn predict(&self, product: &str, model: &impl PriceModel, horizon: i64) -> Prediction {
+        Prediction::new(product, model, horizon, &self.levels)
+    }
+    /// Scores `prediction` against the `realized` value. Predictions with
+    /// an infinite or NaN bound or not made at the levels of the evaluation
+    /// and non-positive values are left out.
+    pub fn record(&mut self, prediction: Prediction, realized: f64) {
+        if prediction.intervals.len() == self.levels.len()
+            && prediction.is_finite()
+            && realized > 0.0
+        {
+            self.outcomes.push(Outcome {
+                prediction,
+                realized,
+            });
+        }
+    }
+    pub fn report(&self, model: &str) -> EvaluationReport {
+        let mut summaries = Vec::new();
+        for dimension in ["all", "product", "observations"] {
+            let mut groups: BTreeMap<(usize, String), Vec<&Outcome>> = BTreeMap::new();
+            for outcome in &self.outcomes {
+                groups
+                    .entry(outcome.bucket(dimension))
+                    .or_default()
+                    .push(outcome);
+            }
+            for ((_, bucket), outcomes) in groups {
+                summaries.push(ForecastScores::new(
+                    dimension,
+                    bucket,
+                    &self.levels,
+                    &outcomes,
+                ));
+            }
+        }
+        EvaluationReport {
+            model: model.to_string(),
+            summaries,
+        }
+    }
+}
+
+/// Pinball loss of the `tau` quantile `quantile` of `value`.
+fn pinball(tau: f64, quantile: f64, value: f64) -> f64 {
+    match value >= quantile {
+        true => tau * (value - quantile),
+        false => (1.0 - tau) * (quantile - value),
+    }
+}
+
+/// How the intervals of one confidence level did: the share of the values
+/// inside them against the nominal level, their average width and the
+/// average pinball loss of their bounds, the `(1 - level) / 2` and
+/// `(1 + level) / 2` quantiles. Widths and losses are in basis points of
+/// the realized values.
+#[derive(Debug, Clone, Serialize)]
+pub struct Calibration {
+    pub level: f64,
+    pub coverage: f64,
+    pub width_bps: f64,
+    pub pinball_bps: f64,
+}
+
+/// Scores of a group of forecasts. `crps_bps` approximates the continuous
+/// ranked probability score by twice the pinball loss averaged over the
+/// quantiles of all the levels, lower being better.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForecastScores {
+    /// What the forecasts are grouped by: all of them, product or number
+    /// of observations of the model.
+    pub dimension: String,
+    pub bucket: String,
+    pub forecasts: usize,
+    pub calibration: Vec<Calibration>,
+    pub crps_bps: f64,
+}
+
+impl ForecastScores {
+    fn new(dimension: &str, bucket: String, levels: &[f64], outcomes: &[&Outcome]) -> Self {
+        let count = outcomes.len() as f64;
+        let bps = |value: f64, outcome: &Outcome| value / outcome.realized * 10_000.0;
+        let calibration: Vec<Calibration> = levels
+            .iter()
+            .enumerate()
+            .map(|(index, &level)| {
+                let (mut covered, mut width, mut loss) = (0.0, 0.0, 0.0);
+                for outcome in outcomes {
+                    let interval = &outcome.prediction.intervals[index];
+                    if interval.contains(outcome.realized) {
+                        covered += 1.0;
+                    }
+                    width += bps(interval.width(), outcome);
+                    let lower = pinball((1.0 - level) / 2.0, interval.lower, outcome.realized);
+                    let upper = pinball((1.0 + level) / 2.0, interval.upper, outcome.realized);
+                    loss += bps((lower + upper) / 2.0, outcome);
+                }
+                Calibration {
+                    level,
+                    coverage: covered / count,
+                    width_bps: width / count,
+                    pinball_bps: loss / count,
+                }
+            })
+            .collect();
+        let crps_bps = match calibration.len() {
+            0 => f64::NAN,
+            levels => {
+                2.0 * calibration
+                    .iter()
+                    .map(|level| level.pinball_bps)
+                    .sum::<f64>()
+                    / levels as f64
+            }
+        };
+        Self {
+            dimension: dimension.to_string(),
+            bucket,
+            forecasts: outcomes.len(),
+            calibration,
+            crps_bps,
+        }
+    }
+    /// Calibration at the level closest to `level`.
+    fn at(&self, level: f64) -> Option<&Calibration> {
+        self.calibration
+            .iter()
+            .min_by(|a, b| (a.level - level).abs().total_cmp(&(b.level - level).abs()))
+    }
+}
+
+/// Evaluation of the forecasts of a model over a replay: the
+/// [`ForecastScores`] of all the forecasts, by product and by number of
+/// observations.
+#[derive(Debug, Clone, Serialize)]
+pub struct EvaluationReport {
+    pub model: String,
+    pub summaries: Vec<ForecastScores>,
+}
+
+impl EvaluationReport {
+    /// Writes the calibration curves of the summaries, one row per level,
+    /// as `calibration.csv` and the whole report as `evaluation.json` to
+    /// directory `dir`.
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        tokio::fs::create_dir_all(dir).await?;
+        let mut calibration = String::from(
+            "model,dimension,bucket,forecasts,level,coverage,width_bps,pinball_bps,crps_bps\n",
+        );
+        for summary in &self.summaries {
+            for level in &summary.calibration {
+                calibration.push_str(&format!(
+                    "{},{},{},{},{},{:.4},{:.4},{:.4},{:.4}\n",
+                    self.model,
+                    summary.dimension,
+                    summary.bucket,
+                    summary.forecasts,
+                    level.level,
+                    level.coverage,
+                    level.width_bps,
+                    level.pinball_bps,
+                    summary.crps_bps,
+                ));
+            }
+        }
+        tokio::fs::write(dir.join("calibration.csv"), calibration).await?;
+        tokio::fs::write(
+            dir.join("evaluation.json"),
+            serde_json::to_vec_pretty(self)?,
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for EvaluationReport {
+    /// Table of the coverage of the summaries at a few levels, the width
+    /// of their 95% intervals and their scores.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN: [f64; 4] = [0.5, 0.8, 0.9, 0.95];
+        writeln!(f, "model {}", self.model)?;
+        write!(f, "{:<12} {:<12} {:>9}", "by", "bucket", "forecasts")?;
+        for level in SHOWN {
+            write!(f, " {:>8}", format!("cov {}", level * 100.0))?;
+        }
+        writeln!(f, " {:>10} {:>10} {:>10}", "width 95", "pinball 95", "crps")?;
+        for summary in &self.summaries {
+            write!(
+                f,
+                "{:<12} {:<12} {:>9}",
+                summary.dimension, summary.bucket, summary.forecasts
+            )?;
+            for level in SHOWN {
+                let coverage = summary
+                    .at(level)
+                    .map_or(String::from("-"), |at| format!("{:.3}", at.coverage));
+                write!(f, " {:>8}", coverage)?;
+            }
+            let (width, loss) = summary
+                .at(0.95)
+                .map_or((f64::NAN, f64::NAN), |at| (at.width_bps, at.pinball_bps));
+            writeln!(
+                f,
+                " {:>10.2} {:>10.4} {:>10.4}",
+                width, loss, summary.crps_bps
+            )?;
+        }
+        writeln!(f, "widths and scores in bps of the realized values")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forecast::{ewma::EwmaModel, Observation};
+
+    #[test]
+    fn scores_coverage_width_and_pinball_by_bucket() {
+        let mut model = EwmaModel::new(0.9);
+        model.reset(100.0);
+        for (delta_t, price) in [(10, 100.1), (10, 99.9), (10, 100.0)] {
+            model.update(Observation::Trade { delta_t, price });
+        }
+        let mut evaluation = Evaluation::new(vec![0.5, 0.95]);
+        let prediction = evaluation.predict("BTCUSDT", &model, 100);
+        assert_eq!(prediction.observations, 3);
+        let inner = prediction.intervals[0];
+        let outer = prediction.intervals[1];
+        evaluation.record(prediction.clone(), 100.0);
+        evaluation.record(prediction.clone(), (inner.upper + outer.upper) / 2.0);
+        evaluation.record(prediction.clone(), outer.upper + 1.0);
+        evaluation.record(prediction.clone().map(|price| price * 2.0), f64::NAN);
+        evaluation.record(
+            Prediction {
+                intervals: vec![inner],
+                ..prediction.clone()
+            },
+            100.0,
+        );
+        evaluation.record(
+            Prediction {
+                product: String::from("ETHUSDT"),
+                observations: 12,
+                ..prediction
+            },
+            100.0,
+        );
+        evaluation.record(
+            Prediction {
+                product: String::from("ETHUSDT"),
+                observations: 0,
+                intervals: vec![Forecast {
+                    lower: f64::NAN,
+                    upper: f64::NAN,
+                    observations: 0,
+                }],
+            },
+            100.0,
+        );
+
+        let report = evaluation.report("ewma:0.9");
+        let buckets: Vec<(&str, &str, usize)> = report
+            .summaries
+            .iter()
+            .map(|summary| {
+                (
+                    summary.dimension.as_str(),
+                    summary.bucket.as_str(),
+                    summary.forecasts,
+                )
+            })
+            .collect();
+        assert_eq!(
+            buckets,
+            [
+                ("all", "all", 4),
+                ("product", "BTCUSDT", 3),
+                ("product", "ETHUSDT", 1),
+                ("observations", "3-4", 3),
+                ("observations", "10-19", 1)
+            ]
+        );
+        let btc = &report.summaries[1];
+        assert_eq!(btc.calibration[0].coverage, 1.0 / 3.0);
+        assert_eq!(btc.calibration[1].coverage, 2.0 / 3.0);
+        assert!(btc.calibration[0].width_bps < btc.calibration[1].width_bps);
+        assert!(btc.calibration.iter().all(|level| level.pinball_bps > 0.0));
+        assert!(btc.crps_bps > 0.0);
+        assert_eq!(pinball(0.25, 10.0, 14.0), 1.0);
+        assert_eq!(pinball(0.25, 10.0, 6.0), 3.0);
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use statrs::distribution::{ContinuousCDF, Normal};
+
+use super::{Forecast, Observation, PriceModel};
+
+/// Decay of the RiskMetrics volatility estimate.
+pub const DEFAULT_LAMBDA: f64 = 0.94;
+
+/// Log-normal random walk from the last trade price with an exponentially
+/// weighted estimate of the variance of the log returns per millisecond.
+/// The variance is kept across resets, so the model learns from every
+/// trade of the replay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EwmaModel {
+    lambda: f64,
+    /// `None` before the first return.
+    variance: Option<f64>,
+    price: f64,
+    observations: usize,
+}
+
+impl EwmaModel {
+    pub fn new(lambda: f64) -> Self {
+        Self {
+            lambda,
+            variance: None,
+            price: f64::NAN,
+            observations: 0,
+        }
+    }
+}
+
+impl PriceModel for EwmaModel {
+    fn reset(&mut self, price: f64) {
+        self.price = price;
+        self.observations = 0;
+    }
+    fn update(&mut self, observation: Observation<'_>) {
+        let Observation::Trade { delta_t, price } = observation else {
+            return;
+        };
+        if self.price > 0.0 && price > 0.0 {
+            let sample = (price / self.price).ln().powi(2) / delta_t.max(1) as f64;
+            self.variance = Some(match self.variance {
+                Some(variance) => self.lambda * variance + (1.0 - self.lambda) * sample,
+                None => sample,
+            });
+        }
+        self.price = price;
+        self.observations += 1;
+    }
+    fn predict(&self, horizon: i64, confidence: f64) -> Forecast {
+        let variance = self.variance.unwrap_or(0.0) * horizon.max(0) as f64;
+        let z = Normal::standard().inverse_cdf((1.0 + confidence) / 2.0);
+        let deviation = z * variance.sqrt();
+        Forecast {
+            lower: self.price * (-deviation).exp(),
+            upper: self.price * deviation.exp(),
+            observations: self.observations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_widens_with_horizon_and_confidence() {
+        let mut model = EwmaModel::new(DEFAULT_LAMBDA);
+        model.reset(100.0);
+        assert_eq!(model.predict(1000, 0.95).width(), 0.0);
+        for (delta_t, price) in [(10, 100.1), (20, 99.9), (10, 100.0)] {
+            model.update(Observation::Trade { delta_t, price });
+        }
+        let short = model.predict(100, 0.95);
+        let long = model.predict(1000, 0.95);
+        assert!(short.contains(100.0) && short.width() > 0.0);
+        assert!(long.lower < short.lower && long.upper > short.upper);
+        assert!(model.predict(100, 0.5).width() < short.width());
+        assert_eq!(short.observations, 3);
+
+        model.reset(101.0);
+        let reset = model.predict(100, 0.95);
+        assert_eq!(reset.observations, 0);
+        assert!(reset.contains(101.0) && reset.width() > 0.0);
+    }
+}
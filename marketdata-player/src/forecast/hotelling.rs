@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use statrs::distribution::{ContinuousCDF, FisherSnedecor};
+
+use super::{Forecast, Observation, PriceModel};
+
+/// Joint model of the log times between trades and the sign-sqrt shifts
+/// of their prices from the reference. The price at the next trade is
+/// bounded by the Hotelling T² ellipse of the shifts, whatever the
+/// horizon. With fewer than three trades the interval is the reference.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HotellingModel {
+    last_pbest: f64,
+    time_interval: Vec<f64>,
+    price_shift: Vec<f64>,
+}
+
+impl PriceModel for HotellingModel {
+    fn reset(&mut self, price: f64) {
+        *self = Self {
+            last_pbest: price,
+            ..Default::default()
+        };
+    }
+    fn update(&mut self, observation: Observation<'_>) {
+        let Observation::Trade { delta_t, price } = observation else {
+            return;
+        };
+        self.time_interval.push((delta_t as f64).ln());
+        let price_shift =
+            (self.last_pbest - price).abs().sqrt() * (price - self.last_pbest).signum();
+        self.price_shift.push(price_shift);
+    }
+    fn predict(&self, _horizon: i64, confidence: f64) -> Forecast {
+        let (lower, upper) = self.shift_bounds(confidence);
+        Forecast {
+            lower: lower + self.last_pbest,
+            upper: upper + self.last_pbest,
+            observations: self.time_interval.len(),
+        }
+    }
+}
+
+impl HotellingModel {
+    /// Bounds of the shift of the price from the reference.
+    fn shift_bounds(&self, p: f64) -> (f64, f64) {
+        let num_of_obs = self.time_interval.len() as f64;
+        if num_of_obs < 3.0 {
+            return (0.0, 0.0);
+        }
+
+        let time_mean: f64 = self.time_interval.iter().sum::<f64>() / num_of_obs;
+        let price_mean: f64 = self.price_shift.iter().sum::<f64>() / num_of_obs;
+        let mut variance_time = 0.0;
+        let mut variance_price = 0.0;
+        let mut covariance_time_price = 0.0;
+        for i in 0..self.time_interval.len() {
+            let time_diff = self.time_interval[i] - time_mean;
+            let price_diff = self.price_shift[i] - price_mean;
+            variance_time += time_diff.powi(2);
+            variance_price += price_diff.powi(2);
+            covariance_time_price += time_diff * price_diff;
+        }
+        let covariance_coef = variance_time.sqrt()
+            / ((variance_time * variance_price - covariance_time_price.powi(2)).sqrt());
+
+        let n = num_of_obs - 2.0;
+        let f_dist = FisherSnedecor::new(2.0, n).unwrap();
+        let hotelling_stat =
+            (2.0 * (num_of_obs - 1.0) / (num_of_obs * n) * f_dist.inverse_cdf(p)).sqrt();
+        let lower = price_mean - hotelling_stat / covariance_coef;
+        let upper = price_mean + hotelling_stat / covariance_coef;
+        let lower_bound = lower.powi(2) * lower.signum();
+        let upper_bound = upper.powi(2) * upper.signum();
+        (lower_bound, upper_bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_the_shifts_after_three_trades() {
+        let mut model = HotellingModel::default();
+        model.reset(100.0);
+        for (delta_t, price) in [(10, 100.5), (30, 99.75)] {
+            model.update(Observation::Trade { delta_t, price });
+        }
+        let forecast = model.predict(0, 0.95);
+        assert_eq!((forecast.lower, forecast.upper), (100.0, 100.0));
+
+        model.update(Observation::Trade {
+            delta_t: 20,
+            price: 100.25,
+        });
+        let forecast = model.predict(0, 0.95);
+        assert_eq!(forecast.observations, 3);
+        assert!(forecast.lower < 100.0 && forecast.upper > 100.25);
+        assert!(model.predict(0, 0.5).width() < forecast.width());
+    }
+}
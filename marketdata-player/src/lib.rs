@@ -0,0 +1,67 @@
+pub mod algos;
+pub mod analytics;
+pub mod clock;
+pub mod dataprovider;
+pub mod datasource;
+pub mod forecast;
+pub mod marketdataplayer;
+pub mod orderbook;
+pub mod replaycache;
+pub mod simulator;
+pub mod strategy;
+pub mod tca;
+pub mod tickbook;
+
+use clickhouse::Row;
+use serde::{Deserialize, Serialize};
+
+#[allow(dead_code)]
+#[derive(Row, Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    local_unique_id: i64,
+    venue_timestamp: i64,
+    gate_timestamp: i64,
+    event_type: String,
+    product: String,
+    id1: Option<u64>,
+    id2: Option<u64>,
+    ask_not_bid: Option<bool>,
+    buy_not_sell: Option<bool>,
+    price: String,
+    quantity: String,
+}
+
+/// Kinds of events the collector records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    Snapshot,
+    Depth,
+    Trade,
+}
+
+impl Event {
+    pub fn product(&self) -> &str {
+        &self.product
+    }
+    /// `None` for an event type the player does not know.
+    pub fn kind(&self) -> Option<EventKind> {
+        match self.event_type.as_str() {
+            "snapshot" => Some(EventKind::Snapshot),
+            "depth" => Some(EventKind::Depth),
+            "trade" => Some(EventKind::Trade),
+            _ => None,
+        }
+    }
+    pub fn venue_timestamp(&self) -> i64 {
+        self.venue_timestamp
+    }
+    pub fn gate_timestamp(&self) -> i64 {
+        self.gate_timestamp
+    }
+    pub fn price(&self) -> &str {
+        &self.price
+    }
+    pub fn quantity(&self) -> &str {
+        &self.quantity
+    }
+}
@@ -1,43 +1,386 @@
-mod dataprovider;
-mod marketdataplayer;
-mod orderbook;
-
-use anyhow::{Ok, Result};
-use clickhouse::Row;
+use anyhow::{bail, ensure, Error, Ok, Result};
+use argh::FromArgs;
+use chrono::{NaiveDateTime, Utc};
+use clickhouse::Client;
 use fpdec::Decimal;
-use marketdataplayer::MarketdataPlayer;
-use serde::Deserialize;
-use tokio::{fs::OpenOptions, io::{AsyncBufReadExt, BufReader}};
-use std::str::FromStr;
+use marketdata_player::{
+    algos::{
+        profile::{VolumeProfile, DAY, DEFAULT_BUCKET},
+        Algo, AlgoParams, ExecutionAlgo,
+    },
+    analytics::DEFAULT_LEVELS,
+    dataprovider::DataProvider,
+    datasource::{file::FileSource, reorder::ReorderedSource, Clock, MarketDataSource},
+    forecast::ModelKind,
+    marketdataplayer::{Checkpoint, MarketdataPlayer},
+    orderbook::{BookKind, OrderSize, Side},
+    replaycache::ReplayCache,
+    simulator::{
+        fees::{FeeModel, FeeRates},
+        impact::ResilienceModel,
+        LatencyModel,
+    },
+    strategy::{hotelling::Hotelling, Strategy},
+};
+use std::{collections::HashMap, str::FromStr};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncBufReadExt, BufReader},
+};
 
-#[derive(Row, Deserialize, Debug, Clone)]
-pub struct Event {
-    local_unique_id: i64,
-    venue_timestamp: i64,
-    gate_timestamp: i64,
-    event_type: String,
-    product: String,
-    id1: Option<u64>,
-    id2: Option<u64>,
-    ask_not_bid: Option<bool>,
-    buy_not_sell: Option<bool>,
-    price: String,
+#[derive(FromArgs)]
+/// Market data player
+struct Options {
+    /// clickhouse server url
+    #[argh(option, default = "String::from(\"http://127.0.1.1:8123\")")]
+    url: String,
+    /// table with the sorted market data
+    #[argh(option, default = "String::from(\"marketDataSorted\")")]
+    table: String,
+    /// directory with capture files to replay instead of the table
+    #[argh(option)]
+    path: Option<String>,
+    /// path to the file containing symbols, one per line, optionally
+    /// followed by the tick size
+    #[argh(option, default = "String::from(\"symbols.txt\")")]
+    symbols_path: String,
+    /// replay start (UTC), e.g. "2024-11-26 05:50:00"
+    #[argh(option, default = "String::from(\"2024-11-26 05:50:00\")")]
+    start: String,
+    /// replay end (UTC), exclusive, now by default
+    #[argh(option)]
+    end: Option<String>,
+    /// number of events fetched ahead of the player from clickhouse
+    #[argh(option, default = "65_536")]
+    prefetch: usize,
+    /// directory to cache the fetched market data in
+    #[argh(option)]
+    cache_dir: Option<String>,
+    /// length of a cached window in minutes
+    #[argh(option, default = "60")]
+    cache_window: i64,
+    /// minutes after its end a window is cached
+    #[argh(option, default = "60")]
+    cache_settle: i64,
+    /// timestamp the products are merged by: gate or venue
+    #[argh(option, default = "Clock::Gate")]
+    clock: Clock,
+    /// milliseconds an event read from --path may be out of clock order by
+    #[argh(option, default = "5000")]
+    reorder_window: i64,
+    /// file to save the replay state to when the replay ends
+    #[argh(option)]
+    checkpoint: Option<String>,
+    /// also save the checkpoint every so many minutes of replayed time
+    #[argh(option)]
+    checkpoint_interval: Option<i64>,
+    /// checkpoint to continue the replay from instead of --start
+    #[argh(option)]
+    resume: Option<String>,
+    /// quantity to execute
+    #[argh(option, default = "String::from(\"1.01\")")]
     quantity: String,
+    /// amount of the quote asset to spend instead of a --quantity
+    #[argh(option)]
+    notional: Option<String>,
+    /// lot step the quantity bought for a --notional is rounded down to
+    #[argh(option)]
+    lot_step: Option<String>,
+    /// number of book levels imbalance and weighted mid are taken over
+    #[argh(option, default = "DEFAULT_LEVELS")]
+    book_levels: usize,
+    /// comma separated distances from the mid in bps of the depth curve
+    #[argh(option, default = "String::from(\"5,10,25,50,100\")")]
+    depth_bps: String,
+    /// order book implementation: map or tick
+    #[argh(option, default = "BookKind::Map")]
+    book: BookKind,
+    /// delay of the simulated orders in milliseconds: N, empirical[:WINDOW]
+    /// or uniform:MIN:MAX
+    #[argh(option, default = "LatencyModel::default()")]
+    latency: LatencyModel,
+    /// seed of the random latencies
+    #[argh(option, default = "0")]
+    latency_seed: u64,
+    /// how the liquidity taken by simulated fills comes back: instant (no
+    /// impact), permanent, exponential:HALF_LIFE or linear:REFILL in
+    /// milliseconds
+    #[argh(option, default = "ResilienceModel::default()")]
+    resilience: ResilienceModel,
+    /// fee rates of the simulated fills: vip0 to vip9 (binance spot) or
+    /// MAKER:TAKER in bps, negative for a rebate; no fees by default
+    #[argh(option)]
+    fees: Option<FeeRates>,
+    /// comma separated PRODUCT=RATES fee rates of products other than the
+    /// --fees ones
+    #[argh(option)]
+    product_fees: Option<String>,
+    /// pay the fees in BNB, a quarter off
+    #[argh(switch)]
+    bnb_discount: bool,
+    /// price model of the Hotelling experiment: hotelling or ewma[:LAMBDA]
+    #[argh(option, default = "ModelKind::default()")]
+    model: ModelKind,
+    /// directory to write the evaluation of the forecasts of --model to
+    #[argh(option)]
+    evaluation: Option<String>,
+    /// execution algorithm working a parent order of --quantity instead of
+    /// the Hotelling experiment: twap, vwap, pov:RATE or is:RISK_AVERSION
+    #[argh(option)]
+    algo: Option<Algo>,
+    /// side of the parent orders: buy or sell
+    #[argh(option, default = "Side::Buy")]
+    side: Side,
+    /// minutes a parent order is worked over
+    #[argh(option, default = "10")]
+    horizon: i64,
+    /// seconds between child orders
+    #[argh(option, default = "30")]
+    slice: i64,
+    /// worst price of the child orders
+    #[argh(option)]
+    limit_price: Option<String>,
+    /// share of each child order sent across the spread, from 0 to 1
+    #[argh(option, default = "0.5")]
+    urgency: f64,
+    /// comma separated arrival times (UTC) of the parent orders, --start by
+    /// default
+    #[argh(option)]
+    order_times: Option<String>,
+    /// number of days before --start the vwap volume profile is taken over
+    #[argh(option, default = "1")]
+    profile_days: i64,
+    /// directory to write the transaction cost analysis of the simulated
+    /// orders to
+    #[argh(option)]
+    tca: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let file = OpenOptions::new().read(true).open("symbols.txt").await?;
+    let options: Options = argh::from_env();
+    let client = Client::default()
+        .with_url(&options.url)
+        .with_user("default")
+        .with_database("default")
+        .with_compression(clickhouse::Compression::None);
+    let start_timestamp = parse_timestamp(&options.start)?;
+    let end_timestamp = match &options.end {
+        Some(end) => parse_timestamp(end)?,
+        None => Utc::now().timestamp_millis(),
+    };
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&options.symbols_path)
+        .await?;
     let mut lines = BufReader::new(file).lines();
-    while let Some(product) = lines.next_line().await? {
-        println!("{}", product);
-        let tablename = "marketDataSorted".to_string();
-        let start_timestamp = "2024-11-26 05:50:00".to_string();
-        let quantity_execution = Decimal::from_str("1.01")?;
-        println!("Buying {} in amount of {}", product, quantity_execution);
-        let mut marketdata_player =
-            MarketdataPlayer::new(product, tablename, start_timestamp, quantity_execution).await;
-        marketdata_player.play().await?;
-    } 
+    let mut products = Vec::new();
+    let mut tick_sizes = HashMap::new();
+    while let Some(line) = lines.next_line().await? {
+        let mut columns = line.split_whitespace();
+        let Some(product) = columns.next() else {
+            continue;
+        };
+        if let Some(tick_size) = columns.next() {
+            let tick_size = Decimal::from_str(tick_size)?;
+            ensure!(
+                tick_size > Decimal::ZERO,
+                "tick size of {} must be positive",
+                product
+            );
+            tick_sizes.insert(product.to_string(), tick_size);
+        }
+        products.push(product.to_string());
+    }
+    let lot_step = options
+        .lot_step
+        .as_deref()
+        .map(Decimal::from_str)
+        .transpose()?;
+    ensure!(
+        options.algo.is_none() || options.notional.is_none(),
+        "execution algorithms work a --quantity, not a --notional"
+    );
+    let order = match &options.notional {
+        Some(notional) => {
+            let notional = Decimal::from_str(notional)?;
+            println!("Buying {} for {}", products.join(", "), notional);
+            OrderSize::Notional { notional, lot_step }
+        }
+        None if options.algo.is_some() => {
+            OrderSize::Quantity(Decimal::from_str(&options.quantity)?)
+        }
+        None => {
+            let quantity_execution = Decimal::from_str(&options.quantity)?;
+            println!(
+                "Buying {} in amount of {}",
+                products.join(", "),
+                quantity_execution
+            );
+            OrderSize::Quantity(quantity_execution)
+        }
+    };
+    let source = open_source(
+        &options,
+        &client,
+        products.clone(),
+        start_timestamp,
+        end_timestamp,
+    )?;
+    let mut fees = FeeModel::new(options.fees.unwrap_or_default());
+    for product_fees in options.product_fees.iter().flat_map(|fees| fees.split(',')) {
+        let Some((product, rates)) = product_fees.split_once('=') else {
+            bail!(
+                "invalid product fees {:?}, expected PRODUCT=RATES",
+                product_fees
+            );
+        };
+        fees = fees.with_product(product.trim(), rates.trim().parse().map_err(Error::msg)?);
+    }
+    if options.bnb_discount {
+        fees = fees.with_bnb_discount();
+    }
+    let depth_bps = options
+        .depth_bps
+        .split(',')
+        .map(|bps| bps.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let strategy: Box<dyn Strategy> = match (options.algo, order) {
+        (Some(algo), OrderSize::Quantity(quantity)) => {
+            println!(
+                "Working {:?} {} of {} by {:?}",
+                options.side,
+                quantity,
+                products.join(", "),
+                algo
+            );
+            let params = AlgoParams {
+                side: options.side,
+                quantity,
+                horizon: options.horizon * 60 * 1000,
+                slice: options.slice * 1000,
+                limit_price: options
+                    .limit_price
+                    .as_deref()
+                    .map(Decimal::from_str)
+                    .transpose()?,
+                urgency: options.urgency,
+                lot_step,
+            };
+            ensure!(
+                params.horizon > 0 && params.slice > 0,
+                "horizon and slice must be positive"
+            );
+            ensure!(
+                (0.0..=1.0).contains(&params.urgency),
+                "urgency must be between 0 and 1"
+            );
+            let mut strategy = ExecutionAlgo::new(algo, params);
+            if algo == Algo::Vwap {
+                let from = start_timestamp - options.profile_days * DAY;
+                let mut history = open_source(&options, &client, products, from, start_timestamp)?;
+                strategy = strategy.with_volume_profile(
+                    VolumeProfile::from_source(
+                        history.as_mut(),
+                        from,
+                        start_timestamp,
+                        DEFAULT_BUCKET,
+                    )
+                    .await?,
+                );
+            }
+            Box::new(strategy)
+        }
+        _ => {
+            let mut strategy = Hotelling::new(order).with_model(options.model);
+            if let Some(evaluation) = &options.evaluation {
+                strategy = strategy.with_evaluation(evaluation);
+            }
+            Box::new(strategy)
+        }
+    };
+    let order_times = match &options.order_times {
+        Some(times) => times
+            .split(',')
+            .map(|time| parse_timestamp(time.trim()))
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![start_timestamp],
+    };
+    let mut marketdata_player = MarketdataPlayer::new(source, strategy)
+        .with_analytics(options.book_levels, depth_bps, Some(order))
+        .with_book(options.book, tick_sizes)
+        .with_clock(options.clock)
+        .with_latency(options.latency, options.latency_seed)
+        .with_resilience(options.resilience)
+        .with_fees(fees)
+        .with_order_times(order_times)
+        .with_end_timestamp(end_timestamp);
+    if let Some(tca) = &options.tca {
+        marketdata_player = marketdata_player.with_tca(tca);
+    }
+    if let Some(checkpoint) = &options.checkpoint {
+        marketdata_player = marketdata_player.with_checkpoint(
+            checkpoint,
+            options
+                .checkpoint_interval
+                .map(|minutes| minutes * 60 * 1000),
+        );
+    }
+    match &options.resume {
+        Some(resume) => {
+            marketdata_player
+                .restore(Checkpoint::load(resume).await?)
+                .await?
+        }
+        None => marketdata_player.seek(start_timestamp).await?,
+    }
+    marketdata_player.play().await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<i64> {
+    Ok(
+        NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f")?
+            .and_utc()
+            .timestamp_millis(),
+    )
+}
+
+/// Source of the market data of `products` between `start_timestamp` and
+/// `end_timestamp`: the capture files of --path or else the table.
+fn open_source(
+    options: &Options,
+    client: &Client,
+    products: Vec<String>,
+    start_timestamp: i64,
+    end_timestamp: i64,
+) -> Result<Box<dyn MarketDataSource>> {
+    let source: Box<dyn MarketDataSource> = match &options.path {
+        // Capture files are written in the order events arrived in, so
+        // they are sorted by the clock within a sliding window.
+        Some(path) => Box::new(ReorderedSource::new(
+            Box::new(FileSource::new(path, products)?.with_end_timestamp(end_timestamp)),
+            options.clock,
+            options.reorder_window,
+        )),
+        None => {
+            let mut provider = DataProvider::new(
+                client.clone(),
+                products,
+                options.table.clone(),
+                start_timestamp,
+                end_timestamp,
+            )
+            .with_clock(options.clock)
+            .with_prefetch(options.prefetch);
+            if let Some(cache_dir) = &options.cache_dir {
+                provider = provider.with_cache(
+                    ReplayCache::new(cache_dir, options.cache_window * 60 * 1000)
+                        .with_settle_delay(options.cache_settle * 60 * 1000),
+                );
+            }
+            Box::new(provider)
+        }
+    };
+    Ok(source)
+}
@@ -1,187 +1,880 @@
-use anyhow::{Ok, Result};
-use clickhouse::Client;
+use anyhow::{bail, Context as _, Ok, Result};
 use fpdec::Decimal;
-use statrs::distribution::{ContinuousCDF, FisherSnedecor};
-use std::str::FromStr;
-use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use crate::{dataprovider::DataProvider, orderbook::Orderbook, Event};
+use crate::{
+    analytics::{Analytics, DEFAULT_DEPTH_BPS, DEFAULT_LEVELS},
+    clock::{SimulationClock, Wakeup},
+    datasource::{Clock, EventKey, MarketDataSource},
+    orderbook::{AnyBook, Book, BookKind, OrderSize, Side},
+    simulator::{
+        fees::FeeModel, impact::ResilienceModel, limit::QueueModel, LatencyModel, OrderSimulator,
+    },
+    strategy::{Context, Strategy},
+    tca::TcaReport,
+    tickbook::DEFAULT_TICK_SIZE,
+    Event, EventKind,
+};
 
+/// Replays the events of a source through the books of its products,
+/// driving a [`Strategy`].
 pub struct MarketdataPlayer {
+    datasource: Box<dyn MarketDataSource>,
+    strategy: Box<dyn Strategy>,
+    /// Analytics every product starts with.
+    analytics: Analytics,
+    book: BookKind,
+    tick_sizes: HashMap<String, Decimal>,
+    products: BTreeMap<String, ProductState>,
+    simulator: OrderSimulator,
+    clock: SimulationClock,
+    /// Clock of the events the simulation time follows.
+    event_clock: Clock,
+    /// Arrival times of the parent orders.
+    order_times: Vec<i64>,
+    /// Events before this gate timestamp only rebuild the books and models.
+    start_timestamp: i64,
+    /// The wakeups before it fire after the last event.
+    end_timestamp: Option<i64>,
+    cursor: Cursor,
+    /// Position of a restored checkpoint, the events up to it are skipped.
+    resume: Option<Cursor>,
+    checkpoint_path: Option<PathBuf>,
+    checkpoint_interval: Option<i64>,
+    /// Directory the transaction cost analysis is written to.
+    tca_path: Option<PathBuf>,
+}
+
+/// Book of a single product of the replayed timeline.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ProductState {
     last_update_id: Option<u64>,
-    dataprovider: DataProvider,
-    orderbook: Orderbook,
-    quantity_execution: Decimal,
-    model: Model, // create a superstructure simulator
+    pub(crate) orderbook: AnyBook,
+    pub(crate) analytics: Analytics,
+}
+
+/// Gate time a replay by another clock than the gate is resumed that far
+/// before its cursor, for the events that were stamped by the collector
+/// before they happened by the exchange clock.
+const RESUME_MARGIN: i64 = 60_000;
+
+/// Position in the replay: the [`Clock::key`] of the last handled event,
+/// `None` before the first one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cursor {
+    pub clock: Clock,
+    pub key: Option<EventKey>,
+}
+
+impl Cursor {
+    fn advance(&mut self, clock: Clock, event: &Event) {
+        self.clock = clock;
+        self.key = Some(clock.owned_key(event));
+    }
+    /// Timestamp of the last handled event on the clock.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.key.as_ref().map(|(timestamp, ..)| *timestamp)
+    }
+    /// Gate timestamp the source is positioned at to resume after the
+    /// cursor.
+    fn seek_timestamp(&self) -> i64 {
+        match (self.clock, self.timestamp()) {
+            (_, None) => 0,
+            (Clock::Gate, Some(timestamp)) => timestamp,
+            (Clock::Venue, Some(timestamp)) => timestamp - RESUME_MARGIN,
+        }
+    }
+    /// Whether `event` was handled before the cursor was taken.
+    fn passed(&self, event: &Event) -> bool {
+        self.key
+            .as_ref()
+            .is_some_and(|(clock, other, product, event_type, id, local_id)| {
+                self.clock.key(event)
+                    <= (
+                        *clock,
+                        *other,
+                        product.as_str(),
+                        event_type.as_str(),
+                        *id,
+                        *local_id,
+                    )
+            })
+    }
+}
+
+/// Replay state saved by [`MarketdataPlayer::checkpoint`]: the books, the
+/// strategy, the clock and the position in the source.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    cursor: Cursor,
+    products: BTreeMap<String, ProductState>,
+    simulator: OrderSimulator,
+    clock: SimulationClock,
+    strategy: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+    /// Writes the checkpoint, replacing `path` atomically. The format is
+    /// bincode rather than JSON, which has no infinities and NaNs.
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let partial = path.with_extension("partial");
+        tokio::fs::write(&partial, bincode::serialize(self)?).await?;
+        tokio::fs::rename(&partial, path).await?;
+        Ok(())
+    }
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read checkpoint {:?}", path))?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
 }
 
 impl MarketdataPlayer {
-    pub async fn new(
-        product: String,
-        tablename: String,
-        start_timestamp: String,
-        quantity_execution: Decimal,
+    pub fn new(datasource: Box<dyn MarketDataSource>, strategy: Box<dyn Strategy>) -> Self {
+        let products = datasource
+            .products()
+            .into_iter()
+            .map(|product| (product, ProductState::default()))
+            .collect();
+        let mut player = Self {
+            datasource,
+            strategy,
+            analytics: Analytics::new(DEFAULT_LEVELS, DEFAULT_DEPTH_BPS.to_vec(), None),
+            book: BookKind::Map,
+            tick_sizes: HashMap::new(),
+            products,
+            simulator: OrderSimulator::default(),
+            clock: SimulationClock::default(),
+            event_clock: Clock::Gate,
+            order_times: Vec::new(),
+            start_timestamp: i64::MIN,
+            end_timestamp: None,
+            cursor: Cursor::default(),
+            resume: None,
+            checkpoint_path: None,
+            checkpoint_interval: None,
+            tca_path: None,
+        };
+        player.reset_states();
+        player
+    }
+    /// Saves a checkpoint to `path` when the replay ends and, with an
+    /// `interval`, every `interval` milliseconds of replayed time.
+    pub fn with_checkpoint(mut self, path: impl Into<PathBuf>, interval: Option<i64>) -> Self {
+        self.checkpoint_path = Some(path.into());
+        self.checkpoint_interval = interval.filter(|&interval| interval > 0);
+        self
+    }
+    /// Writes the transaction cost analysis of the simulated orders to
+    /// directory `path` and prints its summary when the replay ends.
+    pub fn with_tca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tca_path = Some(path.into());
+        self
+    }
+    /// Takes book imbalance and weighted mid over `levels` levels, the depth
+    /// curve at `depth_bps` from the mid and VWAPs for an order of `order`
+    /// size.
+    pub fn with_analytics(
+        mut self,
+        levels: usize,
+        depth_bps: Vec<f64>,
+        order: Option<OrderSize>,
     ) -> Self {
-        let client = Client::default()
-            .with_url("http://127.0.1.1:8123")
-            .with_user("default")
-            .with_database("default")
-            .with_compression(clickhouse::Compression::None);
-        Self {
-            last_update_id: None,
-            dataprovider: DataProvider::new(client, product, tablename, &start_timestamp),
-            orderbook: Orderbook::default(),
-            quantity_execution: quantity_execution,
-            model: Model::reinit(0.0),
-        }
+        self.analytics = Analytics::new(levels, depth_bps, order);
+        self.reset_states();
+        self
     }
-    pub async fn play(&mut self) -> Result<()> {
-        while let Some(event) = self.dataprovider.next().await {
-            if event.event_type == "snapshot" {
-                self.last_update_id = event.id1;
-                self.orderbook.update(event)?;
-                break;
+    /// Keeps the books of `kind`. The tick book takes the tick size of each
+    /// product from `tick_sizes`, [`DEFAULT_TICK_SIZE`] if it is missing.
+    pub fn with_book(mut self, kind: BookKind, tick_sizes: HashMap<String, Decimal>) -> Self {
+        self.book = kind;
+        self.tick_sizes = tick_sizes;
+        self.reset_states();
+        self
+    }
+    /// Delays the market orders of the strategy by `latency`, drawing random
+    /// latencies from `seed`.
+    pub fn with_latency(mut self, latency: LatencyModel, seed: u64) -> Self {
+        self.simulator = std::mem::take(&mut self.simulator).with_latency(latency, seed);
+        self
+    }
+    /// Estimates the queue positions of the limit orders of the strategy
+    /// by `model`.
+    pub fn with_queue_model(mut self, model: QueueModel) -> Self {
+        self.simulator = std::mem::take(&mut self.simulator).with_queue_model(model);
+        self
+    }
+    /// Charges the simulated fills the fees of `fees`.
+    pub fn with_fees(mut self, fees: FeeModel) -> Self {
+        self.simulator = std::mem::take(&mut self.simulator).with_fees(fees);
+        self
+    }
+    /// Keeps the liquidity the simulated fills take out of the books until
+    /// `model` brings it back.
+    pub fn with_resilience(mut self, model: ResilienceModel) -> Self {
+        self.simulator = std::mem::take(&mut self.simulator).with_resilience(model);
+        self
+    }
+    /// Takes the simulation time from `clock` instead of the gate time.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.event_clock = clock;
+        self
+    }
+    /// Calls [`Strategy::on_order`] at each of `order_times`, simulation
+    /// times of parent order arrivals. The ones before the start of the
+    /// replay are dropped.
+    pub fn with_order_times(mut self, order_times: Vec<i64>) -> Self {
+        self.order_times = order_times;
+        self.reset_clock(self.clock.now());
+        self
+    }
+    /// Keeps the clock going until `timestamp` after the last event, so that
+    /// the wakeups before it fire even if no events are left.
+    pub fn with_end_timestamp(mut self, timestamp: i64) -> Self {
+        self.end_timestamp = Some(timestamp);
+        self
+    }
+    fn reset_clock(&mut self, now: i64) {
+        self.clock = SimulationClock::new(now);
+        for (order, &timestamp) in self.order_times.iter().enumerate() {
+            if timestamp >= self.start_timestamp {
+                self.clock.order_at(timestamp, order);
             }
         }
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(format!("output/{}.txt", self.dataprovider.product()))
+    }
+    fn reset_states(&mut self) {
+        let products: Vec<String> = self.products.keys().cloned().collect();
+        for product in products {
+            let tick_size = self
+                .tick_sizes
+                .get(&product)
+                .copied()
+                .unwrap_or(DEFAULT_TICK_SIZE);
+            let state = ProductState {
+                orderbook: AnyBook::new(self.book, tick_size),
+                analytics: self.analytics.clone(),
+                ..Default::default()
+            };
+            self.products.insert(product, state);
+        }
+    }
+    /// Starts the replay at `timestamp`. The source is positioned at the
+    /// last snapshot before it and the events up to `timestamp` are
+    /// fast-forwarded through the books without producing output.
+    pub async fn seek(&mut self, timestamp: i64) -> Result<()> {
+        let from = self
+            .datasource
+            .last_snapshot(timestamp)
+            .await?
+            .map_or(timestamp, |snapshot| snapshot.min(timestamp));
+        self.datasource.seek(from).await?;
+        self.reset_states();
+        self.simulator.restore(OrderSimulator::default());
+        self.start_timestamp = timestamp;
+        self.reset_clock(from);
+        self.cursor = Cursor::default();
+        self.resume = None;
+        Ok(())
+    }
+    /// Transaction cost analysis of the simulated orders so far.
+    pub fn tca(&self) -> TcaReport {
+        self.simulator.tca()
+    }
+    /// Current state of the replay.
+    pub fn checkpoint(&self) -> Result<Checkpoint> {
+        Ok(Checkpoint {
+            cursor: self.cursor.clone(),
+            products: self.products.clone(),
+            simulator: self.simulator.clone(),
+            clock: self.clock.clone(),
+            strategy: self.strategy.checkpoint()?,
+        })
+    }
+    /// Continues the replay from `checkpoint`, which must have been taken
+    /// with the same clock. The events up to the checkpoint cursor are
+    /// skipped by their clock keys.
+    pub async fn restore(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        if checkpoint.cursor.key.is_some() && checkpoint.cursor.clock != self.event_clock {
+            bail!(
+                "checkpoint was taken with the {:?} clock, not {:?}",
+                checkpoint.cursor.clock,
+                self.event_clock
+            );
+        }
+        self.datasource
+            .seek(checkpoint.cursor.seek_timestamp())
             .await?;
-        file.write_all(b"Best player price | Best model price lower | Best model price upper | Real price | Delta execution | Num of obs\n").await?;
-        let mut last_event: Option<Event> = None;
-        let mut best_player_total_price = Decimal::ZERO;
-        let mut best_model_price_lower = 0.0;
-        let mut best_model_price_upper = 0.0;
-        let mut real_total_price = Decimal::ZERO;
-        let mut delta_execution = 0;
-        let mut num_of_obs = 0.0;
-        let mut prev_pbest = Decimal::ZERO;
-        let quantity_execution = f64::from_str(&self.quantity_execution.to_string()).unwrap();
-        while let Some(event) = self.dataprovider.next().await {
-            let cur_event = event.clone();
-            match event.event_type.as_str() {
-                "snapshot" => {
-                    if self.last_update_id == event.id1 {
-                        self.orderbook.update(event)?;
-                    }
-                    if let Some(last_event) = last_event {
-                        if last_event.event_type == "depth" {
-                            self.model = Model::reinit(self.orderbook.pbest());
-                        }
-                    }
+        for (product, state) in checkpoint.products {
+            if let Some(current) = self.products.get_mut(&product) {
+                *current = state;
+            }
+        }
+        self.simulator.restore(checkpoint.simulator);
+        self.clock = checkpoint.clock;
+        self.strategy.restore(&checkpoint.strategy)?;
+        self.start_timestamp = checkpoint.cursor.timestamp().unwrap_or_default();
+        self.resume = Some(checkpoint.cursor.clone());
+        self.cursor = checkpoint.cursor;
+        Ok(())
+    }
+    pub async fn play(&mut self) -> Result<()> {
+        let mut ctx = Context::new(
+            &mut self.clock,
+            true,
+            &self.products,
+            self.analytics.depth_bps(),
+            &mut self.simulator,
+        );
+        self.strategy.on_start(&mut ctx).await?;
+        fill_orders(self.strategy.as_mut(), &mut ctx).await?;
+        let mut next_checkpoint = None;
+        while let Some(event) = self.datasource.next().await? {
+            if let Some(resume) = &self.resume {
+                if resume.passed(&event) {
+                    continue;
                 }
-                "depth" => {
-                    if let Some(last_event) = last_event {
-                        if last_event.event_type == "trade" {
-                            best_player_total_price = self
-                                .orderbook
-                                .best_total_price(self.quantity_execution.clone())
-                                .unwrap();
-                            let best_price = self.model.get_best_price(0.95);
-                            best_model_price_lower =
-                                (best_price.0 + self.model.last_pbest) * quantity_execution;
-                            best_model_price_upper =
-                                (best_price.1 + self.model.last_pbest) * quantity_execution;
-                            num_of_obs = best_price.2;
-                            delta_execution = event.venue_timestamp - last_event.venue_timestamp;
-                        } else if last_event.event_type == "snapshot" {
-                            self.model = Model::reinit(self.orderbook.pbest());
-                        }
-                    }
-                    self.orderbook.update(event)?;
+                self.resume = None;
+            }
+            // Checkpoints are taken between events, before `event` is handled.
+            if let (Some(path), Some(interval)) = (&self.checkpoint_path, self.checkpoint_interval)
+            {
+                let due = *next_checkpoint.get_or_insert(event.gate_timestamp + interval);
+                if event.gate_timestamp >= due {
+                    self.checkpoint()?.save(path).await?;
+                    next_checkpoint = Some(event.gate_timestamp + interval);
                 }
-                "trade" => {
-                    if let Some(last_event) = last_event {
-                        if last_event.event_type == "depth" {
-                            real_total_price = self
-                                .orderbook
-                                .best_total_price(self.quantity_execution.clone())
-                                .unwrap();
-                            if !best_model_price_upper.is_nan() && prev_pbest != real_total_price {
-                                let res = format!(
-                                    "{} {} {} {} {} {}\n",
-                                    best_player_total_price,
-                                    best_model_price_lower,
-                                    best_model_price_upper,
-                                    real_total_price,
-                                    delta_execution,
-                                    num_of_obs,
-                                );
-                                file.write_all(res.as_bytes()).await?;
-                                prev_pbest = real_total_price;
-                            }
-                            self.model = Model::reinit(self.orderbook.pbest());
-                        } else if last_event.event_type == "snapshot" {
-                            self.model = Model::reinit(self.orderbook.pbest());
-                        }
-                        let delta_t = event.venue_timestamp - last_event.venue_timestamp;
-                        self.model.update(delta_t, event.price.clone())?;
-                    }
-                    self.orderbook.handle_trade(event)?;
+            }
+            self.cursor.advance(self.event_clock, &event);
+            let snapshot = event.kind() == Some(EventKind::Snapshot);
+            self.refresh_analytics(snapshot.then_some(event.product.as_str()));
+            self.advance_clock(self.event_clock.timestamp(&event))
+                .await?;
+            self.simulator.observe(&event);
+            self.handle(event).await?;
+        }
+        self.refresh_analytics(None);
+        if let Some(end_timestamp) = self.end_timestamp {
+            // The wakeups at the end of the replay are still part of it.
+            self.wake_before(end_timestamp.saturating_add(1)).await?;
+            self.clock.advance(end_timestamp);
+        }
+        if let Some(path) = &self.checkpoint_path {
+            self.checkpoint()?.save(path).await?;
+        }
+        let mut ctx = Context::new(
+            &mut self.clock,
+            true,
+            &self.products,
+            self.analytics.depth_bps(),
+            &mut self.simulator,
+        );
+        self.strategy.on_end(&mut ctx).await?;
+        fill_orders(self.strategy.as_mut(), &mut ctx).await?;
+        if let Some(path) = &self.tca_path {
+            let report = self.tca();
+            report.write(path).await?;
+            print!("{report}");
+        }
+        Ok(())
+    }
+    /// Brings the books and the analytics of the products whose snapshot is
+    /// complete up to date, all but `snapshot`, the product a snapshot is
+    /// being applied to.
+    fn refresh_analytics(&mut self, snapshot: Option<&str>) {
+        for (product, state) in self.products.iter_mut() {
+            if Some(product.as_str()) != snapshot {
+                state.orderbook.finish_snapshot();
+                state.analytics.refresh(&state.orderbook);
+            }
+        }
+    }
+    /// Moves the simulation time to `timestamp`, first handling the wakeups
+    /// before it. This is where the handling of every event starts.
+    async fn advance_clock(&mut self, timestamp: i64) -> Result<()> {
+        self.wake_before(timestamp).await?;
+        self.clock.advance(timestamp);
+        Ok(())
+    }
+    /// Handles the wakeups before `timestamp` in their order.
+    async fn wake_before(&mut self, timestamp: i64) -> Result<()> {
+        while let Some(wakeup) = self.clock.next_before(timestamp) {
+            let live = self.clock.now() >= self.start_timestamp;
+            let mut ctx = Context::new(
+                &mut self.clock,
+                live,
+                &self.products,
+                self.analytics.depth_bps(),
+                &mut self.simulator,
+            );
+            match wakeup {
+                Wakeup::Timer(timer) => self.strategy.on_timer(&mut ctx, timer).await?,
+                Wakeup::Order(order) => self.strategy.on_order(&mut ctx, order).await?,
+                Wakeup::Arrival(order) => ctx.arrive(order),
+            }
+            fill_orders(self.strategy.as_mut(), &mut ctx).await?;
+        }
+        Ok(())
+    }
+    /// Passes `event` to the strategy, then applies it to the book of its
+    /// product. Events before the first snapshot of a product are dropped,
+    /// as are the depth updates the snapshot already contains.
+    async fn handle(&mut self, event: Event) -> Result<()> {
+        let Some(state) = self.products.get_mut(&event.product) else {
+            return Ok(());
+        };
+        if state.last_update_id.is_none() {
+            if event.kind() == Some(EventKind::Snapshot) {
+                state.last_update_id = event.id1;
+                state.orderbook.update(event)?;
+            }
+            return Ok(());
+        }
+        let Some(kind) = event.kind() else {
+            return Ok(());
+        };
+        if kind == EventKind::Depth && event.id2.is_some_and(|id| Some(id) <= state.last_update_id)
+        {
+            return Ok(());
+        }
+        let mut ctx = Context::new(
+            &mut self.clock,
+            event.gate_timestamp >= self.start_timestamp,
+            &self.products,
+            self.analytics.depth_bps(),
+            &mut self.simulator,
+        );
+        let strategy = self.strategy.as_mut();
+        match kind {
+            EventKind::Snapshot => strategy.on_snapshot(&mut ctx, &event).await?,
+            EventKind::Depth => strategy.on_depth(&mut ctx, &event).await?,
+            EventKind::Trade => strategy.on_trade(&mut ctx, &event).await?,
+        }
+        fill_orders(strategy, &mut ctx).await?;
+        let live = ctx.live();
+        if let Some(state) = self.products.get_mut(&event.product) {
+            self.simulator
+                .on_event(kind, &event, &state.orderbook, self.clock.now())?;
+            state.apply(kind, event)?;
+        }
+        let mut ctx = Context::new(
+            &mut self.clock,
+            live,
+            &self.products,
+            self.analytics.depth_bps(),
+            &mut self.simulator,
+        );
+        fill_orders(self.strategy.as_mut(), &mut ctx).await?;
+        Ok(())
+    }
+}
+
+impl ProductState {
+    /// Applies `event` of `kind` to the book, keeping the analytics up to
+    /// date. Snapshots other than the first one are ignored.
+    fn apply(&mut self, kind: EventKind, event: Event) -> Result<()> {
+        let timestamp = event.venue_timestamp;
+        match kind {
+            // The analytics wait for the rest of the levels of the snapshot.
+            EventKind::Snapshot => {
+                if self.last_update_id == event.id1 {
+                    self.orderbook.update(event)?;
+                    self.analytics.defer(timestamp);
                 }
-                _ => {}
             }
-            last_event = Some(cur_event);
+            EventKind::Depth => {
+                let side = match event.ask_not_bid {
+                    Some(true) => Side::Buy,
+                    _ => Side::Sell,
+                };
+                let before = self.orderbook.update(event)?;
+                self.analytics.on_level(
+                    &self.orderbook,
+                    side,
+                    before.price,
+                    before.quantity,
+                    timestamp,
+                );
+            }
+            EventKind::Trade => {
+                self.analytics.on_trade(&self.orderbook, timestamp);
+                self.orderbook.handle_trade(event)?;
+                self.analytics.on_update(&self.orderbook, timestamp);
+            }
         }
         Ok(())
     }
 }
 
-pub struct Model {
-    last_pbest: f64,
-    time_interval: Vec<f64>,
-    price_shift: Vec<f64>,
+/// Executes the requests that have reached the books and passes the fills
+/// and the order updates to the strategy. Market orders a book cannot fill
+/// are rejected.
+async fn fill_orders(strategy: &mut dyn Strategy, ctx: &mut Context<'_>) -> Result<()> {
+    loop {
+        if let Some(update) = ctx.next_update() {
+            strategy.on_order_update(ctx, &update).await?;
+            continue;
+        }
+        let Some(request) = ctx.next_arrived() else {
+            return Ok(());
+        };
+        if let Some(fill) = ctx.execute(request) {
+            strategy.on_fill(ctx, &fill).await?;
+        }
+    }
 }
 
-impl Model {
-    pub fn reinit(pbest: f64) -> Self {
-        Self {
-            last_pbest: pbest,
-            time_interval: Vec::new(),
-            price_shift: Vec::new(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::TimerId,
+        datasource::memory::MemorySource,
+        orderbook::Side,
+        simulator::{
+            limit::{OrderEvent, OrderUpdate},
+            Fill, OrderId,
+        },
+    };
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    fn event(
+        event_type: &str,
+        gate_timestamp: i64,
+        ask_not_bid: Option<bool>,
+        price: &str,
+        quantity: &str,
+    ) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid,
+            buy_not_sell: ask_not_bid.is_none().then_some(false),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
         }
     }
-    pub fn update(&mut self, delta_t: i64, price: String) -> Result<()> {
-        self.time_interval.push((delta_t as f64).ln());
-        let price = f64::from_str(&price)?;
-        let price_shift =
-            (self.last_pbest - price).abs().sqrt() * (price - self.last_pbest).signum();
-        self.price_shift.push(price_shift);
-        Ok(())
+
+    /// Logs the time and the best ask every handler sees.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn log(&self, ctx: &Context<'_>, handler: &str) {
+            let ask = ctx.book("TEST").and_then(|book| book.best_ask());
+            let ask = ask.map_or(String::from("-"), |ask| {
+                format!("{}@{}", ask.quantity, ask.price)
+            });
+            let timestamp = ctx.timestamp();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{timestamp} {handler} {ask}"));
+        }
+    }
+
+    #[async_trait]
+    impl Strategy for Recorder {
+        async fn on_start(&mut self, ctx: &mut Context<'_>) -> Result<()> {
+            ctx.clock().at(2);
+            self.log(ctx, "start");
+            Ok(())
+        }
+        async fn on_snapshot(&mut self, ctx: &mut Context<'_>, _: &Event) -> Result<()> {
+            self.log(ctx, "snapshot");
+            Ok(())
+        }
+        async fn on_depth(&mut self, ctx: &mut Context<'_>, _: &Event) -> Result<()> {
+            self.log(ctx, "depth");
+            ctx.market_order("TEST", Side::Buy, OrderSize::Quantity(Decimal::ONE));
+            Ok(())
+        }
+        async fn on_trade(&mut self, ctx: &mut Context<'_>, _: &Event) -> Result<()> {
+            self.log(ctx, "trade");
+            Ok(())
+        }
+        async fn on_timer(&mut self, ctx: &mut Context<'_>, timer: TimerId) -> Result<()> {
+            self.log(ctx, &format!("timer {timer}"));
+            Ok(())
+        }
+        async fn on_order(&mut self, ctx: &mut Context<'_>, order: usize) -> Result<()> {
+            self.log(ctx, &format!("order {order}"));
+            Ok(())
+        }
+        async fn on_fill(&mut self, ctx: &mut Context<'_>, fill: &Fill) -> Result<()> {
+            let slippage = fill.slippage().unwrap();
+            let fill = format!(
+                "fill {} {} {}",
+                fill.execution.notional,
+                fill.latency(),
+                slippage
+            );
+            self.log(ctx, &fill);
+            Ok(())
+        }
+        async fn on_end(&mut self, ctx: &mut Context<'_>) -> Result<()> {
+            self.log(ctx, "end");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn wakeups_fire_between_events() {
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("snapshot", 1, Some(false), "99", "1"),
+            event("depth", 2, Some(true), "100", "2"),
+            event("trade", 3, None, "100", "0.5"),
+        ]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player = MarketdataPlayer::new(Box::new(source), Box::new(Recorder(log.clone())))
+            .with_order_times(vec![-1, 3, 5, 10, 20])
+            .with_end_timestamp(10);
+        player.seek(0).await.unwrap();
+        player.play().await.unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "0 start -",
+                "1 snapshot 1@101",
+                "2 depth 1@101",
+                "2 fill 101 0 1 1@101",
+                "2 timer 0 2@100",
+                "3 trade 2@100",
+                "3 order 1 1.5@100",
+                "5 order 2 1.5@100",
+                "10 order 3 1.5@100",
+                "10 end 1.5@100",
+            ]
+        );
     }
-    pub fn get_best_price(&self, p: f64) -> (f64, f64, f64) {
-        let num_of_obs = self.time_interval.len() as f64;
-        if num_of_obs < 3.0 {
-            return (0.0, 0.0, num_of_obs);
-        }
-
-        let time_mean: f64 = self.time_interval.iter().sum::<f64>() / num_of_obs;
-        let price_mean: f64 = self.price_shift.iter().sum::<f64>() / num_of_obs;
-        let mut variance_time = 0.0;
-        let mut variance_price = 0.0;
-        let mut covariance_time_price = 0.0;
-        for i in 0..self.time_interval.len() {
-            let time_diff = self.time_interval[i] - time_mean;
-            let price_diff = self.price_shift[i] - price_mean;
-            variance_time += time_diff.powi(2);
-            variance_price += price_diff.powi(2);
-            covariance_time_price += time_diff * price_diff;
-        }
-        let covariance_coef = variance_time.sqrt()
-            / ((variance_time * variance_price - covariance_time_price.powi(2)).sqrt());
-
-        let n = num_of_obs - 2.0;
-        if n <= 0.0 {
-            return (0.0, 0.0, num_of_obs);
-        }
-        let f_dist = FisherSnedecor::new(2.0, n).unwrap();
-        let hotelling_stat =
-            (2.0 * (num_of_obs - 1.0) / (num_of_obs * n) * f_dist.inverse_cdf(p)).sqrt();
-        let lower = price_mean - hotelling_stat / covariance_coef;
-        let upper = price_mean + hotelling_stat / covariance_coef;
-        let lower_bound = lower.powi(2) * lower.signum();
-        let upper_bound = upper.powi(2) * upper.signum();
-        (lower_bound, upper_bound, num_of_obs)
-    }
-}
\ No newline at end of file
+
+    /// Logs the events the strategy sees.
+    struct Seen(Arc<Mutex<Vec<String>>>);
+
+    impl Seen {
+        fn log(&self, handler: &str, event: &Event) {
+            let (venue, gate) = (event.venue_timestamp, event.gate_timestamp);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{handler} {venue}/{gate}"));
+        }
+    }
+
+    #[async_trait]
+    impl Strategy for Seen {
+        async fn on_snapshot(&mut self, _: &mut Context<'_>, event: &Event) -> Result<()> {
+            self.log("snapshot", event);
+            Ok(())
+        }
+        async fn on_depth(&mut self, _: &mut Context<'_>, event: &Event) -> Result<()> {
+            self.log("depth", event);
+            Ok(())
+        }
+        async fn on_trade(&mut self, _: &mut Context<'_>, event: &Event) -> Result<()> {
+            self.log("trade", event);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_last_event_by_the_clock() {
+        // In venue order, but the collector received the second update
+        // before the first one.
+        let mut events = vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("snapshot", 1, Some(false), "99", "1"),
+            event("depth", 12, Some(true), "100", "1"),
+            event("depth", 11, Some(true), "102", "1"),
+            event("trade", 12, None, "100", "0.5"),
+        ];
+        for (event, venue_timestamp) in events.iter_mut().zip([1, 1, 2, 3, 4]) {
+            event.venue_timestamp = venue_timestamp;
+        }
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player = MarketdataPlayer::new(
+            Box::new(MemorySource::new(events[..3].to_vec())),
+            Box::new(Seen(log.clone())),
+        )
+        .with_clock(Clock::Venue);
+        player.seek(0).await.unwrap();
+        player.play().await.unwrap();
+        let checkpoint = player.checkpoint().unwrap();
+        assert_eq!(checkpoint.cursor().timestamp(), Some(2));
+
+        let mut gate = MarketdataPlayer::new(
+            Box::new(MemorySource::new(events.clone())),
+            Box::new(Seen(log.clone())),
+        );
+        assert!(gate.restore(checkpoint.clone()).await.is_err());
+
+        log.lock().unwrap().clear();
+        let mut player = MarketdataPlayer::new(
+            Box::new(MemorySource::new(events)),
+            Box::new(Seen(log.clone())),
+        )
+        .with_clock(Clock::Venue);
+        player.restore(checkpoint).await.unwrap();
+        player.play().await.unwrap();
+        assert_eq!(*log.lock().unwrap(), ["depth 3/11", "trade 4/12"]);
+        let ask = player.products["TEST"].orderbook.best_ask().unwrap();
+        assert_eq!(ask.price, Decimal::from(100));
+    }
+
+    #[tokio::test]
+    async fn drops_depth_updates_the_snapshot_contains() {
+        let mut events = vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("depth", 2, Some(true), "100", "1"),
+            event("depth", 3, Some(true), "102", "1"),
+            event("depth", 4, Some(true), "101", "0"),
+        ];
+        events[0].id1 = Some(5);
+        for (event, id) in events[1..].iter_mut().zip([4, 5, 6]) {
+            event.id2 = Some(id);
+        }
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player = MarketdataPlayer::new(
+            Box::new(MemorySource::new(events)),
+            Box::new(Seen(log.clone())),
+        );
+        player.play().await.unwrap();
+        assert_eq!(*log.lock().unwrap(), ["depth 4/4"]);
+        let asks: Vec<Decimal> = player.products["TEST"]
+            .orderbook
+            .levels(Side::Buy)
+            .map(|level| level.price)
+            .collect();
+        assert!(asks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn orders_fill_when_they_reach_the_book() {
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("snapshot", 1, Some(false), "99", "1"),
+            event("depth", 2, Some(true), "100", "2"),
+            event("trade", 3, None, "100", "0.5"),
+            event("depth", 4, Some(true), "100", "0.5"),
+        ]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player = MarketdataPlayer::new(Box::new(source), Box::new(Recorder(log.clone())))
+            .with_latency(LatencyModel::Constant(1), 0);
+        player.seek(0).await.unwrap();
+        player.play().await.unwrap();
+        let fills: Vec<String> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains("fill"))
+            .cloned()
+            .collect();
+        // The order of the first depth update reaches the book after the
+        // trade, the one of the second update never does.
+        assert_eq!(fills, ["3 fill 100 1 0 1.5@100"]);
+    }
+
+    /// Joins the bid with a limit order on the first depth update and
+    /// cancels what is left of it after the first fill.
+    struct Maker(Arc<Mutex<Vec<String>>>, Option<OrderId>);
+
+    #[async_trait]
+    impl Strategy for Maker {
+        async fn on_depth(&mut self, ctx: &mut Context<'_>, _: &Event) -> Result<()> {
+            if self.1.is_none() {
+                let price = Decimal::from(99);
+                self.1 = Some(ctx.limit_order("TEST", Side::Buy, price, Decimal::ONE));
+            }
+            Ok(())
+        }
+        async fn on_order_update(
+            &mut self,
+            ctx: &mut Context<'_>,
+            update: &OrderUpdate,
+        ) -> Result<()> {
+            let event = match &update.event {
+                OrderEvent::Accepted { queue_ahead } => format!("accepted {queue_ahead}"),
+                OrderEvent::Filled {
+                    price, quantity, ..
+                } => {
+                    ctx.cancel(update.order);
+                    format!("filled {quantity}@{price}")
+                }
+                OrderEvent::Cancelled { remaining } => format!("cancelled {remaining}"),
+                event => format!("{event:?}"),
+            };
+            let timestamp = ctx.timestamp();
+            self.0.lock().unwrap().push(format!("{timestamp} {event}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_orders_fill_behind_their_queue() {
+        let sell = |timestamp, quantity| {
+            let mut trade = event("trade", timestamp, None, "99", quantity);
+            trade.buy_not_sell = Some(true);
+            trade
+        };
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("snapshot", 1, Some(false), "99", "1"),
+            event("depth", 2, Some(false), "99", "3"),
+            sell(4, "2"),
+            event("depth", 5, Some(false), "99", "0.5"),
+            sell(6, "1"),
+        ]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player =
+            MarketdataPlayer::new(Box::new(source), Box::new(Maker(log.clone(), None)))
+                .with_latency(LatencyModel::Constant(1), 0)
+                .with_end_timestamp(10);
+        player.seek(0).await.unwrap();
+        player.play().await.unwrap();
+        // The order arrives behind the 3 of the update, the trade takes 2 of
+        // them and the cancellations the rest but the 0.5 left in the level.
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["3 accepted 3", "6 filled 0.5@99", "7 cancelled 0.5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_deplete_the_book_for_later_orders() {
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "1"),
+            event("snapshot", 1, Some(true), "102", "5"),
+            event("snapshot", 1, Some(false), "99", "1"),
+            event("depth", 2, Some(true), "103", "1"),
+            event("depth", 3, Some(true), "103", "2"),
+        ]);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut player = MarketdataPlayer::new(Box::new(source), Box::new(Recorder(log.clone())))
+            .with_resilience(ResilienceModel::Permanent);
+        player.seek(0).await.unwrap();
+        player.play().await.unwrap();
+        let fills: Vec<String> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.contains("fill"))
+            .cloned()
+            .collect();
+        assert_eq!(fills, ["2 fill 101 0 1 1@101", "3 fill 102 0 2 1@101"]);
+    }
+}
@@ -1,81 +1,594 @@
-use crate::Event;
-use anyhow::{Ok, Result};
-use fpdec::Decimal;
+use crate::{tickbook::TickBook, Event};
+use anyhow::{Context, Result};
+use fpdec::{Decimal, Round};
+use serde::{Deserialize, Serialize};
 use std::{cmp::Reverse, collections::BTreeMap, str::FromStr};
 
 pub type Price = Decimal;
 pub type Quantity = Decimal;
 
-#[derive(Debug)]
+/// Side of an order: buys take the asks, sells take the bids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        }
+    }
+}
+
+impl FromStr for Side {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(format!("unknown side {:?}, expected buy or sell", s)),
+        }
+    }
+}
+
+/// Size of a market order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderSize {
+    /// Quantity of the base asset.
+    Quantity(Quantity),
+    /// Amount of the quote asset to spend or raise, filled in whole lots of
+    /// `lot_step` if one is given.
+    Notional {
+        notional: Decimal,
+        lot_step: Option<Quantity>,
+    },
+}
+
+/// Price level of the book, or the part of an order filled at one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Level {
+    pub price: Price,
+    pub quantity: Quantity,
+}
+
+/// Top levels of both sides, best first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Depth {
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+/// Result of walking the book with a market order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Execution {
+    pub side: Side,
+    /// Fills level by level, best price first.
+    pub fills: Vec<Level>,
+    pub quantity: Quantity,
+    /// Amount paid for a buy or received for a sell.
+    pub notional: Decimal,
+    /// Part of a notional sized order left unspent.
+    pub leftover: Decimal,
+}
+
+impl Execution {
+    fn new(side: Side) -> Self {
+        Self {
+            side,
+            fills: Vec::new(),
+            quantity: Decimal::ZERO,
+            notional: Decimal::ZERO,
+            leftover: Decimal::ZERO,
+        }
+    }
+    fn fill(&mut self, price: Price, quantity: Quantity) {
+        self.fills.push(Level { price, quantity });
+        self.quantity += quantity;
+        self.notional += price * quantity;
+    }
+    pub fn average_price(&self) -> Option<Price> {
+        (!self.quantity.eq_zero()).then(|| self.notional / self.quantity)
+    }
+    /// Number of price levels the order reached.
+    pub fn levels(&self) -> usize {
+        self.fills.len()
+    }
+}
+
+/// Interface of the order book implementations. Everything derived from the
+/// levels is provided on top of [`Book::levels`].
+pub trait Book {
+    /// Applies a depth or snapshot level, returning the level as it was
+    /// before: its price and previous quantity.
+    fn update(&mut self, diff: Event) -> Result<Level>;
+    /// Makes the levels of a snapshot visible if the book holds them back
+    /// until the snapshot is complete.
+    fn finish_snapshot(&mut self) {}
+    /// Applies a trade between depth updates: the levels better than the
+    /// trade price on the side it took are gone and the level at the trade
+    /// price is reduced by its quantity.
+    fn handle_trade(&mut self, trade: Event) -> Result<()>;
+    /// Levels an order of `side` takes, best first.
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_>;
+
+    /// Quantity of the level at `price` of the side an order of `side`
+    /// takes, zero if there is none.
+    fn quantity(&self, side: Side, price: Price) -> Quantity {
+        self.levels(side)
+            .find(|level| level.price == price)
+            .map_or(Decimal::ZERO, |level| level.quantity)
+    }
+    fn best_bid(&self) -> Option<Level> {
+        self.levels(Side::Sell).next()
+    }
+    fn best_ask(&self) -> Option<Level> {
+        self.levels(Side::Buy).next()
+    }
+    fn mid(&self) -> Option<Price> {
+        Some((self.best_bid()?.price + self.best_ask()?.price) / 2)
+    }
+    fn spread(&self) -> Option<Price> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+    /// Up to `levels` best levels of each side.
+    fn depth(&self, levels: usize) -> Depth {
+        Depth {
+            bids: self.levels(Side::Sell).take(levels).collect(),
+            asks: self.levels(Side::Buy).take(levels).collect(),
+        }
+    }
+    /// Fills of a market order of `side` and `size`, `None` if the book
+    /// cannot fill a quantity sized order or any of a notional sized one.
+    fn execute(&self, side: Side, size: OrderSize) -> Option<Execution> {
+        match size {
+            OrderSize::Quantity(quantity) => self.cost(side, quantity),
+            OrderSize::Notional { notional, lot_step } => {
+                Some(self.quantity_for_notional(side, notional, lot_step))
+                    .filter(|execution| !execution.fills.is_empty())
+            }
+        }
+    }
+    /// Fills of a market order of `side` for `quantity`, `None` if the book
+    /// is not deep enough.
+    fn cost(&self, side: Side, quantity: Quantity) -> Option<Execution> {
+        let mut execution = Execution::new(side);
+        let mut remaining = quantity;
+        let mut levels = self.levels(side);
+        while remaining > Decimal::ZERO {
+            let level = levels.next()?;
+            let filled = level.quantity.min(remaining);
+            execution.fill(level.price, filled);
+            remaining -= filled;
+        }
+        Some(execution)
+    }
+    /// Fills of a market order of `side` spending (or, for a sell, raising)
+    /// at most `notional`, i.e. `T = min{M / p_1, x_1} + max{min{(M - x_1 *
+    /// p_1) / p_2, x_2}, 0} + ...`, rounded down to a multiple of
+    /// `lot_step`. Stops early if the book runs out.
+    fn quantity_for_notional(
+        &self,
+        side: Side,
+        notional: Decimal,
+        lot_step: Option<Quantity>,
+    ) -> Execution {
+        let mut execution = Execution::new(side);
+        let mut remaining = notional;
+        for level in self.levels(side) {
+            if remaining <= Decimal::ZERO {
+                break;
+            }
+            let mut filled = (remaining / level.price).min(level.quantity);
+            // The quotient is rounded, never spend more than is left.
+            if filled * level.price > remaining {
+                filled -= Decimal::new_raw(1, filled.n_frac_digits());
+            }
+            if filled <= Decimal::ZERO {
+                break;
+            }
+            execution.fill(level.price, filled);
+            remaining -= level.price * filled;
+        }
+        if let Some(lot_step) = lot_step.filter(|lot_step| *lot_step > Decimal::ZERO) {
+            let lots = (execution.quantity - execution.quantity % lot_step)
+                .round(lot_step.n_frac_digits() as i8);
+            if lots != execution.quantity {
+                // Fewer lots cost less, so the walk for them stays within
+                // `notional`.
+                execution = self
+                    .cost(side, lots)
+                    .expect("book covers a smaller quantity");
+            }
+        }
+        execution.leftover = notional - execution.notional;
+        execution
+    }
+}
+
+/// Side that took liquidity in `trade`. Binance flags the trades whose buyer
+/// was the maker, i.e. sells; without the flag a trade at or above the best
+/// ask is a buy.
+pub(crate) fn aggressor(trade: &Event, at_ask: impl FnOnce() -> bool) -> Side {
+    match trade.buy_not_sell {
+        Some(true) => Side::Sell,
+        Some(false) => Side::Buy,
+        None if at_ask() => Side::Buy,
+        None => Side::Sell,
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Orderbook {
     bids: BTreeMap<Reverse<Price>, Quantity>,
     asks: BTreeMap<Price, Quantity>,
 }
 
-impl Default for Orderbook {
-    fn default() -> Self {
-        Self {
-            bids: BTreeMap::new(),
-            asks: BTreeMap::new(),
+impl Orderbook {
+    fn apply_trade(&mut self, side: Side, price: Price, quantity: Quantity) {
+        match side {
+            Side::Buy => {
+                self.asks = self.asks.split_off(&price);
+                if let Some(remaining) = self.asks.get_mut(&price) {
+                    *remaining -= quantity;
+                    if *remaining <= Decimal::ZERO {
+                        self.asks.remove(&price);
+                    }
+                }
+            }
+            Side::Sell => {
+                self.bids = self.bids.split_off(&Reverse(price));
+                if let Some(remaining) = self.bids.get_mut(&Reverse(price)) {
+                    *remaining -= quantity;
+                    if *remaining <= Decimal::ZERO {
+                        self.bids.remove(&Reverse(price));
+                    }
+                }
+            }
         }
     }
 }
 
-impl Orderbook {
-    pub fn update(&mut self, diff: Event) -> Result<()> {
+impl Book for Orderbook {
+    fn update(&mut self, diff: Event) -> Result<Level> {
         let price: Price = Decimal::from_str(&diff.price)?;
         let quantity: Quantity = Decimal::from_str(&diff.quantity)?;
-        let ask_not_bid = diff.ask_not_bid.unwrap();
-        if ask_not_bid {
+        let ask_not_bid = diff
+            .ask_not_bid
+            .with_context(|| format!("{} of {} has no side", diff.event_type, diff.product))?;
+        let before = if ask_not_bid {
             if quantity.eq_zero() {
-                self.asks.remove(&price);
+                self.asks.remove(&price)
             } else {
-                self.asks.insert(price, quantity);
+                self.asks.insert(price, quantity)
             }
         } else {
             if quantity.eq_zero() {
-                self.bids.remove(&Reverse(price));
+                self.bids.remove(&Reverse(price))
             } else {
-                self.bids.insert(Reverse(price), quantity);
+                self.bids.insert(Reverse(price), quantity)
             }
-        }
-        Ok(())
+        };
+        Ok(Level {
+            price,
+            quantity: before.unwrap_or(Decimal::ZERO),
+        })
     }
-    pub fn handle_trade(&mut self, trade: Event) -> Result<()> {
+    fn handle_trade(&mut self, trade: Event) -> Result<()> {
         let price: Price = Decimal::from_str(&trade.price)?;
         let quantity: Quantity = Decimal::from_str(&trade.quantity)?;
-        if let Some((&key, &value)) = self.asks.iter().next() {
-            if price >= key {
-                let remaining_quantity = value - quantity;
-                if remaining_quantity.eq_zero() {
-                    self.asks.remove(&price);
-                } else {
-                    self.asks.insert(price, remaining_quantity);
-                }
+        let side = aggressor(&trade, || {
+            self.best_ask().is_some_and(|ask| price >= ask.price)
+        });
+        self.apply_trade(side, price, quantity);
+        Ok(())
+    }
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        match side {
+            Side::Buy => Box::new(
+                self.asks
+                    .iter()
+                    .map(|(&price, &quantity)| Level { price, quantity }),
+            ),
+            Side::Sell => Box::new(
+                self.bids
+                    .iter()
+                    .map(|(&Reverse(price), &quantity)| Level { price, quantity }),
+            ),
+        }
+    }
+    fn quantity(&self, side: Side, price: Price) -> Quantity {
+        let quantity = match side {
+            Side::Buy => self.asks.get(&price),
+            Side::Sell => self.bids.get(&Reverse(price)),
+        };
+        quantity.copied().unwrap_or(Decimal::ZERO)
+    }
+}
+
+/// Book implementation selected at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BookKind {
+    /// [`Orderbook`], exact decimal prices in ordered maps.
+    Map,
+    /// [`TickBook`], integer ticks in sorted arrays.
+    Tick,
+}
+
+impl FromStr for BookKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "map" => Ok(BookKind::Map),
+            "tick" => Ok(BookKind::Tick),
+            _ => Err(format!("unknown book {:?}, expected map or tick", s)),
+        }
+    }
+}
+
+/// Either book implementation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AnyBook {
+    Map(Orderbook),
+    Tick(TickBook),
+}
+
+impl AnyBook {
+    /// Empty book of `kind`, `tick_size` is used by the tick book.
+    pub fn new(kind: BookKind, tick_size: Decimal) -> Self {
+        match kind {
+            BookKind::Map => AnyBook::Map(Orderbook::default()),
+            BookKind::Tick => AnyBook::Tick(TickBook::new(tick_size)),
+        }
+    }
+}
+
+impl Default for AnyBook {
+    fn default() -> Self {
+        AnyBook::Map(Orderbook::default())
+    }
+}
+
+impl Book for AnyBook {
+    fn update(&mut self, diff: Event) -> Result<Level> {
+        match self {
+            AnyBook::Map(book) => book.update(diff),
+            AnyBook::Tick(book) => book.update(diff),
+        }
+    }
+    fn finish_snapshot(&mut self) {
+        match self {
+            AnyBook::Map(book) => book.finish_snapshot(),
+            AnyBook::Tick(book) => book.finish_snapshot(),
+        }
+    }
+    fn handle_trade(&mut self, trade: Event) -> Result<()> {
+        match self {
+            AnyBook::Map(book) => book.handle_trade(trade),
+            AnyBook::Tick(book) => book.handle_trade(trade),
+        }
+    }
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        match self {
+            AnyBook::Map(book) => book.levels(side),
+            AnyBook::Tick(book) => book.levels(side),
+        }
+    }
+    fn quantity(&self, side: Side, price: Price) -> Quantity {
+        match self {
+            AnyBook::Map(book) => book.quantity(side, price),
+            AnyBook::Tick(book) => book.quantity(side, price),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Book kept as unsorted `(price, quantity)` pairs, sorted on demand.
+    #[derive(Debug, Default)]
+    struct NaiveBook {
+        bids: Vec<(Price, Quantity)>,
+        asks: Vec<(Price, Quantity)>,
+    }
+
+    impl NaiveBook {
+        fn update(&mut self, ask_not_bid: bool, price: Price, quantity: Quantity) {
+            let side = if ask_not_bid {
+                &mut self.asks
             } else {
-                let (_, &value) = self.bids.iter().next().unwrap();
-                let remaining_quantity = value - quantity;
-                if remaining_quantity.eq_zero() {
-                    self.bids.remove(&Reverse(price));
-                } else {
-                    self.bids.insert(Reverse(price), remaining_quantity);
+                &mut self.bids
+            };
+            side.retain(|&(level, _)| level != price);
+            if !quantity.eq_zero() {
+                side.push((price, quantity));
+            }
+        }
+        fn levels(&self, side: Side) -> Vec<Level> {
+            let mut levels: Vec<Level> = match side {
+                Side::Buy => &self.asks,
+                Side::Sell => &self.bids,
+            }
+            .iter()
+            .map(|&(price, quantity)| Level { price, quantity })
+            .collect();
+            levels.sort_by_key(|level| level.price);
+            if side == Side::Sell {
+                levels.reverse();
+            }
+            levels
+        }
+        fn cost(&self, side: Side, quantity: Quantity) -> Option<(Quantity, Decimal)> {
+            let levels = self.levels(side);
+            let available: Decimal = levels
+                .iter()
+                .fold(Decimal::ZERO, |sum, level| sum + level.quantity);
+            if available < quantity {
+                return None;
+            }
+            let mut notional = Decimal::ZERO;
+            let mut taken = Decimal::ZERO;
+            for level in levels {
+                let filled = level.quantity.min(quantity - taken);
+                notional += filled * level.price;
+                taken += filled;
+            }
+            Some((taken, notional))
+        }
+        fn trade(&mut self, side: Side, price: Price, quantity: Quantity) {
+            let (levels, better): (_, fn(Price, Price) -> bool) = match side {
+                Side::Buy => (&mut self.asks, |level, price| level < price),
+                Side::Sell => (&mut self.bids, |level, price| level > price),
+            };
+            levels.retain(|&(level, _)| !better(level, price));
+            for level in levels.iter_mut() {
+                if level.0 == price {
+                    level.1 -= quantity;
                 }
             }
+            levels.retain(|&(_, remaining)| remaining > Decimal::ZERO);
         }
-        Ok(())
     }
-    pub fn pbest(&mut self) -> f64 {
-        let (&price, _) = self.asks.iter().next().unwrap();
-        f64::from_str(&price.to_string()).unwrap()
+
+    fn event(ask_not_bid: bool, price: Price, quantity: Quantity) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: "depth".to_string(),
+            product: "TEST".to_string(),
+            id1: None,
+            id2: None,
+            ask_not_bid: Some(ask_not_bid),
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    fn price() -> impl Strategy<Value = Price> {
+        (100i128..200).prop_map(|coeff| Decimal::new_raw(coeff, 1))
+    }
+
+    fn quantity() -> impl Strategy<Value = Quantity> {
+        (0i128..5_000).prop_map(|coeff| Decimal::new_raw(coeff, 3))
+    }
+
+    fn side() -> impl Strategy<Value = Side> {
+        prop_oneof![Just(Side::Buy), Just(Side::Sell)]
     }
-    pub fn best_total_price(&self, mut amount: Decimal) -> Option<Decimal> {
-        let mut total_price = Decimal::ZERO;
-        let mut iter = self.asks.iter();
-        while amount > 0 {
-            let (&price, &quantity) = iter.next()?;
-            total_price += quantity.min(amount) * price;
-            amount -= quantity;
+
+    fn books() -> impl Strategy<Value = (Orderbook, NaiveBook)> {
+        prop::collection::vec((any::<bool>(), price(), quantity()), 0..60).prop_map(|updates| {
+            let mut book = Orderbook::default();
+            let mut naive = NaiveBook::default();
+            for (ask_not_bid, price, quantity) in updates {
+                book.update(event(ask_not_bid, price, quantity)).unwrap();
+                naive.update(ask_not_bid, price, quantity);
+            }
+            (book, naive)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn levels_match_reference((book, naive) in books(), levels in 0usize..10) {
+            let depth = book.depth(levels);
+            let bids = naive.levels(Side::Sell);
+            let asks = naive.levels(Side::Buy);
+            prop_assert_eq!(&depth.bids[..], &bids[..levels.min(bids.len())]);
+            prop_assert_eq!(&depth.asks[..], &asks[..levels.min(asks.len())]);
+            prop_assert_eq!(book.best_bid(), bids.first().copied());
+            prop_assert_eq!(book.best_ask(), asks.first().copied());
+            let spread = bids.first().zip(asks.first()).map(|(bid, ask)| ask.price - bid.price);
+            prop_assert_eq!(book.spread(), spread);
+            if let Some(mid) = book.mid() {
+                prop_assert_eq!(mid * 2, bids[0].price + asks[0].price);
+            }
+        }
+
+        #[test]
+        fn cost_matches_reference(
+            (book, naive) in books(),
+            side in side(),
+            quantity in quantity(),
+        ) {
+            let execution = book.cost(side, quantity);
+            prop_assert_eq!(
+                execution.as_ref().map(|execution| (execution.quantity, execution.notional)),
+                naive.cost(side, quantity)
+            );
+            if let Some(execution) = execution {
+                prop_assert_eq!(execution.quantity, quantity);
+                let levels = naive.levels(side);
+                for (fill, level) in execution.fills.iter().zip(&levels) {
+                    prop_assert_eq!(fill.price, level.price);
+                    prop_assert!(fill.quantity <= level.quantity);
+                }
+                // Only the last level is taken partially.
+                if let Some((_, full)) = execution.fills.split_last() {
+                    for (fill, level) in full.iter().zip(&levels) {
+                        prop_assert_eq!(fill.quantity, level.quantity);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn notional_is_not_exceeded(
+            (book, naive) in books(),
+            side in side(),
+            notional in (0i128..2_000_000).prop_map(|coeff| Decimal::new_raw(coeff, 2)),
+        ) {
+            let execution = book.quantity_for_notional(side, notional, None);
+            prop_assert!(execution.notional <= notional);
+            // Buying the obtained quantity costs what was spent, up to the
+            // rounding of the last digit.
+            let (quantity, cost) = naive.cost(side, execution.quantity).unwrap();
+            prop_assert_eq!(quantity, execution.quantity);
+            prop_assert!((cost - execution.notional).abs() < Decimal::new_raw(1, 12));
+            // What is left would not buy a whole tick more at the next price.
+            let levels = naive.levels(side);
+            let available = levels.iter().fold(Decimal::ZERO, |sum, level| sum + level.quantity);
+            let exhausted = execution.quantity == available;
+            if !exhausted {
+                let next = levels[execution.fills.len().saturating_sub(1)];
+                let tick = Decimal::new_raw(1, 6);
+                prop_assert!(notional - execution.notional < next.price * tick + tick);
+            }
+        }
+
+        #[test]
+        fn notional_is_spent_in_whole_lots(
+            (book, _naive) in books(),
+            side in side(),
+            notional in (0i128..2_000_000).prop_map(|coeff| Decimal::new_raw(coeff, 2)),
+            lot_step in (1i128..1_000).prop_map(|coeff| Decimal::new_raw(coeff, 3)),
+        ) {
+            let unrounded = book.quantity_for_notional(side, notional, None);
+            let execution = book.quantity_for_notional(side, notional, Some(lot_step));
+            prop_assert!((execution.quantity % lot_step).eq_zero());
+            prop_assert!(execution.quantity <= unrounded.quantity);
+            prop_assert!(unrounded.quantity - execution.quantity < lot_step);
+            prop_assert_eq!(execution.notional + execution.leftover, notional);
+            prop_assert!(execution.leftover >= Decimal::ZERO);
+        }
+
+        #[test]
+        fn trades_match_reference(
+            (mut book, mut naive) in books(),
+            side in side(),
+            price in price(),
+            quantity in quantity(),
+        ) {
+            book.apply_trade(side, price, quantity);
+            naive.trade(side, price, quantity);
+            prop_assert_eq!(book.levels(Side::Buy).collect::<Vec<_>>(), naive.levels(Side::Buy));
+            prop_assert_eq!(book.levels(Side::Sell).collect::<Vec<_>>(), naive.levels(Side::Sell));
         }
-        Some(total_price)
     }
 }
@@ -0,0 +1,181 @@
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+use tokio::fs;
+
+use crate::Event;
+
+/// Version of the cached event layout, bumped whenever `Event` changes so
+/// that stale cache files are not read back.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Time a window is left to settle by default before it is cached, for
+/// late events to be loaded into the table.
+const DEFAULT_SETTLE_DELAY: i64 = 60 * 60 * 1000;
+
+/// Local store of the events fetched from ClickHouse, one file per table,
+/// product and window.
+#[derive(Debug, Clone)]
+pub struct ReplayCache {
+    dir: PathBuf,
+    window: i64,
+    settle_delay: i64,
+}
+
+impl ReplayCache {
+    /// Caches windows of `window` milliseconds under `dir`. Windows are
+    /// aligned to the epoch so that replays with different start times share
+    /// them.
+    pub fn new(dir: impl Into<PathBuf>, window: i64) -> Self {
+        Self {
+            dir: dir.into(),
+            window: window.max(1),
+            settle_delay: DEFAULT_SETTLE_DELAY,
+        }
+    }
+    /// Sets how long after its end a window is cached.
+    pub fn with_settle_delay(mut self, delay: i64) -> Self {
+        self.settle_delay = delay.max(0);
+        self
+    }
+    /// Starts of the windows covering `[start_timestamp, end_timestamp)`.
+    pub fn windows(&self, start_timestamp: i64, end_timestamp: i64) -> impl Iterator<Item = i64> {
+        let window = self.window;
+        let first = start_timestamp.div_euclid(window) * window;
+        (first..end_timestamp).step_by(window as usize)
+    }
+    pub fn window(&self) -> i64 {
+        self.window
+    }
+    /// Whether the window starting at `window_start` can no longer change:
+    /// it ended `settle_delay` before `now` and the table already has events
+    /// after it, up to `loaded`.
+    pub fn settled(&self, window_start: i64, loaded: i64, now: i64) -> bool {
+        let window_end = window_start + self.window;
+        window_end <= loaded && window_end + self.settle_delay <= now
+    }
+    fn path(&self, table: &str, product: &str, window_start: i64) -> Result<PathBuf> {
+        for name in [table, product] {
+            if name.is_empty()
+                || name.starts_with('.')
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+            {
+                bail!("{:?} can not be used as a cache directory name", name);
+            }
+        }
+        Ok(self.dir.join(table).join(product).join(format!(
+            "{}-{}.v{}.bin",
+            window_start,
+            window_start + self.window,
+            SCHEMA_VERSION
+        )))
+    }
+    /// Events of a cached window, `None` if it has not been cached yet.
+    pub async fn read(
+        &self,
+        table: &str,
+        product: &str,
+        window_start: i64,
+    ) -> Result<Option<Vec<Event>>> {
+        match fs::read(self.path(table, product, window_start)?).await {
+            Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    /// Stores a window, replacing the file atomically so that concurrent
+    /// replays never read a partial one.
+    pub async fn write(
+        &self,
+        table: &str,
+        product: &str,
+        window_start: i64,
+        events: &[Event],
+    ) -> Result<()> {
+        let path = self.path(table, product, window_start)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let partial = path.with_extension(format!("partial.{}", std::process::id()));
+        fs::write(&partial, bincode::serialize(events)?).await?;
+        fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trade(gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: gate_timestamp,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: "trade".to_string(),
+            product: "BTCUSDT".to_string(),
+            id1: Some(gate_timestamp as u64),
+            id2: None,
+            ask_not_bid: None,
+            buy_not_sell: Some(false),
+            price: "1".to_string(),
+            quantity: "1".to_string(),
+        }
+    }
+
+    #[test]
+    fn windows_are_aligned_to_the_epoch() {
+        let cache = ReplayCache::new("cache", 100);
+        assert_eq!(
+            cache.windows(150, 420).collect::<Vec<_>>(),
+            [100, 200, 300, 400]
+        );
+        assert_eq!(cache.windows(-50, 1).collect::<Vec<_>>(), [-100, 0]);
+        assert_eq!(cache.windows(200, 200).count(), 0);
+    }
+
+    #[test]
+    fn windows_settle_after_the_delay_once_the_table_moved_past_them() {
+        let cache = ReplayCache::new("cache", 100).with_settle_delay(50);
+        assert!(cache.settled(100, 200, 250));
+        assert!(!cache.settled(100, 200, 249));
+        assert!(!cache.settled(100, 199, 1000));
+    }
+
+    #[tokio::test]
+    async fn round_trips_windows() {
+        let dir = std::env::temp_dir().join(format!("replaycache-{}", std::process::id()));
+        let cache = ReplayCache::new(&dir, 100);
+        assert!(cache.read("md", "BTCUSDT", 100).await.unwrap().is_none());
+
+        let events = vec![trade(100), trade(150)];
+        cache.write("md", "BTCUSDT", 100, &events).await.unwrap();
+        let cached = cache.read("md", "BTCUSDT", 100).await.unwrap().unwrap();
+        let timestamps: Vec<i64> = cached.iter().map(|event| event.gate_timestamp).collect();
+        assert_eq!(timestamps, [100, 150]);
+        assert!(cache.read("md", "BTCUSDT", 200).await.unwrap().is_none());
+        assert!(dir.join("md/BTCUSDT/100-200.v1.bin").is_file());
+
+        // A gap in the data is cached as an empty window.
+        cache.write("md", "BTCUSDT", 200, &[]).await.unwrap();
+        let cached = cache.read("md", "BTCUSDT", 200).await.unwrap();
+        assert!(cached.unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_names_outside_the_cache_dir() {
+        let cache = ReplayCache::new("cache", 100);
+        for (table, product) in [
+            ("..", "BTCUSDT"),
+            ("md", "../etc"),
+            ("/tmp", "x"),
+            ("md", ""),
+        ] {
+            assert!(cache.read(table, product, 0).await.is_err());
+            assert!(cache.write(table, product, 0, &[]).await.is_err());
+        }
+        assert!(cache.path("db.md_sorted", "BTCUSDT", 0).is_ok());
+    }
+}
@@ -0,0 +1,675 @@
+pub mod fees;
+pub mod impact;
+pub mod limit;
+
+use anyhow::Result;
+use fpdec::Decimal;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+};
+
+use self::{
+    fees::{FeeModel, FeeRates},
+    impact::{Impact, ImpactedBook, ResilienceModel},
+    limit::{LimitOrder, LimitOrders, Liquidity, OrderEvent, OrderUpdate, QueueModel, Rejection},
+};
+use crate::{
+    clock::SimulationClock,
+    orderbook::{Book, Execution, OrderSize, Price, Quantity, Side},
+    tca::{OrderRole, Tca, TcaReport},
+    Event, EventKind,
+};
+
+/// Identifier of a simulated order or request, unique within a replay.
+pub type OrderId = u64;
+
+/// Number of recent events the empirical latency is drawn from by default.
+pub const DEFAULT_LATENCY_WINDOW: usize = 1000;
+
+/// Delay in milliseconds between submitting an order and it reaching the
+/// book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LatencyModel {
+    Constant(i64),
+    /// The `gate - venue` delta of one of the last `window` events, the time
+    /// market data took to reach the collector.
+    Empirical {
+        window: usize,
+    },
+    /// Uniform between `min` and `max` inclusive.
+    Uniform {
+        min: i64,
+        max: i64,
+    },
+}
+
+impl Default for LatencyModel {
+    fn default() -> Self {
+        LatencyModel::Constant(0)
+    }
+}
+
+impl FromStr for LatencyModel {
+    type Err = String;
+    /// Parses "N" or "constant:N", "empirical" or "empirical:WINDOW" and
+    /// "uniform:MIN:MAX".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |part: &str| {
+            part.parse::<i64>()
+                .ok()
+                .filter(|&number| number >= 0)
+                .ok_or_else(|| format!("invalid latency {:?}", part))
+        };
+        match parts.as_slice() {
+            [latency] if latency.parse::<i64>().is_ok() => {
+                Ok(LatencyModel::Constant(number(latency)?))
+            }
+            ["constant", latency] => Ok(LatencyModel::Constant(number(latency)?)),
+            ["empirical"] => Ok(LatencyModel::Empirical {
+                window: DEFAULT_LATENCY_WINDOW,
+            }),
+            ["empirical", window] => match window.parse() {
+                Ok(window) if window > 0 => Ok(LatencyModel::Empirical { window }),
+                _ => Err(format!("invalid latency window {:?}", window)),
+            },
+            ["uniform", min, max] => {
+                let (min, max) = (number(min)?, number(max)?);
+                if min > max {
+                    return Err(format!("latency {} is greater than {}", min, max));
+                }
+                Ok(LatencyModel::Uniform { min, max })
+            }
+            _ => Err(format!(
+                "unknown latency model {:?}, expected N, constant:N, empirical[:WINDOW] or uniform:MIN:MAX",
+                s
+            )),
+        }
+    }
+}
+
+/// Market order placed through `Context::market_order`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarketOrder {
+    pub id: OrderId,
+    pub product: String,
+    pub side: Side,
+    pub size: OrderSize,
+    /// Simulation time the order was submitted at.
+    pub submitted: i64,
+}
+
+/// Report of a [`MarketOrder`] filled by the [`OrderSimulator`].
+#[derive(Debug, Clone)]
+pub struct Fill {
+    pub order: MarketOrder,
+    /// Simulation time the order reached the book and was filled at.
+    pub timestamp: i64,
+    /// Mid of the book when the order was submitted, its arrival price.
+    pub arrival_mid: Option<Price>,
+    pub execution: Execution,
+    /// Taker fee of the execution in the quote asset, see [`FeeModel`].
+    pub fee: Decimal,
+}
+
+impl Fill {
+    pub fn latency(&self) -> i64 {
+        self.timestamp - self.order.submitted
+    }
+    pub fn average_price(&self) -> Option<Price> {
+        self.execution.average_price()
+    }
+    /// How much worse than the arrival mid the average price is, positive
+    /// when a buy paid more or a sell got less.
+    pub fn slippage(&self) -> Option<Decimal> {
+        let (price, mid) = self.average_price().zip(self.arrival_mid)?;
+        Some(match self.order.side {
+            Side::Buy => price - mid,
+            Side::Sell => mid - price,
+        })
+    }
+    /// [`Fill::fee`] in basis points of the notional.
+    pub fn fee_bps(&self) -> Option<f64> {
+        let notional = f64::from(self.execution.notional);
+        (notional > 0.0).then(|| f64::from(self.fee) / notional * 10_000.0)
+    }
+    /// [`Fill::slippage`] in basis points of the arrival mid.
+    pub fn slippage_bps(&self) -> Option<f64> {
+        let slippage = f64::from(self.slippage()?);
+        Some(slippage / f64::from(self.arrival_mid?) * 10_000.0)
+    }
+    /// Number of price levels the order walked.
+    pub fn levels(&self) -> usize {
+        self.execution.levels()
+    }
+}
+
+/// Market order on its way to the book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Submitted {
+    pub(crate) order: MarketOrder,
+    pub(crate) arrival_mid: Option<Price>,
+}
+
+/// Request of the strategy on its way to the book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Request {
+    Market(Submitted),
+    Place(LimitOrder),
+    Cancel(OrderId),
+    Amend {
+        order: OrderId,
+        price: Price,
+        quantity: Quantity,
+    },
+}
+
+/// Delays the requests of the strategy by a [`LatencyModel`] until they
+/// reach the book. Market orders with no latency are filled against the
+/// book the strategy saw, the others against the book at the time they
+/// arrive. Limit orders rest in [`LimitOrders`] until they are filled or
+/// cancelled. The fills taking liquidity deplete the books for the later
+/// orders as the [`ResilienceModel`] says. Every fill pays the fees of
+/// the [`FeeModel`] and every order and fill is recorded for the transaction cost analysis of [`OrderSimulator::tca`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrderSimulator {
+    latency: LatencyModel,
+    /// Seed of the random latencies, drawn for each request separately so
+    /// that they do not depend on the replay being restored from a
+    /// checkpoint.
+    seed: u64,
+    next_request: OrderId,
+    /// Recent `gate - venue` deltas for the empirical latency.
+    deltas: VecDeque<i64>,
+    in_flight: HashMap<OrderId, Request>,
+    /// Requests that have reached the book and wait to be executed.
+    arrived: VecDeque<Request>,
+    limit_orders: LimitOrders,
+    impact: Impact,
+    fees: FeeModel,
+    tca: Tca,
+}
+
+impl OrderSimulator {
+    /// Delays the requests by `latency`, drawing random latencies from
+    /// `seed`.
+    pub fn with_latency(mut self, latency: LatencyModel, seed: u64) -> Self {
+        self.latency = latency;
+        self.seed = seed;
+        self
+    }
+    /// Moves the queues of the resting limit orders by `model`.
+    pub fn with_queue_model(mut self, model: QueueModel) -> Self {
+        self.limit_orders.set_model(model);
+        self
+    }
+    /// Keeps the liquidity taken by the fills out of the books as long as
+    /// `model` says.
+    pub fn with_resilience(mut self, model: ResilienceModel) -> Self {
+        self.impact.set_model(model);
+        self
+    }
+    /// Charges the fills the fees of `fees`.
+    pub fn with_fees(mut self, fees: FeeModel) -> Self {
+        self.fees = fees;
+        self
+    }
+    /// Continues from the state of `simulator`, keeping the latency, queue,
+    /// resilience and fee models.
+    pub(crate) fn restore(&mut self, simulator: OrderSimulator) {
+        let queue_model = self.limit_orders.model();
+        let resilience = self.impact.model();
+        *self = Self {
+            latency: self.latency.clone(),
+            seed: self.seed,
+            fees: std::mem::take(&mut self.fees),
+            ..simulator
+        };
+        self.limit_orders.set_model(queue_model);
+        self.impact.set_model(resilience);
+    }
+    /// Keeps the latency of `event` for the empirical model. Snapshots are
+    /// stamped with the gate time on both clocks and have none.
+    pub(crate) fn observe(&mut self, event: &Event) {
+        if event.kind() == Some(EventKind::Snapshot) {
+            return;
+        }
+        if let LatencyModel::Empirical { window } = self.latency {
+            if self.deltas.len() == window {
+                self.deltas.pop_front();
+            }
+            self.deltas
+                .push_back((event.gate_timestamp - event.venue_timestamp).max(0));
+        }
+    }
+    /// Sends a market order on its way to the book.
+    pub(crate) fn submit(
+        &mut self,
+        clock: &mut SimulationClock,
+        product: &str,
+        side: Side,
+        size: OrderSize,
+        arrival_mid: Option<Price>,
+    ) -> OrderId {
+        let now = clock.now();
+        let id = self.send(clock, |id| {
+            Request::Market(Submitted {
+                order: MarketOrder {
+                    id,
+                    product: product.to_string(),
+                    side,
+                    size,
+                    submitted: now,
+                },
+                arrival_mid,
+            })
+        });
+        self.tca.order(
+            id,
+            OrderRole::Child,
+            None,
+            product,
+            side,
+            size,
+            arrival_mid,
+            now,
+        );
+        id
+    }
+    /// Sends a limit order on its way to the book, a child of `parent` if
+    /// it is given.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn place(
+        &mut self,
+        clock: &mut SimulationClock,
+        product: &str,
+        side: Side,
+        price: Price,
+        quantity: Quantity,
+        arrival_mid: Option<Price>,
+        parent: Option<OrderId>,
+    ) -> OrderId {
+        let now = clock.now();
+        let id = self.send(clock, |id| {
+            Request::Place(LimitOrder {
+                id,
+                product: product.to_string(),
+                side,
+                price,
+                quantity,
+                filled: Decimal::ZERO,
+                queue_ahead: Decimal::ZERO,
+                submitted: now,
+            })
+        });
+        let size = OrderSize::Quantity(quantity);
+        self.tca.order(
+            id,
+            OrderRole::Child,
+            parent,
+            product,
+            side,
+            size,
+            arrival_mid,
+            now,
+        );
+        id
+    }
+    /// Records a parent order, which only exists for the transaction cost
+    /// analysis of the child orders placed for it.
+    pub(crate) fn parent_order(
+        &mut self,
+        product: &str,
+        side: Side,
+        quantity: Quantity,
+        arrival_mid: Option<Price>,
+        now: i64,
+    ) -> OrderId {
+        let id = self.next_request;
+        self.next_request += 1;
+        let size = OrderSize::Quantity(quantity);
+        self.tca.order(
+            id,
+            OrderRole::Parent,
+            None,
+            product,
+            side,
+            size,
+            arrival_mid,
+            now,
+        );
+        id
+    }
+    /// Product and side of parent order `parent`.
+    pub(crate) fn parent(&self, parent: OrderId) -> Option<(&str, Side)> {
+        self.tca.parent(parent)
+    }
+    pub(crate) fn cancel(&mut self, clock: &mut SimulationClock, order: OrderId) {
+        self.send(clock, |_| Request::Cancel(order));
+    }
+    pub(crate) fn amend(
+        &mut self,
+        clock: &mut SimulationClock,
+        order: OrderId,
+        price: Price,
+        quantity: Quantity,
+    ) {
+        self.send(clock, |_| Request::Amend {
+            order,
+            price,
+            quantity,
+        });
+    }
+    /// Sends the request `request` makes of its id, scheduling its arrival
+    /// on `clock` unless it has no latency.
+    fn send(
+        &mut self,
+        clock: &mut SimulationClock,
+        request: impl FnOnce(OrderId) -> Request,
+    ) -> OrderId {
+        let id = self.next_request;
+        self.next_request += 1;
+        let request = request(id);
+        let latency = self.latency(id);
+        if latency == 0 {
+            self.arrived.push_back(request);
+        } else {
+            self.in_flight.insert(id, request);
+            clock.arrival_at(clock.now().saturating_add(latency), id);
+        }
+        id
+    }
+    /// Marks `request` as having reached the book.
+    pub(crate) fn arrive(&mut self, request: OrderId) {
+        if let Some(request) = self.in_flight.remove(&request) {
+            self.arrived.push_back(request);
+        }
+    }
+    pub(crate) fn next_arrived(&mut self) -> Option<Request> {
+        self.arrived.pop_front()
+    }
+    /// Executes a request that has reached the book, `books` giving the
+    /// book of a product. Returns the fill of a market order, which is
+    /// rejected if the book cannot fill it.
+    pub(crate) fn execute<'b, B: Book + 'b>(
+        &mut self,
+        request: Request,
+        books: impl Fn(&str) -> Option<&'b B>,
+        now: i64,
+    ) -> Option<Fill> {
+        let pending = self.limit_orders.pending_updates().len();
+        let (product, side, fills) = match request {
+            Request::Market(Submitted { order, arrival_mid }) => {
+                let Some(book) = books(&order.product) else {
+                    self.reject(order.id, Rejection::UnknownProduct, now);
+                    return None;
+                };
+                let Some(execution) = self
+                    .impact
+                    .view(&order.product, book, now)
+                    .execute(order.side, order.size)
+                else {
+                    self.reject(order.id, Rejection::InsufficientLiquidity, now);
+                    return None;
+                };
+                self.impact
+                    .take(&order.product, order.side, &execution.fills, now);
+                let mut fee = Decimal::ZERO;
+                for fill in &execution.fills {
+                    let level_fee =
+                        self.fees
+                            .fee(&order.product, Liquidity::Taker, fill.price * fill.quantity);
+                    self.tca.fill(
+                        order.id,
+                        fill.price,
+                        fill.quantity,
+                        level_fee,
+                        Some(book),
+                        now,
+                    );
+                    fee += level_fee;
+                }
+                return Some(Fill {
+                    order,
+                    timestamp: now,
+                    arrival_mid,
+                    execution,
+                    fee,
+                });
+            }
+            Request::Place(order) => {
+                let book = books(&order.product);
+                let book = book.map(|book| self.impact.view(&order.product, book, now));
+                let (product, side) = (order.product.clone(), order.side);
+                let fills = self.limit_orders.place(order, book.as_ref(), now);
+                (product, side, fills)
+            }
+            Request::Cancel(order) => {
+                self.limit_orders.cancel(order, now);
+                return None;
+            }
+            Request::Amend {
+                order,
+                price,
+                quantity,
+            } => {
+                let (product, side) = self
+                    .limit_orders
+                    .get(order)
+                    .map(|order| (order.product.clone(), order.side))
+                    .unzip();
+                let book = product
+                    .as_deref()
+                    .and_then(|product| Some(self.impact.view(product, books(product)?, now)));
+                let fills = self
+                    .limit_orders
+                    .amend(order, price, quantity, book.as_ref(), now);
+                (product?, side?, fills)
+            }
+        };
+        self.impact.take(&product, side, &fills, now);
+        self.charge(&product, pending, books(&product));
+        None
+    }
+    /// Reports the rejection of market order `order`, which leaves no costs
+    /// to analyse.
+    fn reject(&mut self, order: OrderId, rejection: Rejection, now: i64) {
+        self.limit_orders.reject(order, now, rejection);
+        self.tca.reject(order);
+    }
+    /// Charges the fees of the limit order fills of `product` among the
+    /// updates after the first `pending` and records them for the
+    /// transaction cost analysis, `book` being the recorded book.
+    fn charge(&mut self, product: &str, pending: usize, book: Option<&impl Book>) {
+        let updates = self.limit_orders.pending_updates();
+        for update in updates.iter_mut().skip(pending) {
+            if let OrderEvent::Filled {
+                price,
+                quantity,
+                liquidity,
+                fee,
+                ..
+            } = &mut update.event
+            {
+                *fee = self.fees.fee(product, *liquidity, *price * *quantity);
+            }
+        }
+        self.tca.limit_fills(updates.iter().skip(pending), book);
+    }
+    /// `book` of `product` without the liquidity the fills have taken.
+    pub fn impacted<'a, B: Book>(
+        &'a self,
+        product: &str,
+        book: &'a B,
+        now: i64,
+    ) -> ImpactedBook<'a, B> {
+        self.impact.view(product, book, now)
+    }
+    /// Moves the limit orders of the product of `event` before it is
+    /// applied to `book`.
+    pub(crate) fn on_event(
+        &mut self,
+        kind: EventKind,
+        event: &Event,
+        book: &impl Book,
+        now: i64,
+    ) -> Result<()> {
+        self.tca.on_event(kind, event, book, now);
+        let pending = self.limit_orders.pending_updates().len();
+        self.limit_orders.on_event(kind, event, book, now)?;
+        self.charge(&event.product, pending, Some(book));
+        Ok(())
+    }
+    /// Fee rates of the fills of `product`.
+    pub fn fee_rates(&self, product: &str) -> FeeRates {
+        self.fees.rates(product)
+    }
+    /// Transaction cost analysis of the orders so far.
+    pub fn tca(&self) -> TcaReport {
+        self.tca.report()
+    }
+    pub(crate) fn next_update(&mut self) -> Option<OrderUpdate> {
+        self.limit_orders.next_update()
+    }
+    /// Limit order `order` if it rests in the book.
+    pub fn limit_order(&self, order: OrderId) -> Option<&LimitOrder> {
+        self.limit_orders.get(order)
+    }
+    pub fn limit_orders(&self) -> impl Iterator<Item = &LimitOrder> {
+        self.limit_orders.iter()
+    }
+    fn latency(&self, order: OrderId) -> i64 {
+        let mut rng = StdRng::seed_from_u64(self.seed ^ order.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        match self.latency {
+            LatencyModel::Constant(latency) => latency,
+            LatencyModel::Empirical { .. } if self.deltas.is_empty() => 0,
+            LatencyModel::Empirical { .. } => self.deltas[rng.gen_range(0..self.deltas.len())],
+            LatencyModel::Uniform { min, max } => rng.gen_range(min..=max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::Orderbook;
+
+    fn event(event_type: &str, venue_timestamp: i64, gate_timestamp: i64) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: "101".to_string(),
+            quantity: "2".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_latency_models() {
+        assert_eq!("5".parse(), Ok(LatencyModel::Constant(5)));
+        assert_eq!("constant:0".parse(), Ok(LatencyModel::Constant(0)));
+        assert_eq!(
+            "empirical".parse(),
+            Ok(LatencyModel::Empirical {
+                window: DEFAULT_LATENCY_WINDOW
+            })
+        );
+        assert_eq!(
+            "uniform:2:10".parse(),
+            Ok(LatencyModel::Uniform { min: 2, max: 10 })
+        );
+        assert!("uniform:10:2".parse::<LatencyModel>().is_err());
+        assert!("-1".parse::<LatencyModel>().is_err());
+        assert!("empirical:0".parse::<LatencyModel>().is_err());
+    }
+
+    #[test]
+    fn random_latencies_are_reproducible() {
+        let simulator =
+            OrderSimulator::default().with_latency(LatencyModel::Uniform { min: 2, max: 10 }, 7);
+        let latencies: Vec<i64> = (0..100).map(|order| simulator.latency(order)).collect();
+        assert!(latencies.iter().all(|latency| (2..=10).contains(latency)));
+        assert!(latencies.iter().any(|&latency| latency != latencies[0]));
+        let again: Vec<i64> = (0..100).map(|order| simulator.latency(order)).collect();
+        assert_eq!(latencies, again);
+    }
+
+    #[test]
+    fn rejects_market_orders_the_book_cannot_fill() {
+        let mut book = Orderbook::default();
+        book.update(event("snapshot", 0, 0)).unwrap();
+        let mut simulator = OrderSimulator::default();
+        let mut clock = SimulationClock::new(0);
+        let size = OrderSize::Quantity(Decimal::from(3));
+        let order = simulator.submit(&mut clock, "TEST", Side::Buy, size, None);
+        let other = simulator.submit(&mut clock, "OTHER", Side::Buy, size, None);
+
+        for _ in 0..2 {
+            let request = simulator.next_arrived().unwrap();
+            let fill =
+                simulator.execute(request, |product| (product == "TEST").then_some(&book), 5);
+            assert!(fill.is_none());
+        }
+        let updates: Vec<OrderUpdate> = std::iter::from_fn(|| simulator.next_update()).collect();
+        assert_eq!(
+            updates,
+            [
+                OrderUpdate {
+                    order,
+                    timestamp: 5,
+                    event: OrderEvent::Rejected(Rejection::InsufficientLiquidity),
+                },
+                OrderUpdate {
+                    order: other,
+                    timestamp: 5,
+                    event: OrderEvent::Rejected(Rejection::UnknownProduct),
+                },
+            ]
+        );
+        assert!(simulator.tca().orders.is_empty());
+    }
+
+    #[test]
+    fn rejects_notional_orders_nothing_fills() {
+        let book = Orderbook::default();
+        let mut simulator = OrderSimulator::default();
+        let mut clock = SimulationClock::new(0);
+        let size = OrderSize::Notional {
+            notional: Decimal::from(100),
+            lot_step: None,
+        };
+        let order = simulator.submit(&mut clock, "TEST", Side::Buy, size, None);
+
+        let request = simulator.next_arrived().unwrap();
+        assert!(simulator.execute(request, |_| Some(&book), 5).is_none());
+        assert_eq!(
+            simulator.next_update(),
+            Some(OrderUpdate {
+                order,
+                timestamp: 5,
+                event: OrderEvent::Rejected(Rejection::InsufficientLiquidity),
+            })
+        );
+        assert!(simulator.tca().orders.is_empty());
+    }
+
+    #[test]
+    fn empirical_latencies_leave_out_snapshots() {
+        let mut simulator =
+            OrderSimulator::default().with_latency(LatencyModel::Empirical { window: 10 }, 7);
+        simulator.observe(&event("snapshot", 100, 100));
+        simulator.observe(&event("depth", 100, 104));
+        simulator.observe(&event("snapshot", 110, 110));
+        assert_eq!(simulator.deltas, [4]);
+        assert!((0..20).all(|order| simulator.latency(order) == 4));
+    }
+}
@@ -0,0 +1,161 @@
+use fpdec::{Decimal, Round};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str::FromStr};
+
+use super::limit::Liquidity;
+use crate::tickbook::QUANTITY_DIGITS;
+
+/// Maker and taker rates of the Binance spot VIP tiers 0 to 9 in millionths
+/// of the notional, 1000 being 0.1% or 10 basis points.
+const VIP_RATES: [(i128, i128); 10] = [
+    (1000, 1000),
+    (900, 1000),
+    (800, 1000),
+    (420, 600),
+    (420, 540),
+    (360, 480),
+    (300, 420),
+    (240, 360),
+    (180, 300),
+    (120, 240),
+];
+
+/// Rates of the fees of a fill as fractions of its notional. A negative
+/// rate is a rebate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeRates {
+    pub maker: Decimal,
+    pub taker: Decimal,
+}
+
+impl FeeRates {
+    pub fn from_bps(maker: Decimal, taker: Decimal) -> Self {
+        let bps = Decimal::from(10_000);
+        Self {
+            maker: maker / bps,
+            taker: taker / bps,
+        }
+    }
+    /// Rates of Binance spot VIP `tier`, `None` above 9.
+    pub fn vip(tier: usize) -> Option<Self> {
+        let (maker, taker) = *VIP_RATES.get(tier)?;
+        Some(Self {
+            maker: Decimal::new_raw(maker, 6),
+            taker: Decimal::new_raw(taker, 6),
+        })
+    }
+    pub fn rate(&self, liquidity: Liquidity) -> Decimal {
+        match liquidity {
+            Liquidity::Maker => self.maker,
+            Liquidity::Taker => self.taker,
+        }
+    }
+}
+
+impl FromStr for FeeRates {
+    type Err = String;
+    /// Parses "vipN" and "MAKER:TAKER" in basis points.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(tier) = s.strip_prefix("vip") {
+            return tier
+                .parse()
+                .ok()
+                .and_then(FeeRates::vip)
+                .ok_or_else(|| format!("unknown VIP tier {:?}, expected vip0 to vip9", s));
+        }
+        let bps = |part: &str| {
+            Decimal::from_str(part).map_err(|_| format!("invalid fee rate {:?}", part))
+        };
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            [maker, taker] => Ok(FeeRates::from_bps(bps(maker)?, bps(taker)?)),
+            _ => Err(format!(
+                "unknown fee rates {:?}, expected vipN or MAKER:TAKER in bps",
+                s
+            )),
+        }
+    }
+}
+
+/// Fees the simulated fills pay, in the quote asset. Products take the
+/// default rates unless they have their own. Without rates the fills are
+/// free.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeModel {
+    rates: FeeRates,
+    products: HashMap<String, FeeRates>,
+    /// Whether the fees are paid in BNB, which takes a quarter off them.
+    bnb: bool,
+}
+
+impl FeeModel {
+    pub fn new(rates: FeeRates) -> Self {
+        Self {
+            rates,
+            ..Default::default()
+        }
+    }
+    pub fn with_product(mut self, product: impl Into<String>, rates: FeeRates) -> Self {
+        self.products.insert(product.into(), rates);
+        self
+    }
+    /// Pays the fees in BNB. The discount does not apply to rebates.
+    pub fn with_bnb_discount(mut self) -> Self {
+        self.bnb = true;
+        self
+    }
+    /// Rates the fills of `product` pay, after the discount.
+    pub fn rates(&self, product: &str) -> FeeRates {
+        let rates = self.products.get(product).unwrap_or(&self.rates);
+        let discount = |rate: Decimal| match self.bnb && rate > Decimal::ZERO {
+            true => rate * Decimal::new_raw(75, 2),
+            false => rate,
+        };
+        FeeRates {
+            maker: discount(rates.maker),
+            taker: discount(rates.taker),
+        }
+    }
+    /// Fee of a fill of `product` for `notional`, in the precision of the
+    /// quantities of the venue.
+    pub fn fee(&self, product: &str, liquidity: Liquidity, notional: Decimal) -> Decimal {
+        (notional * self.rates(product).rate(liquidity)).round(QUANTITY_DIGITS as i8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn fees_follow_tiers_products_and_discount() {
+        assert_eq!("vip3".parse(), Ok(FeeRates::vip(3).unwrap()));
+        assert!("vip10".parse::<FeeRates>().is_err());
+        let rebate: FeeRates = "-0.5:2".parse().unwrap();
+        assert_eq!(rebate.maker, decimal("-0.00005"));
+
+        let fees = FeeModel::new(FeeRates::vip(0).unwrap())
+            .with_product("ETHUSDT", rebate)
+            .with_bnb_discount();
+        let notional = decimal("1000");
+        assert_eq!(
+            fees.fee("BTCUSDT", Liquidity::Taker, notional),
+            decimal("0.75")
+        );
+        assert_eq!(
+            fees.fee("ETHUSDT", Liquidity::Taker, notional),
+            decimal("0.15")
+        );
+        assert_eq!(
+            fees.fee("ETHUSDT", Liquidity::Maker, notional),
+            decimal("-0.05")
+        );
+        assert_eq!(
+            FeeModel::default().fee("BTCUSDT", Liquidity::Taker, notional),
+            Decimal::ZERO
+        );
+    }
+}
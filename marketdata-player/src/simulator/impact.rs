@@ -0,0 +1,273 @@
+use anyhow::{bail, Result};
+use fpdec::{Decimal, Round};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use crate::{
+    orderbook::{Book, Level, Price, Quantity, Side},
+    tickbook::QUANTITY_DIGITS,
+    Event,
+};
+
+/// How the liquidity taken by simulated fills comes back to the book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResilienceModel {
+    /// Right away, the fills have no impact.
+    #[default]
+    Instant,
+    /// Never, the levels stay depleted by the fills.
+    Permanent,
+    /// The part still missing halves every `half_life` milliseconds.
+    Exponential { half_life: i64 },
+    /// The part missing at the last fill of a level comes back evenly over
+    /// `refill` milliseconds.
+    Linear { refill: i64 },
+}
+
+impl FromStr for ResilienceModel {
+    type Err = String;
+    /// Parses "instant", "permanent", "exponential:HALF_LIFE" and
+    /// "linear:REFILL".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let time = |part: &str| {
+            part.parse::<i64>()
+                .ok()
+                .filter(|&time| time > 0)
+                .ok_or_else(|| format!("invalid resilience time {:?}", part))
+        };
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["instant"] => Ok(ResilienceModel::Instant),
+            ["permanent"] => Ok(ResilienceModel::Permanent),
+            ["exponential", half_life] => Ok(ResilienceModel::Exponential {
+                half_life: time(half_life)?,
+            }),
+            ["linear", refill] => Ok(ResilienceModel::Linear {
+                refill: time(refill)?,
+            }),
+            _ => Err(format!(
+                "unknown resilience model {:?}, expected instant, permanent, exponential:HALF_LIFE or linear:REFILL",
+                s
+            )),
+        }
+    }
+}
+
+impl ResilienceModel {
+    /// Part of `taken`, taken `elapsed` milliseconds ago, still missing
+    /// from its level, in the precision of the quantities of the venue.
+    pub fn missing(&self, taken: Quantity, elapsed: i64) -> Quantity {
+        let elapsed = elapsed.max(0);
+        match *self {
+            ResilienceModel::Instant => Decimal::ZERO,
+            ResilienceModel::Permanent => taken,
+            ResilienceModel::Exponential { half_life } => {
+                let factor = 0.5f64.powf(elapsed as f64 / half_life as f64);
+                let factor = Decimal::new_raw((factor * 1e9).round() as i128, 9);
+                (taken * factor).round(QUANTITY_DIGITS as i8)
+            }
+            ResilienceModel::Linear { refill } if elapsed >= refill => Decimal::ZERO,
+            ResilienceModel::Linear { refill } => (taken * Decimal::from(refill - elapsed)
+                / Decimal::from(refill))
+            .round(QUANTITY_DIGITS as i8),
+        }
+    }
+}
+
+/// Quantity taken from a level and when.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Taken {
+    quantity: Quantity,
+    timestamp: i64,
+}
+
+/// Liquidity the simulated fills have taken from the recorded books. The
+/// recorded depth updates know nothing of it, so it is subtracted from the
+/// levels until the [`ResilienceModel`] brings it back.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Impact {
+    model: ResilienceModel,
+    /// Taken quantities by product and the side of the orders that took
+    /// them, by price.
+    taken: HashMap<(String, Side), BTreeMap<Price, Taken>>,
+}
+
+impl Impact {
+    pub(crate) fn model(&self) -> ResilienceModel {
+        self.model
+    }
+    pub(crate) fn set_model(&mut self, model: ResilienceModel) {
+        self.model = model;
+    }
+    /// `book` of `product` less the liquidity still missing at `now`.
+    pub(crate) fn view<'a, B: Book>(
+        &'a self,
+        product: &str,
+        book: &'a B,
+        now: i64,
+    ) -> ImpactedBook<'a, B> {
+        let taken = |side| self.taken.get(&(product.to_string(), side));
+        ImpactedBook {
+            book,
+            model: self.model,
+            asks: taken(Side::Buy),
+            bids: taken(Side::Sell),
+            now,
+        }
+    }
+    /// Takes the `fills` of an order of `side` out of the book of `product`.
+    pub(crate) fn take(&mut self, product: &str, side: Side, fills: &[Level], now: i64) {
+        if self.model == ResilienceModel::Instant || fills.is_empty() {
+            return;
+        }
+        let model = self.model;
+        let levels = self.taken.entry((product.to_string(), side)).or_default();
+        levels.retain(|_, taken| {
+            !model
+                .missing(taken.quantity, now - taken.timestamp)
+                .eq_zero()
+        });
+        for fill in fills {
+            let missing = levels.get(&fill.price).map_or(Decimal::ZERO, |taken| {
+                model.missing(taken.quantity, now - taken.timestamp)
+            });
+            levels.insert(
+                fill.price,
+                Taken {
+                    quantity: missing + fill.quantity,
+                    timestamp: now,
+                },
+            );
+        }
+    }
+}
+
+/// Read-only view of a book with the impact of the simulated fills, see
+/// [`ResilienceModel`].
+pub struct ImpactedBook<'a, B> {
+    book: &'a B,
+    model: ResilienceModel,
+    asks: Option<&'a BTreeMap<Price, Taken>>,
+    bids: Option<&'a BTreeMap<Price, Taken>>,
+    now: i64,
+}
+
+impl<B: Book> Book for ImpactedBook<'_, B> {
+    fn update(&mut self, _diff: Event) -> Result<Level> {
+        bail!("the impacted view of a book cannot be updated")
+    }
+    fn handle_trade(&mut self, _trade: Event) -> Result<()> {
+        bail!("the impacted view of a book cannot be updated")
+    }
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        let taken = match side {
+            Side::Buy => self.asks,
+            Side::Sell => self.bids,
+        };
+        let Some(taken) = taken else {
+            return self.book.levels(side);
+        };
+        Box::new(self.book.levels(side).filter_map(move |level| {
+            let missing = taken.get(&level.price).map_or(Decimal::ZERO, |taken| {
+                self.model
+                    .missing(taken.quantity, self.now - taken.timestamp)
+            });
+            let quantity = level.quantity - missing;
+            (quantity > Decimal::ZERO).then_some(Level {
+                price: level.price,
+                quantity,
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::{OrderSize, Orderbook};
+
+    fn decimal(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn book() -> Orderbook {
+        let mut book = Orderbook::default();
+        for (price, quantity) in [("101", "1"), ("102", "2")] {
+            book.update(ask(price, quantity)).unwrap();
+        }
+        book
+    }
+
+    fn ask(price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: "snapshot".to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid: Some(true),
+            buy_not_sell: None,
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_resilience_models() {
+        assert_eq!("instant".parse(), Ok(ResilienceModel::Instant));
+        assert_eq!(
+            "exponential:500".parse(),
+            Ok(ResilienceModel::Exponential { half_life: 500 })
+        );
+        assert_eq!(
+            "linear:100".parse(),
+            Ok(ResilienceModel::Linear { refill: 100 })
+        );
+        assert!("linear:0".parse::<ResilienceModel>().is_err());
+    }
+
+    #[test]
+    fn liquidity_comes_back_by_model() {
+        let taken = decimal("2");
+        let exponential = ResilienceModel::Exponential { half_life: 100 };
+        assert_eq!(exponential.missing(taken, 0), taken);
+        assert_eq!(exponential.missing(taken, 200), decimal("0.5"));
+        let linear = ResilienceModel::Linear { refill: 100 };
+        assert_eq!(linear.missing(taken, 25), decimal("1.5"));
+        assert_eq!(linear.missing(taken, 100), Decimal::ZERO);
+        assert_eq!(ResilienceModel::Permanent.missing(taken, 1000), taken);
+    }
+
+    #[test]
+    fn later_orders_see_the_depleted_book() {
+        let book = book();
+        let mut impact = Impact::default();
+        impact.set_model(ResilienceModel::Linear { refill: 100 });
+        let order = OrderSize::Quantity(decimal("1.5"));
+        let execution = impact.view("TEST", &book, 0).execute(Side::Buy, order);
+        impact.take("TEST", Side::Buy, &execution.unwrap().fills, 0);
+        // Half of the 1.5 taken is back after half of the refill time.
+        let view = impact.view("TEST", &book, 50);
+        let levels: Vec<Level> = view.levels(Side::Buy).collect();
+        assert_eq!(
+            levels,
+            [
+                Level {
+                    price: decimal("101"),
+                    quantity: decimal("0.5")
+                },
+                Level {
+                    price: decimal("102"),
+                    quantity: decimal("1.75")
+                },
+            ]
+        );
+        // The other side and products are not affected.
+        assert_eq!(impact.view("OTHER", &book, 50).best_ask(), book.best_ask());
+        assert!(view.best_bid().is_none());
+    }
+}
@@ -0,0 +1,705 @@
+use anyhow::Result;
+use fpdec::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+    str::FromStr,
+};
+
+use super::OrderId;
+use crate::{
+    orderbook::{aggressor, Book, Level, Price, Quantity, Side},
+    Event, EventKind,
+};
+
+/// Power of the probabilistic queue model by default.
+pub const DEFAULT_QUEUE_POWER: u32 = 2;
+
+/// How the quantity ahead of a resting order moves when its level shrinks
+/// without a trade. Trades at the price of the order move it up in every
+/// model, and new quantity always joins the level behind it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueueModel {
+    /// Cancellations come from behind the order, so it is filled only once
+    /// the trades at its price exceed the quantity ahead.
+    #[default]
+    Conservative,
+    /// A cancellation comes from ahead of the order with probability
+    /// `f(ahead) / (f(ahead) + f(behind))`, `f(x) = x^power`, and the queue
+    /// moves up by the expected quantity. `power` 1 spreads cancellations
+    /// over the queue evenly, higher ones take them more from the longer
+    /// part.
+    Probabilistic { power: u32 },
+}
+
+impl FromStr for QueueModel {
+    type Err = String;
+    /// Parses "conservative", "probabilistic" or "probabilistic:POWER".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["conservative"] => Ok(QueueModel::Conservative),
+            ["probabilistic"] => Ok(QueueModel::Probabilistic {
+                power: DEFAULT_QUEUE_POWER,
+            }),
+            ["probabilistic", power] => match power.parse() {
+                Ok(power) if power > 0 => Ok(QueueModel::Probabilistic { power }),
+                _ => Err(format!("invalid queue model power {:?}", power)),
+            },
+            _ => Err(format!(
+                "unknown queue model {:?}, expected conservative or probabilistic[:POWER]",
+                s
+            )),
+        }
+    }
+}
+
+impl QueueModel {
+    /// Quantity ahead of an order after its level shrank from `before` to
+    /// `after` without a trade.
+    pub fn on_decrease(&self, ahead: Quantity, before: Quantity, after: Quantity) -> Quantity {
+        let ahead = match *self {
+            QueueModel::Conservative => ahead,
+            QueueModel::Probabilistic { power } => {
+                let behind = (before - ahead).max(Decimal::ZERO);
+                let (front, back) = (pow(ahead, power), pow(behind, power));
+                if (front + back).eq_zero() {
+                    ahead
+                } else {
+                    ahead - (before - after) * front / (front + back)
+                }
+            }
+        };
+        ahead.max(Decimal::ZERO).min(after)
+    }
+}
+
+fn pow(x: Decimal, power: u32) -> Decimal {
+    (0..power).fold(Decimal::ONE, |product, _| product * x)
+}
+
+/// Limit order resting in the book, as the venue sees it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LimitOrder {
+    pub id: OrderId,
+    pub product: String,
+    pub side: Side,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub filled: Quantity,
+    /// Estimated quantity of the level ahead of the order.
+    pub queue_ahead: Quantity,
+    /// Simulation time the order was submitted at.
+    pub submitted: i64,
+}
+
+impl LimitOrder {
+    pub fn remaining(&self) -> Quantity {
+        self.quantity - self.filled
+    }
+    /// Whether a level at `price` of the other side of the book would fill
+    /// the order.
+    fn crosses(&self, price: Price) -> bool {
+        match self.side {
+            Side::Buy => price <= self.price,
+            Side::Sell => price >= self.price,
+        }
+    }
+    /// Whether the order is better priced than `price` on its side.
+    fn improves(&self, price: Price) -> bool {
+        match self.side {
+            Side::Buy => self.price > price,
+            Side::Sell => self.price < price,
+        }
+    }
+    /// Side of the book the order rests on, the one `ask_not_bid` events
+    /// update for a sell.
+    fn rests_on_asks(&self) -> bool {
+        self.side == Side::Sell
+    }
+}
+
+/// Whether a fill provided or took liquidity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// Why the venue refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    UnknownProduct,
+    /// Price or quantity not positive, or an amendment to no more than the
+    /// filled quantity.
+    InvalidOrder,
+    /// Cancel or amendment of an order that is not in the book, e.g. one
+    /// filled while the request was on its way.
+    NotOpen,
+    /// Market order for more than the book holds.
+    InsufficientLiquidity,
+}
+
+/// Step of the lifecycle of a limit order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderEvent {
+    /// The order reached the book and what it did not fill right away rests
+    /// behind `queue_ahead`.
+    Accepted {
+        queue_ahead: Quantity,
+    },
+    Filled {
+        price: Price,
+        quantity: Quantity,
+        remaining: Quantity,
+        liquidity: Liquidity,
+        /// Fee in the quote asset, negative for a rebate, see
+        /// [`super::fees::FeeModel`].
+        fee: Decimal,
+    },
+    /// The amendment reached the book. A new price or a larger quantity
+    /// sends the order to the back of the queue.
+    Amended {
+        price: Price,
+        quantity: Quantity,
+        queue_ahead: Quantity,
+    },
+    Cancelled {
+        remaining: Quantity,
+    },
+    Rejected(Rejection),
+}
+
+/// Update passed to `Strategy::on_order_update`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderUpdate {
+    pub order: OrderId,
+    pub timestamp: i64,
+    pub event: OrderEvent,
+}
+
+/// Limit orders resting in the books of the replay. The recorded books do
+/// not contain them, so their queue positions are estimated from the depth
+/// updates and trades at their prices. An order is only filled by trades:
+/// one at its price fills what exceeds the quantity ahead, one through its
+/// price fills it completely.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LimitOrders {
+    model: QueueModel,
+    orders: BTreeMap<OrderId, LimitOrder>,
+    updates: VecDeque<OrderUpdate>,
+}
+
+impl LimitOrders {
+    pub(crate) fn model(&self) -> QueueModel {
+        self.model
+    }
+    pub(crate) fn set_model(&mut self, model: QueueModel) {
+        self.model = model;
+    }
+    pub(crate) fn get(&self, order: OrderId) -> Option<&LimitOrder> {
+        self.orders.get(&order)
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &LimitOrder> {
+        self.orders.values()
+    }
+    pub(crate) fn next_update(&mut self) -> Option<OrderUpdate> {
+        self.updates.pop_front()
+    }
+    /// Updates the strategy has not been told about yet, oldest first.
+    pub(crate) fn pending_updates(&mut self) -> &mut VecDeque<OrderUpdate> {
+        &mut self.updates
+    }
+    /// Places `order` that has reached `book`. It takes the levels of the
+    /// other side it crosses, which are returned, and the rest joins the
+    /// back of its level.
+    pub(crate) fn place(
+        &mut self,
+        mut order: LimitOrder,
+        book: Option<&impl Book>,
+        now: i64,
+    ) -> Vec<Level> {
+        let Some(book) = book else {
+            self.push(
+                order.id,
+                now,
+                OrderEvent::Rejected(Rejection::UnknownProduct),
+            );
+            return Vec::new();
+        };
+        if order.price <= Decimal::ZERO || order.quantity <= Decimal::ZERO {
+            self.push(order.id, now, OrderEvent::Rejected(Rejection::InvalidOrder));
+            return Vec::new();
+        }
+        order.queue_ahead = resting(book, order.side, order.price);
+        self.push(
+            order.id,
+            now,
+            OrderEvent::Accepted {
+                queue_ahead: order.queue_ahead,
+            },
+        );
+        let fills = self.take(&mut order, book, now);
+        if order.remaining() > Decimal::ZERO {
+            self.orders.insert(order.id, order);
+        }
+        fills
+    }
+    pub(crate) fn cancel(&mut self, order: OrderId, now: i64) {
+        let event = match self.orders.remove(&order) {
+            Some(order) => OrderEvent::Cancelled {
+                remaining: order.remaining(),
+            },
+            None => OrderEvent::Rejected(Rejection::NotOpen),
+        };
+        self.push(order, now, event);
+    }
+    /// Changes the price or the total quantity of `order`. Only a smaller
+    /// quantity keeps its place in the queue. Returns the levels a new price
+    /// takes, as [`LimitOrders::place`].
+    pub(crate) fn amend(
+        &mut self,
+        id: OrderId,
+        price: Price,
+        quantity: Quantity,
+        book: Option<&impl Book>,
+        now: i64,
+    ) -> Vec<Level> {
+        let (Some(mut order), Some(book)) = (self.orders.remove(&id), book) else {
+            self.push(id, now, OrderEvent::Rejected(Rejection::NotOpen));
+            return Vec::new();
+        };
+        if price <= Decimal::ZERO || quantity <= order.filled {
+            self.push(id, now, OrderEvent::Rejected(Rejection::InvalidOrder));
+            self.orders.insert(id, order);
+            return Vec::new();
+        }
+        if price != order.price || quantity > order.quantity {
+            order.queue_ahead = resting(book, order.side, price);
+        }
+        order.price = price;
+        order.quantity = quantity;
+        self.push(
+            id,
+            now,
+            OrderEvent::Amended {
+                price,
+                quantity,
+                queue_ahead: order.queue_ahead,
+            },
+        );
+        let fills = self.take(&mut order, book, now);
+        if order.remaining() > Decimal::ZERO {
+            self.orders.insert(id, order);
+        }
+        fills
+    }
+    /// Moves the queues of the orders of the product of `event`, which is
+    /// about to be applied to `book`, and fills the orders it trades with.
+    pub(crate) fn on_event(
+        &mut self,
+        kind: EventKind,
+        event: &Event,
+        book: &impl Book,
+        now: i64,
+    ) -> Result<()> {
+        if !self
+            .orders
+            .values()
+            .any(|order| order.product == event.product)
+        {
+            return Ok(());
+        }
+        let price = Decimal::from_str(&event.price)?;
+        let quantity = Decimal::from_str(&event.quantity)?;
+        match kind {
+            EventKind::Snapshot => {}
+            EventKind::Depth => {
+                let Some(ask_not_bid) = event.ask_not_bid else {
+                    return Ok(());
+                };
+                let model = self.model;
+                for order in self.orders.values_mut() {
+                    if order.product != event.product
+                        || order.rests_on_asks() != ask_not_bid
+                        || order.price != price
+                    {
+                        continue;
+                    }
+                    let before = resting(book, order.side, price);
+                    order.queue_ahead = if quantity < before {
+                        model.on_decrease(order.queue_ahead, before, quantity)
+                    } else {
+                        order.queue_ahead.min(quantity)
+                    };
+                }
+            }
+            EventKind::Trade => {
+                let taker = aggressor(event, || {
+                    book.best_ask().is_some_and(|ask| price >= ask.price)
+                });
+                // Our orders are filled best priced first from the trade
+                // quantity, those at the trade price only from the part
+                // beyond their queue, so that the fills never add up to
+                // more than the trade.
+                let mut taken = Decimal::ZERO;
+                let mut crossed: Vec<(Price, OrderId)> = self
+                    .orders
+                    .values()
+                    .filter(|order| {
+                        order.product == event.product
+                            && order.side == taker.opposite()
+                            && order.crosses(price)
+                    })
+                    .map(|order| (order.price, order.id))
+                    .collect();
+                match taker {
+                    Side::Sell => crossed.sort_by_key(|&(price, id)| (Reverse(price), id)),
+                    Side::Buy => crossed.sort(),
+                }
+                for (_, id) in crossed {
+                    let Some(order) = self.orders.get_mut(&id) else {
+                        continue;
+                    };
+                    let available = quantity - taken;
+                    let filled = if order.improves(price) {
+                        available.min(order.remaining())
+                    } else {
+                        let reached = available - order.queue_ahead;
+                        order.queue_ahead = (order.queue_ahead - quantity).max(Decimal::ZERO);
+                        reached.max(Decimal::ZERO).min(order.remaining())
+                    };
+                    if filled <= Decimal::ZERO {
+                        continue;
+                    }
+                    taken += filled;
+                    let fill_price = order.price;
+                    self.fill(id, fill_price, filled, Liquidity::Maker, now);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Fills `order` from the levels of `book` it crosses.
+    fn take(&mut self, order: &mut LimitOrder, book: &impl Book, now: i64) -> Vec<Level> {
+        let levels: Vec<_> = book
+            .levels(order.side)
+            .take_while(|level| order.crosses(level.price))
+            .collect();
+        let mut fills = Vec::new();
+        for level in levels {
+            let quantity = level.quantity.min(order.remaining());
+            if quantity.eq_zero() {
+                break;
+            }
+            order.filled += quantity;
+            fills.push(Level {
+                price: level.price,
+                quantity,
+            });
+            self.push(
+                order.id,
+                now,
+                OrderEvent::Filled {
+                    price: level.price,
+                    quantity,
+                    remaining: order.remaining(),
+                    liquidity: Liquidity::Taker,
+                    fee: Decimal::ZERO,
+                },
+            );
+        }
+        fills
+    }
+    fn fill(
+        &mut self,
+        id: OrderId,
+        price: Price,
+        quantity: Quantity,
+        liquidity: Liquidity,
+        now: i64,
+    ) {
+        let Some(order) = self.orders.get_mut(&id) else {
+            return;
+        };
+        order.filled += quantity;
+        let remaining = order.remaining();
+        if remaining.eq_zero() {
+            self.orders.remove(&id);
+        }
+        self.push(
+            id,
+            now,
+            OrderEvent::Filled {
+                price,
+                quantity,
+                remaining,
+                liquidity,
+                fee: Decimal::ZERO,
+            },
+        );
+    }
+    /// Reports that market order `order` was refused.
+    pub(crate) fn reject(&mut self, order: OrderId, timestamp: i64, rejection: Rejection) {
+        self.push(order, timestamp, OrderEvent::Rejected(rejection));
+    }
+    fn push(&mut self, order: OrderId, timestamp: i64, event: OrderEvent) {
+        self.updates.push_back(OrderUpdate {
+            order,
+            timestamp,
+            event,
+        });
+    }
+}
+
+/// Quantity of `book` at `price` on the side an order of `side` rests on.
+fn resting(book: &impl Book, side: Side, price: Price) -> Quantity {
+    book.levels(side.opposite())
+        .take_while(|level| match side {
+            Side::Buy => level.price >= price,
+            Side::Sell => level.price <= price,
+        })
+        .find(|level| level.price == price)
+        .map_or(Decimal::ZERO, |level| level.quantity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::Orderbook;
+
+    fn event(event_type: &str, ask_not_bid: Option<bool>, price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid,
+            // A sell taking the bids.
+            buy_not_sell: ask_not_bid.is_none().then_some(true),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    fn decimal(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn book() -> Orderbook {
+        let mut book = Orderbook::default();
+        book.update(event("snapshot", Some(false), "99", "5"))
+            .unwrap();
+        book.update(event("snapshot", Some(true), "101", "3"))
+            .unwrap();
+        book
+    }
+
+    fn buy(price: &str, quantity: &str) -> LimitOrder {
+        LimitOrder {
+            id: 0,
+            product: "TEST".to_string(),
+            side: Side::Buy,
+            price: decimal(price),
+            quantity: decimal(quantity),
+            filled: Decimal::ZERO,
+            queue_ahead: Decimal::ZERO,
+            submitted: 0,
+        }
+    }
+
+    /// Applies `event` to the orders and then to `book`.
+    fn apply(orders: &mut LimitOrders, book: &mut Orderbook, event: Event) {
+        let kind = event.kind().unwrap();
+        orders.on_event(kind, &event, book, 0).unwrap();
+        match kind {
+            EventKind::Trade => book.handle_trade(event).unwrap(),
+            _ => {
+                book.update(event).unwrap();
+            }
+        }
+    }
+
+    fn updates(orders: &mut LimitOrders) -> Vec<OrderEvent> {
+        std::iter::from_fn(|| orders.next_update())
+            .map(|update| update.event)
+            .collect()
+    }
+
+    #[test]
+    fn parses_queue_models() {
+        assert_eq!("conservative".parse(), Ok(QueueModel::Conservative));
+        assert_eq!(
+            "probabilistic".parse(),
+            Ok(QueueModel::Probabilistic {
+                power: DEFAULT_QUEUE_POWER
+            })
+        );
+        assert_eq!(
+            "probabilistic:3".parse(),
+            Ok(QueueModel::Probabilistic { power: 3 })
+        );
+        assert!("probabilistic:0".parse::<QueueModel>().is_err());
+    }
+
+    #[test]
+    fn cancellations_move_the_queue_by_model() {
+        let (ahead, before, after) = (decimal("2"), decimal("8"), decimal("4"));
+        let conservative = QueueModel::Conservative;
+        assert_eq!(conservative.on_decrease(ahead, before, after), ahead);
+        // A quarter of the queue is ahead, a quarter of the cancellation
+        // comes from it.
+        let even = QueueModel::Probabilistic { power: 1 };
+        assert_eq!(even.on_decrease(ahead, before, after), decimal("1"));
+        // 4 / (4 + 36) of it with the squares.
+        let squared = QueueModel::Probabilistic { power: 2 };
+        assert_eq!(squared.on_decrease(ahead, before, after), decimal("1.6"));
+        // The queue ahead is never longer than the level.
+        assert_eq!(conservative.on_decrease(ahead, before, decimal("1")), 1);
+    }
+
+    #[test]
+    fn trades_fill_beyond_the_queue() {
+        let mut book = book();
+        let mut orders = LimitOrders::default();
+        orders.place(buy("99", "2"), Some(&book), 0);
+        assert_eq!(
+            updates(&mut orders),
+            [OrderEvent::Accepted {
+                queue_ahead: decimal("5")
+            }]
+        );
+        apply(
+            &mut orders,
+            &mut book,
+            event("depth", Some(false), "99", "4"),
+        );
+        apply(&mut orders, &mut book, event("trade", None, "99", "3"));
+        assert!(updates(&mut orders).is_empty());
+        assert_eq!(orders.get(0).unwrap().queue_ahead, 1);
+        apply(&mut orders, &mut book, event("trade", None, "99", "2"));
+        assert_eq!(
+            updates(&mut orders),
+            [OrderEvent::Filled {
+                price: decimal("99"),
+                quantity: decimal("1"),
+                remaining: decimal("1"),
+                liquidity: Liquidity::Maker,
+                fee: Decimal::ZERO,
+            }]
+        );
+        // A trade through the price fills no more than its quantity.
+        apply(&mut orders, &mut book, event("trade", None, "98", "0.4"));
+        apply(&mut orders, &mut book, event("trade", None, "98", "5"));
+        assert_eq!(
+            updates(&mut orders),
+            [
+                OrderEvent::Filled {
+                    price: decimal("99"),
+                    quantity: decimal("0.4"),
+                    remaining: decimal("0.6"),
+                    liquidity: Liquidity::Maker,
+                    fee: Decimal::ZERO,
+                },
+                OrderEvent::Filled {
+                    price: decimal("99"),
+                    quantity: decimal("0.6"),
+                    remaining: Decimal::ZERO,
+                    liquidity: Liquidity::Maker,
+                    fee: Decimal::ZERO,
+                }
+            ]
+        );
+        assert!(orders.get(0).is_none());
+    }
+
+    #[test]
+    fn trades_through_the_price_fill_the_best_orders_first() {
+        let mut book = book();
+        let mut orders = LimitOrders::default();
+        orders.place(buy("99", "1"), Some(&book), 0);
+        orders.place(
+            LimitOrder {
+                id: 1,
+                ..buy("100", "1")
+            },
+            Some(&book),
+            0,
+        );
+        orders.place(
+            LimitOrder {
+                id: 2,
+                ..buy("99.5", "1")
+            },
+            Some(&book),
+            0,
+        );
+        updates(&mut orders);
+
+        apply(&mut orders, &mut book, event("trade", None, "98", "1.5"));
+        let filled: Vec<(OrderId, Quantity)> = std::iter::from_fn(|| orders.next_update())
+            .filter_map(|update| match update.event {
+                OrderEvent::Filled { quantity, .. } => Some((update.order, quantity)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(filled, [(1, decimal("1")), (2, decimal("0.5"))]);
+        assert_eq!(orders.get(0).unwrap().filled, Decimal::ZERO);
+    }
+
+    #[test]
+    fn marketable_orders_take_then_rest() {
+        let book = book();
+        let mut orders = LimitOrders::default();
+        orders.place(buy("101", "4"), Some(&book), 0);
+        assert_eq!(
+            updates(&mut orders),
+            [
+                OrderEvent::Accepted {
+                    queue_ahead: Decimal::ZERO
+                },
+                OrderEvent::Filled {
+                    price: decimal("101"),
+                    quantity: decimal("3"),
+                    remaining: decimal("1"),
+                    liquidity: Liquidity::Taker,
+                    fee: Decimal::ZERO,
+                },
+            ]
+        );
+        assert_eq!(orders.get(0).unwrap().remaining(), 1);
+    }
+
+    #[test]
+    fn amendments_keep_priority_only_when_reducing() {
+        let mut book = book();
+        let mut orders = LimitOrders::default();
+        orders.place(buy("99", "2"), Some(&book), 0);
+        apply(&mut orders, &mut book, event("trade", None, "99", "4"));
+        orders.amend(0, decimal("99"), decimal("1"), Some(&book), 0);
+        assert_eq!(orders.get(0).unwrap().queue_ahead, 1);
+        orders.amend(0, decimal("99"), decimal("3"), Some(&book), 0);
+        assert_eq!(orders.get(0).unwrap().queue_ahead, 1);
+        apply(
+            &mut orders,
+            &mut book,
+            event("depth", Some(false), "99", "6"),
+        );
+        orders.amend(0, decimal("99"), decimal("4"), Some(&book), 0);
+        assert_eq!(orders.get(0).unwrap().queue_ahead, 6);
+        orders.cancel(0, 0);
+        orders.cancel(0, 0);
+        assert_eq!(
+            updates(&mut orders)[4..],
+            [
+                OrderEvent::Cancelled {
+                    remaining: decimal("4")
+                },
+                OrderEvent::Rejected(Rejection::NotOpen),
+            ]
+        );
+    }
+}
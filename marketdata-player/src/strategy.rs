@@ -0,0 +1,230 @@
+pub mod hotelling;
+
+use anyhow::{Ok, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+use crate::{
+    analytics::BookFeatures,
+    clock::{SimulationClock, TimerId},
+    marketdataplayer::ProductState,
+    orderbook::{AnyBook, Book, OrderSize, Price, Quantity, Side},
+    simulator::{
+        fees::FeeRates,
+        impact::ImpactedBook,
+        limit::{LimitOrder, OrderUpdate},
+        Fill, OrderId, OrderSimulator, Request,
+    },
+    Event,
+};
+
+/// Trading logic driven by `MarketdataPlayer`. The handler of an event is
+/// called when the event arrives, before it is applied to the book. Every
+/// handler does nothing by default.
+#[async_trait]
+pub trait Strategy: Send {
+    /// Called once before the first event.
+    async fn on_start(&mut self, _ctx: &mut Context<'_>) -> Result<()> {
+        Ok(())
+    }
+    /// Called for each level of a snapshot after the first one of a product,
+    /// which only initializes the book.
+    async fn on_snapshot(&mut self, _ctx: &mut Context<'_>, _snapshot: &Event) -> Result<()> {
+        Ok(())
+    }
+    async fn on_depth(&mut self, _ctx: &mut Context<'_>, _diff: &Event) -> Result<()> {
+        Ok(())
+    }
+    async fn on_trade(&mut self, _ctx: &mut Context<'_>, _trade: &Event) -> Result<()> {
+        Ok(())
+    }
+    /// Called for a timer scheduled on [`Context::clock`].
+    async fn on_timer(&mut self, _ctx: &mut Context<'_>, _timer: TimerId) -> Result<()> {
+        Ok(())
+    }
+    /// Called when a parent order arrives, `order` being the index of its
+    /// arrival time in the order times of the player.
+    async fn on_order(&mut self, _ctx: &mut Context<'_>, _order: usize) -> Result<()> {
+        Ok(())
+    }
+    async fn on_fill(&mut self, _ctx: &mut Context<'_>, _fill: &Fill) -> Result<()> {
+        Ok(())
+    }
+    /// Called for every step of the lifecycle of a limit order: its
+    /// acceptance, fills, amendments, cancellation or rejection.
+    async fn on_order_update(
+        &mut self,
+        _ctx: &mut Context<'_>,
+        _update: &OrderUpdate,
+    ) -> Result<()> {
+        Ok(())
+    }
+    /// Called once after the last event.
+    async fn on_end(&mut self, _ctx: &mut Context<'_>) -> Result<()> {
+        Ok(())
+    }
+    /// State saved in the checkpoints of the player.
+    fn checkpoint(&self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+    /// Continues from the state of [`Strategy::checkpoint`]. Called before
+    /// [`Strategy::on_start`] when the player resumes a replay.
+    fn restore(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// What a [`Strategy`] handler can see and do: read the books, place
+/// simulated orders and schedule timers.
+pub struct Context<'a> {
+    clock: &'a mut SimulationClock,
+    live: bool,
+    products: &'a BTreeMap<String, ProductState>,
+    depth_bps: &'a [f64],
+    simulator: &'a mut OrderSimulator,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(
+        clock: &'a mut SimulationClock,
+        live: bool,
+        products: &'a BTreeMap<String, ProductState>,
+        depth_bps: &'a [f64],
+        simulator: &'a mut OrderSimulator,
+    ) -> Self {
+        Self {
+            clock,
+            live,
+            products,
+            depth_bps,
+            simulator,
+        }
+    }
+    /// Simulation time of the event or the wakeup being handled.
+    pub fn timestamp(&self) -> i64 {
+        self.clock.now()
+    }
+    /// Schedules timers calling [`Strategy::on_timer`].
+    pub fn clock(&mut self) -> &mut SimulationClock {
+        self.clock
+    }
+    /// `false` while the player fast-forwards to the start of the replay, when
+    /// a strategy should only keep its state up to date.
+    pub fn live(&self) -> bool {
+        self.live
+    }
+    pub fn products(&self) -> impl Iterator<Item = &str> {
+        self.products.keys().map(String::as_str)
+    }
+    pub fn book(&self, product: &str) -> Option<&AnyBook> {
+        self.products.get(product).map(|state| &state.orderbook)
+    }
+    /// Book of `product` as the simulated orders see it, without the
+    /// liquidity earlier fills have taken, see
+    /// [`crate::simulator::impact::ResilienceModel`].
+    pub fn impacted_book(&self, product: &str) -> Option<ImpactedBook<'_, AnyBook>> {
+        let book = self.book(product)?;
+        Some(self.simulator.impacted(product, book, self.clock.now()))
+    }
+    /// Features of the book of `product`, see [`crate::analytics`].
+    pub fn features(&self, product: &str) -> Option<&BookFeatures> {
+        self.products
+            .get(product)
+            .map(|state| state.analytics.features())
+    }
+    /// Rates of the fees the fills of `product` pay, to weigh posting
+    /// against crossing the spread.
+    pub fn fee_rates(&self, product: &str) -> FeeRates {
+        self.simulator.fee_rates(product)
+    }
+    /// Distances from the mid of the depth curve of the features.
+    pub fn depth_bps(&self) -> &[f64] {
+        self.depth_bps
+    }
+    /// Submits a market order to the [`OrderSimulator`]. An order
+    /// with no latency is filled against the book the handler sees once it
+    /// returns. Orders the book cannot fill are rejected through
+    /// [`Strategy::on_order_update`].
+    pub fn market_order(&mut self, product: &str, side: Side, size: OrderSize) -> OrderId {
+        let mid = self.book(product).and_then(|book| book.mid());
+        self.simulator.submit(self.clock, product, side, size, mid)
+    }
+    /// Places a limit order, which the handlers hear about through
+    /// [`Strategy::on_order_update`]. The requests of a strategy reach the
+    /// book with the same latency as its market orders.
+    pub fn limit_order(
+        &mut self,
+        product: &str,
+        side: Side,
+        price: Price,
+        quantity: Quantity,
+    ) -> OrderId {
+        let mid = self.book(product).and_then(|book| book.mid());
+        self.simulator
+            .place(self.clock, product, side, price, quantity, mid, None)
+    }
+    /// Starts a parent order of `quantity` of `product`. It is not sent
+    /// anywhere, the transaction cost analysis groups the fills of the
+    /// [`Context::child_order`]s placed for it.
+    pub fn parent_order(&mut self, product: &str, side: Side, quantity: Quantity) -> OrderId {
+        let mid = self.book(product).and_then(|book| book.mid());
+        let now = self.clock.now();
+        self.simulator
+            .parent_order(product, side, quantity, mid, now)
+    }
+    /// Places a limit order for parent order `parent` on its product and
+    /// side, `None` if there is no such parent order.
+    pub fn child_order(
+        &mut self,
+        parent: OrderId,
+        price: Price,
+        quantity: Quantity,
+    ) -> Option<OrderId> {
+        let (product, side) = self.simulator.parent(parent)?;
+        let product = product.to_string();
+        let mid = self.book(&product).and_then(|book| book.mid());
+        Some(self.simulator.place(
+            self.clock,
+            &product,
+            side,
+            price,
+            quantity,
+            mid,
+            Some(parent),
+        ))
+    }
+    pub fn cancel(&mut self, order: OrderId) {
+        self.simulator.cancel(self.clock, order);
+    }
+    /// Changes the price or the total quantity of a limit order.
+    pub fn amend(&mut self, order: OrderId, price: Price, quantity: Quantity) {
+        self.simulator.amend(self.clock, order, price, quantity);
+    }
+    /// Limit order `order` as it rests in the book, `None` if it has not
+    /// reached the book yet or is no longer in it.
+    pub fn open_order(&self, order: OrderId) -> Option<&LimitOrder> {
+        self.simulator.limit_order(order)
+    }
+    pub fn open_orders(&self) -> impl Iterator<Item = &LimitOrder> {
+        self.simulator.limit_orders()
+    }
+    pub(crate) fn arrive(&mut self, request: OrderId) {
+        self.simulator.arrive(request);
+    }
+    pub(crate) fn next_arrived(&mut self) -> Option<Request> {
+        self.simulator.next_arrived()
+    }
+    /// Executes a request against the books, returning the fill of a
+    /// market order.
+    pub(crate) fn execute(&mut self, request: Request) -> Option<Fill> {
+        let products = self.products;
+        self.simulator.execute(
+            request,
+            |product| products.get(product).map(|state| &state.orderbook),
+            self.clock.now(),
+        )
+    }
+    pub(crate) fn next_update(&mut self) -> Option<OrderUpdate> {
+        self.simulator.next_update()
+    }
+}
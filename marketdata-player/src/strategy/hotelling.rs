@@ -0,0 +1,392 @@
+use anyhow::{Ok, Result};
+use async_trait::async_trait;
+use fpdec::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    str::FromStr,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use super::{Context, Strategy};
+use crate::{
+    analytics::BookFeatures,
+    forecast::{
+        evaluation::{Evaluation, EvaluationReport, Prediction},
+        AnyModel, ModelKind, Observation, PriceModel,
+    },
+    orderbook::{Book, Execution, OrderSize, Side},
+    simulator::Fill,
+    Event, EventKind,
+};
+
+/// Confidence level of the bounds of the model price.
+const CONFIDENCE: f64 = 0.95;
+
+/// The price model experiment, the Hotelling model by default. When a run
+/// of trades of a product ends, i.e. at its first depth update after a
+/// trade, the strategy buys `order` and bounds the price of the order at
+/// the next trade from the trades since the book was last updated, the
+/// horizon being the average time between trades. The simulated price, the
+/// bounds and the real price are written to `output/{product}.txt`, leaving
+/// out a real price equal to the previous one, and every forecast is scored
+/// by an [`Evaluation`]. Orders sized by quantity are compared by their
+/// cost, orders sized by notional by the quantity they buy.
+pub struct Hotelling {
+    order: OrderSize,
+    model: ModelKind,
+    products: BTreeMap<String, ProductModel>,
+    /// Directory of the output files.
+    output: PathBuf,
+    files: HashMap<String, File>,
+    evaluation: Evaluation,
+    /// Directory the report of the evaluation is written to.
+    evaluation_path: Option<PathBuf>,
+    /// The output of a restored replay continues the one it was
+    /// checkpointed from.
+    resumed: bool,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct ProductModel {
+    model: AnyModel,
+    /// Kind and venue timestamp of the last event.
+    last_event: Option<(EventKind, i64)>,
+    last_trade: Option<i64>,
+    /// Time between the trades so far and their number.
+    trade_time: i64,
+    trade_gaps: i64,
+    best_player_total_price: Decimal,
+    best_model_price_lower: f64,
+    best_model_price_upper: f64,
+    delta_execution: i64,
+    num_of_obs: usize,
+    prev_pbest: Decimal,
+    /// Book features at the moment of the last decision.
+    decision_features: BookFeatures,
+    /// Forecast of the last decision at the levels of the evaluation.
+    prediction: Option<Prediction>,
+}
+
+impl Hotelling {
+    pub fn new(order: OrderSize) -> Self {
+        Self {
+            order,
+            model: ModelKind::default(),
+            products: BTreeMap::new(),
+            output: PathBuf::from("output"),
+            files: HashMap::new(),
+            evaluation: Evaluation::default(),
+            evaluation_path: None,
+            resumed: false,
+        }
+    }
+    pub fn with_model(mut self, model: ModelKind) -> Self {
+        self.model = model;
+        self
+    }
+    /// Writes the output files to directory `path` instead of `output`.
+    pub fn with_output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output = path.into();
+        self
+    }
+    /// Writes the [`EvaluationReport`] of the forecasts to directory `path`
+    /// at the end of the replay.
+    pub fn with_evaluation(mut self, path: impl Into<PathBuf>) -> Self {
+        self.evaluation_path = Some(path.into());
+        self
+    }
+    pub fn evaluation(&self) -> EvaluationReport {
+        self.evaluation.report(&self.model.to_string())
+    }
+}
+
+impl ProductModel {
+    fn new(model: ModelKind) -> Self {
+        Self {
+            model: AnyModel::new(model),
+            ..Default::default()
+        }
+    }
+    /// Restarts the model from the best ask of `book`, if it has one.
+    fn reset(&mut self, book: &impl Book) {
+        if let Some(ask) = book.best_ask() {
+            self.model.reset(ask.price.into());
+        }
+    }
+    /// Average milliseconds between trades, 0 before the second one.
+    fn horizon(&self) -> i64 {
+        match self.trade_gaps {
+            0 => 0,
+            gaps => self.trade_time / gaps,
+        }
+    }
+}
+
+#[async_trait]
+impl Strategy for Hotelling {
+    async fn on_start(&mut self, ctx: &mut Context<'_>) -> Result<()> {
+        let header = match self.order {
+            OrderSize::Quantity(_) => "Best player price | Best model price lower | Best model price upper | Real price | Delta execution | Num of obs",
+            OrderSize::Notional { .. } => "Best player quantity | Best model quantity lower | Best model quantity upper | Real quantity | Delta execution | Num of obs",
+        };
+        let header = format!("{} | {}\n", header, BookFeatures::header(ctx.depth_bps()));
+        for product in ctx.products() {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(self.resumed)
+                .open(self.output.join(format!("{}.txt", product)))
+                .await?;
+            if !self.resumed {
+                file.write_all(header.as_bytes()).await?;
+            }
+            self.files.insert(product.to_string(), file);
+            self.products
+                .entry(product.to_string())
+                .or_insert_with(|| ProductModel::new(self.model));
+        }
+        Ok(())
+    }
+    async fn on_snapshot(&mut self, ctx: &mut Context<'_>, snapshot: &Event) -> Result<()> {
+        let (Some(state), Some(book)) = (
+            self.products.get_mut(&snapshot.product),
+            ctx.book(&snapshot.product),
+        ) else {
+            return Ok(());
+        };
+        if let Some((EventKind::Depth, _)) = state.last_event {
+            state.reset(book);
+        }
+        state.last_event = Some((EventKind::Snapshot, snapshot.venue_timestamp));
+        Ok(())
+    }
+    async fn on_depth(&mut self, ctx: &mut Context<'_>, diff: &Event) -> Result<()> {
+        let Some(state) = self.products.get_mut(&diff.product) else {
+            return Ok(());
+        };
+        match state.last_event {
+            Some((EventKind::Trade, last_timestamp)) => {
+                if ctx.live() {
+                    ctx.market_order(&diff.product, Side::Buy, self.order);
+                }
+                if let Some(features) = ctx.features(&diff.product) {
+                    state.decision_features.clone_from(features);
+                    state.model.update(Observation::Depth(features));
+                }
+                let forecast = state.model.predict(state.horizon(), CONFIDENCE);
+                let lower = model_total(self.order, forecast.lower);
+                let upper = model_total(self.order, forecast.upper);
+                state.best_model_price_lower = lower.min(upper);
+                state.best_model_price_upper = lower.max(upper);
+                state.num_of_obs = forecast.observations;
+                let prediction =
+                    self.evaluation
+                        .predict(&diff.product, &state.model, state.horizon());
+                state.prediction = Some(prediction.map(|price| model_total(self.order, price)));
+                state.delta_execution = diff.venue_timestamp - last_timestamp;
+            }
+            Some((EventKind::Snapshot, _)) => {
+                if let Some(book) = ctx.book(&diff.product) {
+                    state.reset(book);
+                }
+            }
+            _ => {}
+        }
+        state.last_event = Some((EventKind::Depth, diff.venue_timestamp));
+        Ok(())
+    }
+    async fn on_trade(&mut self, ctx: &mut Context<'_>, trade: &Event) -> Result<()> {
+        let (Some(state), Some(book)) = (
+            self.products.get_mut(&trade.product),
+            ctx.book(&trade.product),
+        ) else {
+            return Ok(());
+        };
+        if let Some((kind, last_timestamp)) = state.last_event {
+            match kind {
+                EventKind::Depth => {
+                    let real_total_price = book
+                        .execute(Side::Buy, self.order)
+                        .map(|execution| total(self.order, &execution));
+                    let prediction = state.prediction.take();
+                    if let Some((prediction, real_total_price)) =
+                        prediction.zip(real_total_price).filter(|_| ctx.live())
+                    {
+                        self.evaluation
+                            .record(prediction, f64::from(real_total_price));
+                    }
+                    let file = self.files.get_mut(&trade.product).filter(|_| ctx.live());
+                    if let Some((file, real_total_price)) =
+                        file.zip(real_total_price).filter(|(_, real_total_price)| {
+                            !state.best_model_price_upper.is_nan()
+                                && state.prev_pbest != *real_total_price
+                        })
+                    {
+                        let res = format!(
+                            "{} {} {} {} {} {} {}\n",
+                            state.best_player_total_price,
+                            state.best_model_price_lower,
+                            state.best_model_price_upper,
+                            real_total_price,
+                            state.delta_execution,
+                            state.num_of_obs,
+                            state.decision_features.row(ctx.depth_bps()),
+                        );
+                        file.write_all(res.as_bytes()).await?;
+                        state.prev_pbest = real_total_price;
+                    }
+                    state.reset(book);
+                }
+                EventKind::Snapshot => state.reset(book),
+                EventKind::Trade => {}
+            }
+            let delta_t = trade.venue_timestamp - last_timestamp;
+            let price = f64::from_str(&trade.price)?;
+            state.model.update(Observation::Trade { delta_t, price });
+        }
+        if let Some(last_trade) = state.last_trade {
+            state.trade_time += trade.venue_timestamp - last_trade;
+            state.trade_gaps += 1;
+        }
+        state.last_trade = Some(trade.venue_timestamp);
+        state.last_event = Some((EventKind::Trade, trade.venue_timestamp));
+        Ok(())
+    }
+    async fn on_fill(&mut self, _ctx: &mut Context<'_>, fill: &Fill) -> Result<()> {
+        if let Some(state) = self.products.get_mut(&fill.order.product) {
+            state.best_player_total_price = total(self.order, &fill.execution);
+        }
+        Ok(())
+    }
+    async fn on_end(&mut self, _ctx: &mut Context<'_>) -> Result<()> {
+        for file in self.files.values_mut() {
+            file.flush().await?;
+        }
+        if let Some(path) = &self.evaluation_path {
+            let report = self.evaluation();
+            report.write(path).await?;
+            print!("{report}");
+        }
+        Ok(())
+    }
+    fn checkpoint(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&(&self.products, &self.evaluation))?)
+    }
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        (self.products, self.evaluation) = bincode::deserialize(state)?;
+        self.resumed = true;
+        Ok(())
+    }
+}
+
+/// What the output compares for an order of `order` size.
+fn total(order: OrderSize, execution: &Execution) -> Decimal {
+    match order {
+        OrderSize::Quantity(_) => execution.notional,
+        OrderSize::Notional { .. } => execution.quantity,
+    }
+}
+
+/// [`total`] of an order filled at the model `price`.
+fn model_total(order: OrderSize, price: f64) -> f64 {
+    match order {
+        OrderSize::Quantity(quantity) => price * f64::from(quantity),
+        OrderSize::Notional { notional, .. } => f64::from(notional) / price,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{datasource::memory::MemorySource, marketdataplayer::MarketdataPlayer};
+
+    fn event(
+        event_type: &str,
+        gate_timestamp: i64,
+        ask_not_bid: Option<bool>,
+        price: &str,
+        quantity: &str,
+    ) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: gate_timestamp,
+            gate_timestamp,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid,
+            buy_not_sell: ask_not_bid.is_none().then_some(true),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scores_every_forecast_but_writes_changed_prices() {
+        // Two runs of trades, each ending with a depth update of the bids
+        // that leaves the asks, and so the real price, unchanged.
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "10"),
+            event("snapshot", 1, Some(false), "99", "10"),
+            event("trade", 3, None, "101", "1"),
+            event("depth", 4, Some(false), "99", "9"),
+            event("trade", 5, None, "101", "1"),
+            event("depth", 6, Some(false), "99", "8"),
+            event("trade", 7, None, "101", "1"),
+        ]);
+        let dir = std::env::temp_dir().join(format!("hotelling-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let strategy = Hotelling::new(OrderSize::Quantity(Decimal::ONE))
+            .with_output(&dir)
+            .with_evaluation(&dir);
+        let mut player = MarketdataPlayer::new(Box::new(source), Box::new(strategy));
+        player.play().await.unwrap();
+
+        let output = std::fs::read_to_string(dir.join("TEST.txt")).unwrap();
+        let prices: Vec<&str> = output
+            .lines()
+            .skip(1)
+            .map(|row| row.split(' ').nth(3).unwrap())
+            .collect();
+        assert_eq!(prices, ["101"]);
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("evaluation.json")).unwrap())
+                .unwrap();
+        let all = report["summaries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|summary| summary["dimension"] == "all")
+            .unwrap();
+        assert_eq!(all["forecasts"], 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn orders_only_once_live() {
+        let source = MemorySource::new(vec![
+            event("snapshot", 1, Some(true), "101", "10"),
+            event("snapshot", 1, Some(false), "99", "10"),
+            event("trade", 3, None, "101", "1"),
+            event("depth", 4, Some(false), "99", "9"),
+            event("trade", 5, None, "101", "1"),
+            event("depth", 6, Some(false), "99", "8"),
+            event("trade", 7, None, "101", "1"),
+        ]);
+        let dir = std::env::temp_dir().join(format!("hotelling-live-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let strategy = Hotelling::new(OrderSize::Quantity(Decimal::ONE)).with_output(&dir);
+        let mut player = MarketdataPlayer::new(Box::new(source), Box::new(strategy));
+        player.seek(5).await.unwrap();
+        player.play().await.unwrap();
+
+        assert_eq!(player.tca().orders.len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,656 @@
+use anyhow::Result;
+use fpdec::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+};
+
+use crate::{
+    algos::profile::DAY,
+    orderbook::{Book, OrderSize, Price, Quantity, Side},
+    simulator::{
+        limit::{OrderEvent, OrderUpdate},
+        OrderId,
+    },
+    Event, EventKind,
+};
+
+/// Milliseconds in an hour, the time of day buckets of the reports.
+const HOUR: i64 = 3_600_000;
+
+/// Whether an order was worked by a strategy through child orders or sent
+/// to the book. Orders without a parent are children too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum OrderRole {
+    Parent,
+    Child,
+}
+
+/// Traded volume and integral of the mid of a product since the start of
+/// the replay. Their differences between two times give the VWAP and the
+/// TWAP of the period.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Cumulative {
+    volume: f64,
+    notional: f64,
+    /// Integral of the mid over the time it was known, and that time.
+    mid_time: f64,
+    duration: i64,
+}
+
+impl Cumulative {
+    fn vwap_since(&self, start: &Cumulative) -> Option<f64> {
+        let volume = self.volume - start.volume;
+        (volume > 0.0).then(|| (self.notional - start.notional) / volume)
+    }
+    fn twap_since(&self, start: &Cumulative) -> Option<f64> {
+        let duration = self.duration - start.duration;
+        (duration > 0).then(|| (self.mid_time - start.mid_time) / duration as f64)
+    }
+}
+
+/// [`Cumulative`] of a product as of its last event.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Market {
+    cumulative: Cumulative,
+    /// Time of the last event.
+    timestamp: Option<i64>,
+}
+
+impl Market {
+    /// Accounts for `event` of `kind` arriving at `now`, `book` being the
+    /// book it has not been applied to yet, whose mid has held since the
+    /// last event.
+    fn on_event(&mut self, kind: EventKind, event: &Event, book: &impl Book, now: i64) {
+        self.cumulative = self.at(now, book.mid());
+        self.timestamp = Some(now);
+        if kind == EventKind::Trade {
+            if let (Ok(price), Ok(quantity)) =
+                (event.price.parse::<f64>(), event.quantity.parse::<f64>())
+            {
+                self.cumulative.volume += quantity;
+                self.cumulative.notional += price * quantity;
+            }
+        }
+    }
+    /// Cumulative values at `now`, `mid` having held since the last event.
+    fn at(&self, now: i64, mid: Option<Price>) -> Cumulative {
+        let mut cumulative = self.cumulative;
+        if let (Some(timestamp), Some(mid)) = (self.timestamp, mid) {
+            let elapsed = (now - timestamp).max(0);
+            cumulative.mid_time += f64::from(mid) * elapsed as f64;
+            cumulative.duration += elapsed;
+        }
+        cumulative
+    }
+}
+
+/// Simulated order as the transaction cost analysis sees it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    product: String,
+    side: Side,
+    role: OrderRole,
+    parent: Option<OrderId>,
+    quantity: Option<Quantity>,
+    /// Size in the quote asset at the arrival mid.
+    size: Option<f64>,
+    arrival: i64,
+    arrival_mid: Option<Price>,
+    at_arrival: Cumulative,
+    at_last_fill: Option<Cumulative>,
+    filled: Quantity,
+    notional: Decimal,
+    fees: Decimal,
+    /// Fill quantities times the best price on the side the order takes
+    /// and times the mid, in the book when it filled.
+    touch_notional: f64,
+    mid_notional: f64,
+}
+
+/// Records the simulated orders and their fills with the state of the books
+/// around them for the transaction cost analysis. See [`TcaReport`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Tca {
+    markets: HashMap<String, Market>,
+    orders: BTreeMap<OrderId, Record>,
+}
+
+impl Tca {
+    pub(crate) fn on_event(&mut self, kind: EventKind, event: &Event, book: &impl Book, now: i64) {
+        self.markets
+            .entry(event.product.clone())
+            .or_default()
+            .on_event(kind, event, book, now);
+    }
+    /// Records an order submitted at `now` when the mid was `arrival_mid`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn order(
+        &mut self,
+        id: OrderId,
+        role: OrderRole,
+        parent: Option<OrderId>,
+        product: &str,
+        side: Side,
+        size: OrderSize,
+        arrival_mid: Option<Price>,
+        now: i64,
+    ) {
+        let (quantity, size) = match size {
+            OrderSize::Quantity(quantity) => (
+                Some(quantity),
+                arrival_mid.map(|mid| f64::from(quantity) * f64::from(mid)),
+            ),
+            OrderSize::Notional { notional, .. } => (None, Some(f64::from(notional))),
+        };
+        let at_arrival = self
+            .markets
+            .get(product)
+            .map(|market| market.at(now, arrival_mid))
+            .unwrap_or_default();
+        self.orders.insert(
+            id,
+            Record {
+                product: product.to_string(),
+                side,
+                role,
+                parent,
+                quantity,
+                size,
+                arrival: now,
+                arrival_mid,
+                at_arrival,
+                at_last_fill: None,
+                filled: Decimal::ZERO,
+                notional: Decimal::ZERO,
+                fees: Decimal::ZERO,
+                touch_notional: 0.0,
+                mid_notional: 0.0,
+            },
+        );
+    }
+    /// Product and side of parent order `parent`.
+    pub(crate) fn parent(&self, parent: OrderId) -> Option<(&str, Side)> {
+        self.orders
+            .get(&parent)
+            .filter(|record| record.role == OrderRole::Parent)
+            .map(|record| (record.product.as_str(), record.side))
+    }
+    /// Records a fill of `order` and of its parent against `book`, the book
+    /// as recorded at `now`.
+    pub(crate) fn fill(
+        &mut self,
+        order: OrderId,
+        price: Price,
+        quantity: Quantity,
+        fee: Decimal,
+        book: Option<&impl Book>,
+        now: i64,
+    ) {
+        let Some(record) = self.orders.get(&order) else {
+            return;
+        };
+        let (product, side, parent) = (record.product.clone(), record.side, record.parent);
+        let mid = book.and_then(|book| book.mid());
+        let touch = book
+            .and_then(|book| book.levels(side).next())
+            .map(|level| level.price);
+        let cumulative = self
+            .markets
+            .get(&product)
+            .map(|market| market.at(now, mid))
+            .unwrap_or_default();
+        for id in [Some(order), parent].into_iter().flatten() {
+            let Some(record) = self.orders.get_mut(&id) else {
+                continue;
+            };
+            let filled = f64::from(quantity);
+            record.filled += quantity;
+            record.notional += price * quantity;
+            record.fees += fee;
+            // Without a book the whole cost of the fill is counted as timing.
+            record.touch_notional += f64::from(touch.unwrap_or(price)) * filled;
+            record.mid_notional += f64::from(mid.unwrap_or(price)) * filled;
+            record.at_last_fill = Some(cumulative);
+        }
+    }
+    /// Forgets `order`, which was rejected before it could fill.
+    pub(crate) fn reject(&mut self, order: OrderId) {
+        self.orders.remove(&order);
+    }
+    /// Records the fills among `updates` of the limit orders, `book` being
+    /// the book of their product.
+    pub(crate) fn limit_fills<'u>(
+        &mut self,
+        updates: impl Iterator<Item = &'u OrderUpdate>,
+        book: Option<&impl Book>,
+    ) {
+        for update in updates {
+            if let OrderEvent::Filled {
+                price,
+                quantity,
+                fee,
+                ..
+            } = update.event
+            {
+                self.fill(update.order, price, quantity, fee, book, update.timestamp);
+            }
+        }
+    }
+    pub(crate) fn report(&self) -> TcaReport {
+        let orders: Vec<OrderCosts> = self
+            .orders
+            .iter()
+            .map(|(&id, record)| OrderCosts::new(id, record))
+            .collect();
+        let mut summaries = Vec::new();
+        for dimension in ["product", "hour", "size"] {
+            let mut groups: BTreeMap<(OrderRole, String), Vec<&OrderCosts>> = BTreeMap::new();
+            for order in &orders {
+                groups
+                    .entry((order.role, order.bucket(dimension)))
+                    .or_default()
+                    .push(order);
+            }
+            for ((role, bucket), orders) in groups {
+                summaries.push(CostSummary::new(dimension, bucket, role, &orders));
+            }
+        }
+        TcaReport { orders, summaries }
+    }
+}
+
+/// Transaction costs of a simulated order, in basis points of its arrival
+/// price and positive when they cost the order. The implementation
+/// shortfall of the fills splits into the spread cost, half the spread in
+/// the book at each fill, the market impact, how much worse than the best
+/// price the fills were, which includes the liquidity taken by the earlier
+/// fills and is negative for the fills of resting orders, and the timing
+/// cost, how the mid moved between the arrival and the fills. The
+/// benchmarks are taken over the time from the arrival to the last fill.
+/// The fees are on top of the shortfall, the total cost being their sum.
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderCosts {
+    pub order: OrderId,
+    pub parent: Option<OrderId>,
+    pub role: OrderRole,
+    pub product: String,
+    pub side: Side,
+    pub arrival: i64,
+    /// `None` for a market order for an amount of the quote asset.
+    pub quantity: Option<Quantity>,
+    /// Size in the quote asset at the arrival price.
+    pub size: Option<f64>,
+    pub filled: Quantity,
+    pub notional: Decimal,
+    /// Fees paid in the quote asset, negative for rebates.
+    pub fees: Decimal,
+    /// Mid of the book when the order was submitted.
+    pub arrival_price: Option<Price>,
+    pub average_price: Option<Price>,
+    pub shortfall_bps: Option<f64>,
+    pub vwap_slippage_bps: Option<f64>,
+    pub twap_slippage_bps: Option<f64>,
+    pub spread_cost_bps: Option<f64>,
+    pub impact_bps: Option<f64>,
+    pub timing_cost_bps: Option<f64>,
+    pub fees_bps: Option<f64>,
+    pub total_cost_bps: Option<f64>,
+}
+
+impl OrderCosts {
+    fn new(id: OrderId, record: &Record) -> Self {
+        let filled = f64::from(record.filled);
+        let average_price = (!record.filled.eq_zero()).then(|| record.notional / record.filled);
+        let arrival = record
+            .arrival_mid
+            .map(f64::from)
+            .filter(|_| average_price.is_some());
+        let sign = match record.side {
+            Side::Buy => 1.0,
+            Side::Sell => -1.0,
+        };
+        let bps = |cost: f64| arrival.map(|arrival| sign * cost / arrival * 10_000.0);
+        let average = average_price.map_or(0.0, f64::from);
+        let (touch, mid) = (record.touch_notional / filled, record.mid_notional / filled);
+        let benchmark = |price: Option<f64>| {
+            let price = price?;
+            Some(sign * (average - price) / price * 10_000.0)
+        };
+        let at_last_fill = record.at_last_fill.filter(|_| average_price.is_some());
+        let fees = arrival.map(|arrival| f64::from(record.fees) / filled / arrival * 10_000.0);
+        let shortfall = arrival.and_then(|arrival| bps(average - arrival));
+        Self {
+            order: id,
+            parent: record.parent,
+            role: record.role,
+            product: record.product.clone(),
+            side: record.side,
+            arrival: record.arrival,
+            quantity: record.quantity,
+            size: record.size,
+            filled: record.filled,
+            notional: record.notional,
+            fees: record.fees,
+            arrival_price: record.arrival_mid,
+            average_price,
+            shortfall_bps: shortfall,
+            vwap_slippage_bps: benchmark(
+                at_last_fill.and_then(|last| last.vwap_since(&record.at_arrival)),
+            ),
+            // The TWAP of a fill at the arrival is the arrival price.
+            twap_slippage_bps: benchmark(at_last_fill.and_then(|last| {
+                last.twap_since(&record.at_arrival)
+                    .or(record.arrival_mid.map(f64::from))
+            })),
+            spread_cost_bps: bps(touch - mid),
+            impact_bps: bps(average - touch),
+            timing_cost_bps: arrival.and_then(|arrival| bps(mid - arrival)),
+            fees_bps: fees,
+            total_cost_bps: shortfall
+                .zip(fees)
+                .map(|(shortfall, fees)| shortfall + fees),
+        }
+    }
+    /// Group of the order by `dimension` in the summaries.
+    fn bucket(&self, dimension: &str) -> String {
+        match (dimension, self.size) {
+            ("product", _) => self.product.clone(),
+            ("hour", _) => format!("{:02}:00", self.arrival.rem_euclid(DAY) / HOUR),
+            (_, Some(size)) if size >= 1.0 => {
+                let decade = size.log10().floor() as i32;
+                format!("1e{}-1e{}", decade, decade + 1)
+            }
+            (_, Some(_)) => String::from("<1"),
+            (_, None) => String::from("-"),
+        }
+    }
+    fn csv(&self) -> String {
+        let option = |value: Option<String>| value.unwrap_or_default();
+        let bps = |value: Option<f64>| option(value.map(|value| format!("{:.4}", value)));
+        [
+            self.order.to_string(),
+            option(self.parent.map(|parent| parent.to_string())),
+            format!("{:?}", self.role),
+            self.product.clone(),
+            format!("{:?}", self.side),
+            self.arrival.to_string(),
+            option(self.quantity.map(|quantity| quantity.to_string())),
+            option(self.size.map(|size| format!("{:.2}", size))),
+            self.filled.to_string(),
+            self.notional.to_string(),
+            self.fees.to_string(),
+            option(self.arrival_price.map(|price| price.to_string())),
+            option(self.average_price.map(|price| price.to_string())),
+            bps(self.shortfall_bps),
+            bps(self.vwap_slippage_bps),
+            bps(self.twap_slippage_bps),
+            bps(self.spread_cost_bps),
+            bps(self.impact_bps),
+            bps(self.timing_cost_bps),
+            bps(self.fees_bps),
+            bps(self.total_cost_bps),
+        ]
+        .join(",")
+    }
+}
+
+/// Costs of a group of orders of one role, averaged over the filled ones
+/// weighted by their filled notional.
+#[derive(Debug, Clone, Serialize)]
+pub struct CostSummary {
+    /// What the orders are grouped by: product, hour of the arrival (UTC)
+    /// or size decade in the quote asset.
+    pub dimension: String,
+    pub bucket: String,
+    pub role: OrderRole,
+    pub orders: usize,
+    pub filled_orders: usize,
+    pub notional: f64,
+    pub shortfall_bps: Option<f64>,
+    pub vwap_slippage_bps: Option<f64>,
+    pub twap_slippage_bps: Option<f64>,
+    pub spread_cost_bps: Option<f64>,
+    pub impact_bps: Option<f64>,
+    pub timing_cost_bps: Option<f64>,
+    pub fees_bps: Option<f64>,
+    pub total_cost_bps: Option<f64>,
+}
+
+impl CostSummary {
+    fn new(dimension: &str, bucket: String, role: OrderRole, orders: &[&OrderCosts]) -> Self {
+        let mean = |cost: fn(&OrderCosts) -> Option<f64>| {
+            let (sum, weight) = orders
+                .iter()
+                .filter_map(|order| Some((cost(order)?, f64::from(order.notional))))
+                .fold((0.0, 0.0), |(sum, weight), (cost, notional)| {
+                    (sum + cost * notional, weight + notional)
+                });
+            (weight > 0.0).then(|| sum / weight)
+        };
+        Self {
+            dimension: dimension.to_string(),
+            bucket,
+            role,
+            orders: orders.len(),
+            filled_orders: orders
+                .iter()
+                .filter(|order| !order.filled.eq_zero())
+                .count(),
+            notional: orders.iter().map(|order| f64::from(order.notional)).sum(),
+            shortfall_bps: mean(|order| order.shortfall_bps),
+            vwap_slippage_bps: mean(|order| order.vwap_slippage_bps),
+            twap_slippage_bps: mean(|order| order.twap_slippage_bps),
+            spread_cost_bps: mean(|order| order.spread_cost_bps),
+            impact_bps: mean(|order| order.impact_bps),
+            timing_cost_bps: mean(|order| order.timing_cost_bps),
+            fees_bps: mean(|order| order.fees_bps),
+            total_cost_bps: mean(|order| order.total_cost_bps),
+        }
+    }
+    fn csv(&self) -> String {
+        let bps = |value: Option<f64>| value.map_or(String::new(), |value| format!("{:.4}", value));
+        [
+            self.dimension.clone(),
+            self.bucket.clone(),
+            format!("{:?}", self.role),
+            self.orders.to_string(),
+            self.filled_orders.to_string(),
+            format!("{:.2}", self.notional),
+            bps(self.shortfall_bps),
+            bps(self.vwap_slippage_bps),
+            bps(self.twap_slippage_bps),
+            bps(self.spread_cost_bps),
+            bps(self.impact_bps),
+            bps(self.timing_cost_bps),
+            bps(self.fees_bps),
+            bps(self.total_cost_bps),
+        ]
+        .join(",")
+    }
+}
+
+/// Transaction cost analysis of the simulated orders of a replay: the
+/// [`OrderCosts`] of every order and their [`CostSummary`] by product,
+/// time of day and size.
+#[derive(Debug, Clone, Serialize)]
+pub struct TcaReport {
+    pub orders: Vec<OrderCosts>,
+    pub summaries: Vec<CostSummary>,
+}
+
+impl TcaReport {
+    /// Writes `orders.csv`, `summary.csv` and the whole report as
+    /// `tca.json` to directory `dir`.
+    pub async fn write(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        tokio::fs::create_dir_all(dir).await?;
+        let costs = "shortfall_bps,vwap_slippage_bps,twap_slippage_bps,spread_cost_bps,impact_bps,timing_cost_bps,fees_bps,total_cost_bps";
+        let mut orders = format!(
+            "order,parent,role,product,side,arrival,quantity,size,filled,notional,fees,arrival_price,average_price,{costs}\n"
+        );
+        for order in &self.orders {
+            orders.push_str(&order.csv());
+            orders.push('\n');
+        }
+        let mut summaries =
+            format!("dimension,bucket,role,orders,filled_orders,notional,{costs}\n");
+        for summary in &self.summaries {
+            summaries.push_str(&summary.csv());
+            summaries.push('\n');
+        }
+        tokio::fs::write(dir.join("orders.csv"), orders).await?;
+        tokio::fs::write(dir.join("summary.csv"), summaries).await?;
+        tokio::fs::write(dir.join("tca.json"), serde_json::to_vec_pretty(self)?).await?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for TcaReport {
+    /// Table of the summaries.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bps =
+            |value: Option<f64>| value.map_or(String::from("-"), |value| format!("{:.2}", value));
+        writeln!(
+            f,
+            "{:<8} {:<12} {:<6} {:>11} {:>14} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "by",
+            "bucket",
+            "role",
+            "filled",
+            "notional",
+            "shortfall",
+            "vs vwap",
+            "vs twap",
+            "spread",
+            "impact",
+            "timing",
+            "fees",
+            "total"
+        )?;
+        for summary in &self.summaries {
+            writeln!(
+                f,
+                "{:<8} {:<12} {:<6} {:>11} {:>14.2} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                summary.dimension,
+                summary.bucket,
+                format!("{:?}", summary.role),
+                format!("{}/{}", summary.filled_orders, summary.orders),
+                summary.notional,
+                bps(summary.shortfall_bps),
+                bps(summary.vwap_slippage_bps),
+                bps(summary.twap_slippage_bps),
+                bps(summary.spread_cost_bps),
+                bps(summary.impact_bps),
+                bps(summary.timing_cost_bps),
+                bps(summary.fees_bps),
+                bps(summary.total_cost_bps),
+            )?;
+        }
+        writeln!(f, "costs in bps of the arrival price, positive when paid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::Orderbook;
+    use std::str::FromStr;
+
+    fn decimal(s: &str) -> Decimal {
+        Decimal::from_str(s).unwrap()
+    }
+
+    fn event(event_type: &str, ask_not_bid: Option<bool>, price: &str, quantity: &str) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: Some(1),
+            id2: None,
+            ask_not_bid,
+            buy_not_sell: ask_not_bid.is_none().then_some(true),
+            price: price.to_string(),
+            quantity: quantity.to_string(),
+        }
+    }
+
+    #[test]
+    fn shortfall_splits_into_spread_impact_and_timing_before_fees() {
+        let mut book = Orderbook::default();
+        for (ask_not_bid, price, quantity) in
+            [(true, "101", "1"), (true, "102", "5"), (false, "99", "1")]
+        {
+            book.update(event("snapshot", Some(ask_not_bid), price, quantity))
+                .unwrap();
+        }
+        let mut tca = Tca::default();
+        let size = OrderSize::Quantity(decimal("2"));
+        let mid = book.mid();
+        tca.on_event(
+            EventKind::Depth,
+            &event("depth", Some(false), "98", "1"),
+            &book,
+            0,
+        );
+        tca.order(0, OrderRole::Parent, None, "TEST", Side::Buy, size, mid, 0);
+        tca.order(
+            1,
+            OrderRole::Child,
+            Some(0),
+            "TEST",
+            Side::Buy,
+            size,
+            mid,
+            0,
+        );
+        tca.on_event(
+            EventKind::Trade,
+            &event("trade", None, "101", "3"),
+            &book,
+            10,
+        );
+        tca.fill(
+            1,
+            decimal("101"),
+            decimal("1"),
+            decimal("0.1"),
+            Some(&book),
+            10,
+        );
+        tca.fill(
+            1,
+            decimal("102"),
+            decimal("1"),
+            decimal("0.1"),
+            Some(&book),
+            10,
+        );
+
+        let report = tca.report();
+        assert_eq!(report.orders.len(), 2);
+        for order in &report.orders {
+            assert_eq!(order.average_price, Some(decimal("101.5")));
+            assert_eq!(order.shortfall_bps, Some(150.0));
+            assert_eq!(order.spread_cost_bps, Some(100.0));
+            assert_eq!(order.impact_bps, Some(50.0));
+            assert_eq!(order.timing_cost_bps, Some(0.0));
+            assert_eq!(order.fees_bps, Some(10.0));
+            assert_eq!(order.total_cost_bps, Some(160.0));
+            assert_eq!(order.twap_slippage_bps, Some(150.0));
+            let vwap = order.vwap_slippage_bps.unwrap();
+            assert!((vwap - 0.5 / 101.0 * 10_000.0).abs() < 1e-9);
+        }
+        let parents = report
+            .summaries
+            .iter()
+            .find(|summary| summary.dimension == "size" && summary.role == OrderRole::Parent)
+            .unwrap();
+        assert_eq!(parents.bucket, "1e2-1e3");
+        assert_eq!(parents.shortfall_bps, Some(150.0));
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::{bail, ensure, Context, Result};
+use fpdec::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    orderbook::{aggressor, Book, Level, Side},
+    Event,
+};
+
+/// Decimal places of quantities, the precision Binance sends them with.
+pub(crate) const QUANTITY_DIGITS: u8 = 8;
+/// Tick size that represents every price Binance sends.
+pub const DEFAULT_TICK_SIZE: Decimal = Decimal::new_raw(1, 8);
+
+/// Order book on integers: prices in ticks of the symbol's tick size and
+/// quantities in units of `10^-8`, parsed straight from the event strings.
+/// Each side is an array sorted so that its best level is the last one, so
+/// that the updates near the touch move few elements. Snapshots arrive best
+/// level first, so their levels are held back and sorted into the sides at
+/// once when the snapshot is complete.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TickBook {
+    /// Tick size in units of `10^-price_digits`.
+    tick: i64,
+    price_digits: u8,
+    /// Ascending by price.
+    bids: Vec<(i64, i64)>,
+    /// Descending by price.
+    asks: Vec<(i64, i64)>,
+    /// Levels of the snapshot being applied with their sides, in arrival
+    /// order.
+    pending: Vec<(bool, i64, i64)>,
+}
+
+impl TickBook {
+    /// Empty book for a symbol with `tick_size`, which must be positive.
+    pub fn new(tick_size: Decimal) -> Self {
+        assert!(tick_size > Decimal::ZERO, "tick size must be positive");
+        Self {
+            tick: i64::try_from(tick_size.coefficient()).expect("tick size out of range"),
+            price_digits: tick_size.n_frac_digits(),
+            bids: Vec::new(),
+            asks: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+    fn ticks(&self, price: &str) -> Result<i64> {
+        let price = parse_fixed(price, self.price_digits)?;
+        ensure!(
+            price % self.tick == 0,
+            "price {} is not a multiple of the tick size",
+            format_fixed(price, self.price_digits)
+        );
+        Ok(price / self.tick)
+    }
+    /// Ticks of `price`, `None` if it is not on the tick grid.
+    fn ticks_of(&self, price: Decimal) -> Option<i64> {
+        let coefficient = i64::try_from(price.coefficient()).ok()?;
+        let digits = price.n_frac_digits();
+        let fixed = if digits <= self.price_digits {
+            coefficient.checked_mul(10i64.checked_pow(u32::from(self.price_digits - digits))?)?
+        } else {
+            let scale = 10i64.checked_pow(u32::from(digits - self.price_digits))?;
+            (coefficient % scale == 0).then_some(coefficient / scale)?
+        };
+        (fixed % self.tick == 0).then_some(fixed / self.tick)
+    }
+    /// Position of the level at `price` of a side.
+    fn position(&self, ask_not_bid: bool, price: i64) -> Result<usize, usize> {
+        if ask_not_bid {
+            self.asks.binary_search_by(|&(level, _)| price.cmp(&level))
+        } else {
+            self.bids.binary_search_by_key(&price, |&(level, _)| level)
+        }
+    }
+    fn level(&self, &(ticks, quantity): &(i64, i64)) -> Level {
+        Level {
+            price: decimal(ticks * self.tick, self.price_digits),
+            quantity: decimal(quantity, QUANTITY_DIGITS),
+        }
+    }
+    fn apply_trade(&mut self, side: Side, price: i64, quantity: i64) {
+        let (levels, better): (_, fn(i64, i64) -> bool) = match side {
+            Side::Buy => (&mut self.asks, |level, price| level < price),
+            Side::Sell => (&mut self.bids, |level, price| level > price),
+        };
+        while levels
+            .last()
+            .is_some_and(|&(level, _)| better(level, price))
+        {
+            levels.pop();
+        }
+        if let Some(level) = levels.last_mut().filter(|(level, _)| *level == price) {
+            level.1 -= quantity;
+            if level.1 <= 0 {
+                levels.pop();
+            }
+        }
+    }
+}
+
+impl Book for TickBook {
+    fn update(&mut self, diff: Event) -> Result<Level> {
+        let price = self.ticks(&diff.price)?;
+        let quantity = parse_fixed(&diff.quantity, QUANTITY_DIGITS)?;
+        let ask_not_bid = diff
+            .ask_not_bid
+            .with_context(|| format!("{} of {} has no side", diff.event_type, diff.product))?;
+        if diff.event_type == "snapshot" {
+            // Snapshots are applied to an empty book.
+            self.pending.push((ask_not_bid, price, quantity));
+            return Ok(self.level(&(price, 0)));
+        }
+        self.finish_snapshot();
+        let position = self.position(ask_not_bid, price);
+        let before = {
+            let levels = if ask_not_bid { &self.asks } else { &self.bids };
+            self.level(&(price, position.map_or(0, |index| levels[index].1)))
+        };
+        let levels = if ask_not_bid {
+            &mut self.asks
+        } else {
+            &mut self.bids
+        };
+        match position {
+            Ok(index) if quantity == 0 => {
+                levels.remove(index);
+            }
+            Ok(index) => levels[index].1 = quantity,
+            Err(index) if quantity != 0 => levels.insert(index, (price, quantity)),
+            Err(_) => {}
+        }
+        Ok(before)
+    }
+    fn finish_snapshot(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        for (ask_not_bid, levels) in [(true, &mut self.asks), (false, &mut self.bids)] {
+            levels.extend(
+                self.pending
+                    .iter()
+                    .filter(|(ask, ..)| *ask == ask_not_bid)
+                    .map(|&(_, price, quantity)| (price, quantity)),
+            );
+            // The sort is stable, so the last of the levels at a price is
+            // the latest one.
+            if ask_not_bid {
+                levels.sort_by_key(|&(price, _)| std::cmp::Reverse(price));
+            } else {
+                levels.sort_by_key(|&(price, _)| price);
+            }
+            let mut merged: Vec<(i64, i64)> = Vec::with_capacity(levels.len());
+            for level in levels.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.0 == level.0 => *last = level,
+                    _ => merged.push(level),
+                }
+            }
+            merged.retain(|&(_, quantity)| quantity != 0);
+            *levels = merged;
+        }
+        self.pending.clear();
+    }
+    fn handle_trade(&mut self, trade: Event) -> Result<()> {
+        self.finish_snapshot();
+        let price = self.ticks(&trade.price)?;
+        let quantity = parse_fixed(&trade.quantity, QUANTITY_DIGITS)?;
+        let side = aggressor(&trade, || {
+            self.asks.last().is_some_and(|&(ask, _)| price >= ask)
+        });
+        self.apply_trade(side, price, quantity);
+        Ok(())
+    }
+    fn levels(&self, side: Side) -> Box<dyn Iterator<Item = Level> + '_> {
+        let levels = match side {
+            Side::Buy => &self.asks,
+            Side::Sell => &self.bids,
+        };
+        Box::new(levels.iter().rev().map(|level| self.level(level)))
+    }
+    fn quantity(&self, side: Side, price: Decimal) -> Decimal {
+        let Some(price) = self.ticks_of(price) else {
+            return Decimal::ZERO;
+        };
+        let (ask_not_bid, levels) = match side {
+            Side::Buy => (true, &self.asks),
+            Side::Sell => (false, &self.bids),
+        };
+        self.position(ask_not_bid, price)
+            .map_or(Decimal::ZERO, |index| self.level(&levels[index]).quantity)
+    }
+}
+
+/// Parses a decimal string into an integer in units of `10^-digits`.
+fn parse_fixed(value: &str, digits: u8) -> Result<i64> {
+    let (negative, magnitude) = match value.strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, value),
+    };
+    let (integer, fraction) = magnitude.split_once('.').unwrap_or((magnitude, ""));
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > digits as usize {
+        bail!("{:?} has more than {} decimal places", value, digits);
+    }
+    if !integer.bytes().all(|digit| digit.is_ascii_digit()) {
+        bail!("invalid decimal {:?}", value);
+    }
+    let mut fixed: i64 = if integer.is_empty() {
+        0
+    } else {
+        integer.parse()?
+    };
+    let mut fraction = fraction.bytes();
+    for _ in 0..digits {
+        let digit = match fraction.next() {
+            Some(digit @ b'0'..=b'9') => i64::from(digit - b'0'),
+            Some(_) => bail!("invalid decimal {:?}", value),
+            None => 0,
+        };
+        fixed = fixed
+            .checked_mul(10)
+            .and_then(|fixed| fixed.checked_add(digit))
+            .ok_or_else(|| anyhow::anyhow!("{:?} is out of range", value))?;
+    }
+    Ok(if negative { -fixed } else { fixed })
+}
+
+/// `value` in units of `10^-digits` without trailing zeros.
+fn decimal(mut value: i64, mut digits: u8) -> Decimal {
+    while digits > 0 && value % 10 == 0 {
+        value /= 10;
+        digits -= 1;
+    }
+    Decimal::new_raw(i128::from(value), digits)
+}
+
+fn format_fixed(value: i64, digits: u8) -> String {
+    decimal(value, digits).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::Orderbook;
+    use proptest::prelude::*;
+
+    fn event(event_type: &str, ask_not_bid: Option<bool>, price: i64, quantity: i64) -> Event {
+        Event {
+            local_unique_id: 0,
+            venue_timestamp: 0,
+            gate_timestamp: 0,
+            event_type: event_type.to_string(),
+            product: "TEST".to_string(),
+            id1: None,
+            id2: None,
+            ask_not_bid,
+            buy_not_sell: None,
+            // Prices and quantities as Binance formats them.
+            price: format!("{}.{:08}", price / 100, (price % 100) * 1_000_000),
+            quantity: format!("{}.{:08}", quantity / 1_000, (quantity % 1_000) * 100_000),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_orderbook(
+            events in prop::collection::vec(
+                (0..3u8, any::<bool>(), 9_900i64..10_100, 0i64..5_000),
+                0..200,
+            ),
+        ) {
+            let mut map = Orderbook::default();
+            let mut tick = TickBook::new(Decimal::new_raw(1, 2));
+            for (kind, ask_not_bid, price, quantity) in events {
+                if kind == 0 {
+                    let trade = event("trade", None, price, quantity);
+                    map.handle_trade(trade.clone()).unwrap();
+                    tick.handle_trade(trade).unwrap();
+                } else {
+                    let event_type = if kind == 1 { "depth" } else { "snapshot" };
+                    let diff = event(event_type, Some(ask_not_bid), price, quantity);
+                    let level_price = Decimal::new_raw(price.into(), 2);
+                    let map_before = map.update(diff.clone()).unwrap();
+                    let tick_before = tick.update(diff).unwrap();
+                    prop_assert_eq!(map_before.price, tick_before.price);
+                    if kind == 2 {
+                        // The tick book holds the snapshot back.
+                        continue;
+                    }
+                    prop_assert_eq!(map_before, tick_before);
+                    for side in [Side::Buy, Side::Sell] {
+                        prop_assert_eq!(
+                            map.quantity(side, level_price),
+                            tick.quantity(side, level_price)
+                        );
+                    }
+                }
+                prop_assert_eq!(map.depth(usize::MAX), tick.depth(usize::MAX));
+            }
+            tick.finish_snapshot();
+            prop_assert_eq!(map.depth(usize::MAX), tick.depth(usize::MAX));
+        }
+    }
+
+    #[test]
+    fn sorts_snapshots_in_once_complete() {
+        let mut book = TickBook::new(Decimal::new_raw(1, 2));
+        book.update(event("depth", Some(true), 10_500, 1_000))
+            .unwrap();
+        for (ask_not_bid, price) in [
+            (true, 10_100),
+            (false, 9_900),
+            (true, 10_200),
+            (false, 9_800),
+        ] {
+            book.update(event("snapshot", Some(ask_not_bid), price, 2_000))
+                .unwrap();
+        }
+        assert_eq!(book.best_ask().unwrap().price, Decimal::from(105));
+        book.finish_snapshot();
+        let prices =
+            |side| -> Vec<Decimal> { book.levels(side).map(|level| level.price).collect() };
+        assert_eq!(prices(Side::Buy), [101, 102, 105].map(Decimal::from));
+        assert_eq!(prices(Side::Sell), [99, 98].map(Decimal::from));
+        assert_eq!(
+            book.quantity(Side::Buy, Decimal::new_raw(10_200, 2)),
+            Decimal::from(2)
+        );
+        assert_eq!(book.quantity(Side::Buy, Decimal::new_raw(1_015, 1)), 0);
+    }
+
+    #[test]
+    fn parses_signed_fixed_point() {
+        assert_eq!(parse_fixed("1.5", 2).unwrap(), 150);
+        assert_eq!(parse_fixed("-1.5", 2).unwrap(), -150);
+        assert_eq!(parse_fixed("-0.5", 1).unwrap(), -5);
+        assert_eq!(parse_fixed(".25", 2).unwrap(), 25);
+        assert!(parse_fixed("--1", 2).is_err());
+        assert!(parse_fixed("1-", 2).is_err());
+        assert!(parse_fixed("1.005", 2).is_err());
+    }
+
+    #[test]
+    fn rejects_updates_without_a_side() {
+        let diff = event("depth", None, 10_000, 1_000);
+        let err = Orderbook::default().update(diff.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "depth of TEST has no side");
+        assert!(TickBook::new(Decimal::new_raw(1, 2)).update(diff).is_err());
+    }
+
+    #[test]
+    fn rejects_prices_off_the_tick() {
+        let mut book = TickBook::new(Decimal::new_raw(5, 2));
+        assert!(book
+            .update(event("depth", Some(true), 10_003, 1_000))
+            .is_err());
+        assert!(book
+            .update(event("depth", Some(true), 10_005, 1_000))
+            .is_ok());
+    }
+}